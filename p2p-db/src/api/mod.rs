@@ -1,14 +1,28 @@
 pub mod p2p_common;
+pub mod p2p_config;
 pub mod p2p_dial;
+pub mod p2p_dht;
 pub mod p2p_event;
 pub mod p2p_factory;
+pub mod p2p_file;
 pub mod p2p_key;
 pub mod p2p_listen;
+pub mod p2p_mdns;
 pub mod p2p_nat;
+pub mod p2p_outbox;
+pub mod p2p_peer_store;
+pub mod p2p_protocols;
 pub mod p2p_pubsub;
 pub mod p2p_rdv;
+pub mod p2p_relay;
 pub mod p2p_request;
+pub mod p2p_stream;
 pub mod p2p_utils;
+pub mod p2p_validation;
+pub mod p2p_whitelist;
 pub mod sled;
+pub mod sled_blob;
+pub mod sled_log;
+pub mod sled_queue;
 pub mod minimal;
 pub mod wrapper;
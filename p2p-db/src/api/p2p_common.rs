@@ -1,17 +1,23 @@
 use flutter_rust_bridge::{frb, RustAutoOpaqueNom};
 use libp2p::autonat::NatStatus;
 pub use libp2p::core::transport::ListenerId;
-pub use libp2p::gossipsub::{Behaviour as GossipBehaviour, MessageId};
+pub use libp2p::gossipsub::MessageId;
 use libp2p::identify::Behaviour as IdentifyBehaviour;
 pub use libp2p::identity::{Keypair, PeerId, PublicKey};
 use libp2p::rendezvous::client::Behaviour as RdvClientBehaviour;
 use libp2p::rendezvous::server::Behaviour as RdvServerBehaviour;
+use super::p2p_protocols::RequestProtocols;
+use super::p2p_pubsub::GossipBehaviour;
+use super::p2p_whitelist::Whitelist;
 pub use libp2p::request_response::{self as request_response, ResponseChannel};
+pub use libp2p::swarm::behaviour::toggle::Toggle;
 pub use libp2p::swarm::ConnectionId;
 pub use libp2p::swarm::{NetworkBehaviour, Swarm, SwarmEvent};
 use libp2p::{
-    allow_block_list, autonat, connection_limits, dcutr, memory_connection_limits, relay, upnp,
+    allow_block_list, autonat, connection_limits, dcutr, kad, mdns, memory_connection_limits, relay,
+    upnp,
 };
+use std::sync::{Arc, Mutex};
 use strum_macros::{AsRefStr, IntoStaticStr};
 
 //
@@ -23,6 +29,26 @@ pub enum GenericError {
     BadAddress,
     Other(String),
     Bytes(Vec<u8>),
+    /// The behaviour was not enabled when the instance was created.
+    BehaviourDisabled,
+    /// The request-response protocol was not registered when the instance was created.
+    UnknownProtocol,
+    /// The inbound request was already answered.
+    ResponseAlreadySent,
+}
+#[frb(external)]
+#[frb(non_opaque)]
+pub enum RequestError {
+    InstanceNotFound,
+    BadAddress,
+    /// No response was received before the timeout.
+    Timeout,
+    /// The outbound request failed (dial failure, unsupported protocol...).
+    Failure(String),
+    /// The instance was dropped while waiting for the response.
+    Closed,
+    /// The request-response protocol was not registered when the instance was created.
+    UnknownProtocol,
 }
 //
 // OVERRIDES
@@ -46,30 +72,50 @@ impl MessageId {
     pub fn to_string(&self) -> String {}
 }
 #[frb(external)]
+#[derive(Clone)]
 pub struct CustomPeerRecord {
     pub peer_id: String,
     pub addresses: Vec<String>
 }
 //
+// RESPONSE CHANNEL
+//
+/// Response channel of an inbound request, shared by every event listener.
+/// Only the first response is sent, the channel is empty afterwards.
+#[frb(opaque)]
+#[derive(Clone)]
+pub struct CustomResponseChannel(Arc<Mutex<Option<ResponseChannel<Vec<u8>>>>>);
+impl CustomResponseChannel {
+    pub(crate) fn new(channel: ResponseChannel<Vec<u8>>) -> Self {
+        CustomResponseChannel(Arc::new(Mutex::new(Some(channel))))
+    }
+    pub(crate) fn take(&self) -> Option<ResponseChannel<Vec<u8>>> {
+        self.0.lock().ok().and_then(|mut channel| channel.take())
+    }
+}
+//
 // BEHAVIOURS
 //
 #[derive(NetworkBehaviour)]
 #[frb(ignore)]
 pub struct CustomBehaviour {
     pub blacklist: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
-    //pub whitelist: allow_block_list::Behaviour<allow_block_list::AllowedPeers>,
+    pub whitelist: Whitelist,
     pub memory_limits: memory_connection_limits::Behaviour,
     pub connection_limits: connection_limits::Behaviour,
-    pub upnp: upnp::tokio::Behaviour,
-    pub auto_nat: autonat::Behaviour,
+    pub upnp: Toggle<upnp::tokio::Behaviour>,
+    pub auto_nat: Toggle<autonat::Behaviour>,
     pub identify: IdentifyBehaviour,
-    pub rdv_server: RdvServerBehaviour,
-    pub rdv_client: RdvClientBehaviour,
+    pub rdv_server: Toggle<RdvServerBehaviour>,
+    pub rdv_client: Toggle<RdvClientBehaviour>,
+    pub kademlia: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
     pub pubsub: GossipBehaviour,
-    pub dcutr: dcutr::Behaviour,
-    pub relay_server: relay::Behaviour,
+    pub dcutr: Toggle<dcutr::Behaviour>,
+    pub relay_server: Toggle<relay::Behaviour>,
     pub relay_client: relay::client::Behaviour,
-    pub request_response: request_response::cbor::Behaviour<Vec<u8>, Vec<u8>>,
+    pub request_response: RequestProtocols,
+    pub stream: libp2p_stream::Behaviour,
 }
 
 //
@@ -77,7 +123,7 @@ pub struct CustomBehaviour {
 //
 #[frb(external)]
 #[frb(non_opaque)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum CustomNatStatus {
     Public(String),
     Private,
@@ -97,7 +143,7 @@ impl From<NatStatus> for CustomNatStatus {
 //
 #[frb(external)]
 #[frb(non_opaque)]
-#[derive(IntoStaticStr, AsRefStr)]
+#[derive(Clone, IntoStaticStr, AsRefStr)]
 pub enum CustomSwarmEvent {
     /// The multiaddress is reachable externally.
     UpnpNewExternalAddr(String),
@@ -115,15 +161,19 @@ pub enum CustomSwarmEvent {
     },
     /// An incoming message (request or response).
     RequestMessage {
+        /// The request-response protocol.
+        protocol: String,
         request_id: String,
         /// The peer who sent the message.
         peer: String,
         /// The incoming message.
         message: Vec<u8>,
-        channel: RustAutoOpaqueNom<ResponseChannel<Vec<u8>>>,
+        channel: RustAutoOpaqueNom<CustomResponseChannel>,
     },
     /// An incoming message (request or response).
     ResponseMessage {
+        /// The request-response protocol.
+        protocol: String,
         request_id: String,
         /// The peer who sent the message.
         peer: String,
@@ -132,6 +182,8 @@ pub enum CustomSwarmEvent {
     },
     /// An outbound request failed.
     RequestOutboundFailure {
+        /// The request-response protocol.
+        protocol: String,
         /// The peer to whom the request was sent.
         peer: String,
         /// The (local) ID of the failed request.
@@ -141,6 +193,8 @@ pub enum CustomSwarmEvent {
     },
     /// An inbound request failed.
     RequestInboundFailure {
+        /// The request-response protocol.
+        protocol: String,
         /// The peer from whom the request was received.
         peer: String,
         /// The ID of the failed inbound request.
@@ -153,11 +207,74 @@ pub enum CustomSwarmEvent {
     /// When this event is received, the response has been flushed on
     /// the underlying transport connection.
     ResponseSent {
+        /// The request-response protocol.
+        protocol: String,
         /// The peer to whom the response was sent.
         peer: String,
         /// The ID of the inbound request whose response was sent.
         request_id: String,
     },
+    /// A message of the outbox was answered, it is removed from the outbox.
+    OutboxDelivered {
+        /// The ID returned by `libp2p_outbox_send`.
+        id: u64,
+        peer: String,
+        protocol: String,
+        response: Vec<u8>,
+    },
+    /// A message of the outbox expired or used all its attempts, it is removed from the outbox.
+    OutboxFailed {
+        /// The ID returned by `libp2p_outbox_send`.
+        id: u64,
+        peer: String,
+        protocol: String,
+        attempts: u32,
+        /// The error of the last attempt, `outbox_expired` when the TTL elapsed.
+        error: String,
+    },
+    /// A file transfer started or resumed, the receiver already had `offset` bytes.
+    FileTransferStarted {
+        /// The ID returned by `libp2p_file_send`.
+        id: String,
+        peer: String,
+        /// True on the sender.
+        outbound: bool,
+        name: String,
+        size: u64,
+        offset: u64,
+    },
+    /// A chunk of the file was written to disk by the receiver.
+    FileTransferProgress {
+        id: String,
+        peer: String,
+        outbound: bool,
+        transferred: u64,
+        size: u64,
+    },
+    /// The transfer stopped before the end of the file, it resumes from the last confirmed chunk.
+    /// The error is none when paused by `libp2p_file_pause`.
+    FileTransferPaused {
+        id: String,
+        peer: String,
+        outbound: bool,
+        transferred: u64,
+        error: Option<String>,
+    },
+    /// The file was received and its hash verified, `path` is the file written by the receiver
+    /// or the file read by the sender.
+    FileTransferCompleted {
+        id: String,
+        peer: String,
+        outbound: bool,
+        path: String,
+    },
+    /// The transfer was abandoned, e.g. `file_hash_mismatch` or a file that can not be read.
+    FileTransferFailed {
+        id: String,
+        peer: String,
+        outbound: bool,
+        error: String,
+    },
     RdvClientDiscovered {
         rendezvous_node: String,
         registrations: Vec<CustomPeerRecord>,
@@ -232,6 +349,107 @@ pub enum CustomSwarmEvent {
     },
     /// A peer that does not support gossipsub has connected.
     GossipsubNotSupported { peer_id: String },
+    /// The routing table was updated with a new peer or new addresses.
+    DhtRoutingUpdated {
+        peer_id: String,
+        /// Every known address of the peer.
+        addresses: Vec<String>,
+        is_new_peer: bool,
+    },
+    /// The kademlia mode changed, server when true.
+    DhtModeChanged { server: bool },
+    /// A bootstrap step reached a peer.
+    DhtBootstrapProgressed {
+        query_id: String,
+        peer_id: String,
+        /// Buckets still to refresh.
+        num_remaining: u32,
+    },
+    /// The closest peers to the key, the searched peer is found when it is in the list.
+    DhtPeersFound {
+        query_id: String,
+        key: Vec<u8>,
+        peers: Vec<String>,
+    },
+    DhtRecordFound {
+        query_id: String,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// The peer that published the record.
+        publisher: Option<String>,
+        /// The peer the record was received from, none when found locally.
+        peer: Option<String>,
+    },
+    /// The record was stored on enough peers (quorum), republishing included.
+    DhtRecordStored { query_id: String, key: Vec<u8> },
+    DhtProvidersFound {
+        query_id: String,
+        key: Vec<u8>,
+        providers: Vec<String>,
+    },
+    /// The local peer was announced as a provider of the key, republishing included.
+    DhtProvidingStarted { query_id: String, key: Vec<u8> },
+    DhtQueryFailed { query_id: String, error: String },
+    /// Last event of a query.
+    DhtQueryFinished {
+        query_id: String,
+        num_successes: u32,
+        num_failures: u32,
+    },
+    /// Peers found on the local network.
+    MdnsDiscovered { peers: Vec<CustomPeerRecord> },
+    /// The addresses of the peers were not refreshed on the local network before their TTL.
+    MdnsExpired { peers: Vec<CustomPeerRecord> },
+    /// The relay accepted or renewed the reservation, durations are in milliseconds.
+    RelayReservationAccepted {
+        relay_peer_id: String,
+        renewal: bool,
+        limit_duration: Option<u64>,
+        limit_bytes: Option<u64>,
+    },
+    /// The reservation was refused or lost, its listener is closed.
+    RelayReservationFailed {
+        relay_peer_id: String,
+        error: String,
+    },
+    /// A circuit to a peer was opened through the relay.
+    RelayOutboundCircuitEstablished { relay_peer_id: String },
+    /// A peer opened a circuit to us through one of our relays.
+    RelayInboundCircuitEstablished { src_peer_id: String },
+    /// The relayed connection was upgraded to a direct connection.
+    DcutrSucceeded {
+        remote_peer_id: String,
+        connection_id: ConnectionId,
+    },
+    DcutrFailed {
+        remote_peer_id: String,
+        error: String,
+    },
+    /// The relay server accepted or renewed the reservation of a peer.
+    RelayServerReservationAccepted { src_peer_id: String, renewed: bool },
+    /// The relay server refused a reservation (limits reached).
+    RelayServerReservationDenied { src_peer_id: String },
+    RelayServerReservationTimedOut { src_peer_id: String },
+    RelayServerCircuitAccepted {
+        src_peer_id: String,
+        dst_peer_id: String,
+    },
+    /// The relay server refused a circuit (limits reached or no reservation of the destination).
+    RelayServerCircuitDenied {
+        src_peer_id: String,
+        dst_peer_id: String,
+    },
+    /// A circuit relayed by the server was closed after `duration` milliseconds.
+    ///
+    /// Reduced scope: the request for relay monitoring asked for the bytes relayed by each
+    /// circuit, they are not reported because libp2p enforces `max_circuit_bytes` without
+    /// exposing the traffic. The owner of the request accepted the duration instead.
+    RelayServerCircuitClosed {
+        src_peer_id: String,
+        dst_peer_id: String,
+        duration: u64,
+        error: Option<String>,
+    },
     /// A connection to the given peer has been opened.
     ConnectionEstablished {
         /// Identity of the peer that we have connected to.
@@ -0,0 +1,501 @@
+use flutter_rust_bridge::frb;
+
+//
+// CONFIG
+//
+/// Configuration of a libp2p instance, durations are in milliseconds unless stated otherwise.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug, Default)]
+pub struct Libp2pConfig {
+    pub transport: Libp2pTransportConfig,
+    pub behaviours: Libp2pBehavioursConfig,
+    pub gossipsub: Libp2pGossipsubConfig,
+    pub peer_score: Libp2pPeerScoreConfig,
+    pub identify: Libp2pIdentifyConfig,
+    pub autonat: Libp2pAutonatConfig,
+    pub connection_limits: Libp2pConnectionLimitsConfig,
+    pub relay_server: Libp2pRelayServerConfig,
+    pub rendezvous_server: Libp2pRendezvousServerConfig,
+    pub request_response: Libp2pRequestResponseConfig,
+    pub kademlia: Libp2pKademliaConfig,
+    pub mdns: Libp2pMdnsConfig,
+    pub swarm: Libp2pSwarmConfig,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pTransportConfig {
+    pub tcp_nodelay: bool,
+    pub tcp_reuse_port: bool,
+    /// Listening on a quic-v1 address fails when disabled.
+    pub quic_enabled: bool,
+    pub quic_keep_alive_interval: u64,
+    pub quic_max_idle_timeout: u32,
+    pub quic_max_concurrent_stream_limit: u32,
+    /// Private network key in the swarm.key format, only nodes sharing it complete tcp,
+    /// websocket and relayed handshakes. Quic can not be enabled with it.
+    pub pnet_psk: Option<String>,
+}
+
+/// Optional behaviours, identify, gossipsub and request-response are always enabled.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pBehavioursConfig {
+    pub upnp: bool,
+    pub autonat: bool,
+    pub dcutr: bool,
+    pub relay_server: bool,
+    pub rendezvous_server: bool,
+    pub rendezvous_client: bool,
+    pub kademlia: bool,
+    /// Local network discovery, disabled by default since it needs multicast permissions on mobile.
+    pub mdns: bool,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pGossipsubConfig {
+    pub heartbeat_delay: u64,
+    pub heartbeat_interval: u64,
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    pub history_length: usize,
+    pub history_gossip: usize,
+    pub max_transmit_size: usize,
+    pub duplicate_cache_time: u64,
+    /// Codec of the payloads, every node of the network must use the same.
+    pub compression: Libp2pCompression,
+    /// Message ids hash the topic and payload instead of the source and sequence number,
+    /// the same payload published by several peers is delivered once.
+    pub content_message_id: bool,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Libp2pCompression {
+    None,
+    Zstd,
+}
+
+/// Gossipsub peer scoring, peers below the thresholds are penalized.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pPeerScoreConfig {
+    pub enabled: bool,
+    pub topic_score_cap: f64,
+    pub app_specific_weight: f64,
+    pub ip_colocation_factor_weight: f64,
+    pub ip_colocation_factor_threshold: f64,
+    pub behaviour_penalty_weight: f64,
+    pub behaviour_penalty_threshold: f64,
+    pub behaviour_penalty_decay: f64,
+    pub decay_interval: u64,
+    pub decay_to_zero: f64,
+    pub retain_score: u64,
+    /// No gossip is exchanged with peers below this score.
+    pub gossip_threshold: f64,
+    /// No message is published to peers below this score.
+    pub publish_threshold: f64,
+    /// Every message of peers below this score is ignored.
+    pub graylist_threshold: f64,
+    pub accept_px_threshold: f64,
+    pub opportunistic_graft_threshold: f64,
+    /// Per topic parameters set when the instance is created.
+    pub topics: Vec<Libp2pTopicScoreConfig>,
+}
+
+/// Score parameters of a topic.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pTopicScoreConfig {
+    pub topic: String,
+    pub topic_weight: f64,
+    pub time_in_mesh_weight: f64,
+    pub time_in_mesh_quantum: u64,
+    pub time_in_mesh_cap: f64,
+    pub first_message_deliveries_weight: f64,
+    pub first_message_deliveries_decay: f64,
+    pub first_message_deliveries_cap: f64,
+    pub mesh_message_deliveries_weight: f64,
+    pub mesh_message_deliveries_decay: f64,
+    pub mesh_message_deliveries_cap: f64,
+    pub mesh_message_deliveries_threshold: f64,
+    pub mesh_message_deliveries_window: u64,
+    pub mesh_message_deliveries_activation: u64,
+    pub mesh_failure_penalty_weight: f64,
+    pub mesh_failure_penalty_decay: f64,
+    pub invalid_message_deliveries_weight: f64,
+    pub invalid_message_deliveries_decay: f64,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pIdentifyConfig {
+    pub protocol_version: String,
+    /// Defaults to the libp2p agent version when none.
+    pub agent_version: Option<String>,
+    pub interval: u64,
+    pub push_listen_addr_updates: bool,
+    pub cache_size: usize,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pAutonatConfig {
+    pub timeout: u64,
+    pub boot_delay: u64,
+    pub refresh_interval: u64,
+    pub retry_interval: u64,
+    pub only_global_ips: bool,
+    pub use_connected: bool,
+    pub confidence_max: usize,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pConnectionLimitsConfig {
+    pub max_established: Option<u32>,
+    pub max_established_incoming: Option<u32>,
+    pub max_established_outgoing: Option<u32>,
+    pub max_pending_incoming: Option<u32>,
+    pub max_pending_outgoing: Option<u32>,
+    pub max_memory_percentage: f64,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pRelayServerConfig {
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration: u64,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub max_circuit_duration: u64,
+    pub max_circuit_bytes: u64,
+    /// Reservations accepted per peer and per ip address during `rate_limit_interval`,
+    /// 0 for no limit.
+    pub reservation_rate_limit: u32,
+    /// Circuits opened per source peer and per source ip address during `rate_limit_interval`,
+    /// 0 for no limit.
+    pub circuit_rate_limit: u32,
+    /// Must not be 0 when a rate limit is set.
+    pub rate_limit_interval: u64,
+}
+
+/// TTLs are in seconds.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pRendezvousServerConfig {
+    pub min_ttl: u64,
+    pub max_ttl: u64,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pRequestResponseConfig {
+    /// The first protocol is used when a request does not name one.
+    pub protocols: Vec<Libp2pRequestProtocolConfig>,
+}
+
+/// Sizes are in bytes.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pRequestProtocolConfig {
+    /// Protocol name, must start with a `/`.
+    pub name: String,
+    pub request_timeout: u64,
+    pub max_concurrent_streams: usize,
+    /// Limits of the cbor encoded messages.
+    pub max_request_size: u64,
+    pub max_response_size: u64,
+}
+
+/// TTLs are in seconds, records never expire when none.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pKademliaConfig {
+    /// Protocol name, nodes only talk to nodes using the same name.
+    pub protocol_name: String,
+    pub query_timeout: u64,
+    pub replication_factor: usize,
+    pub record_ttl: Option<u64>,
+    pub provider_record_ttl: Option<u64>,
+    /// Server (true) or client (false) mode, picked from the confirmed external addresses when none.
+    pub server_mode: Option<bool>,
+    /// Multiaddresses ending with `/p2p/<peer id>`, the bootstrap starts when not empty.
+    pub bootstrap_peers: Vec<String>,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pMdnsConfig {
+    pub ttl: u64,
+    pub query_interval: u64,
+    pub enable_ipv6: bool,
+    /// Dial the discovered peers that are not connected yet.
+    pub auto_dial: bool,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct Libp2pSwarmConfig {
+    pub idle_connection_timeout: u64,
+    /// Number of events a listener or a subscription may lag behind, the oldest events are
+    /// dropped for the ones that fall further behind.
+    pub event_buffer_size: usize,
+    /// Only the whitelisted peers may connect, switchable with `libp2p_set_whitelist_only`.
+    pub whitelist_only: bool,
+}
+
+//
+// DEFAULTS
+//
+impl Default for Libp2pTransportConfig {
+    fn default() -> Self {
+        Libp2pTransportConfig {
+            tcp_nodelay: true,
+            tcp_reuse_port: false,
+            quic_enabled: false,
+            quic_keep_alive_interval: 5_000,
+            quic_max_idle_timeout: 10_000,
+            quic_max_concurrent_stream_limit: 256,
+            pnet_psk: None,
+        }
+    }
+}
+
+impl Default for Libp2pBehavioursConfig {
+    fn default() -> Self {
+        Libp2pBehavioursConfig {
+            upnp: true,
+            autonat: true,
+            dcutr: true,
+            relay_server: true,
+            rendezvous_server: true,
+            rendezvous_client: true,
+            kademlia: false,
+            mdns: false,
+        }
+    }
+}
+
+impl Default for Libp2pGossipsubConfig {
+    fn default() -> Self {
+        Libp2pGossipsubConfig {
+            heartbeat_delay: 5_000,
+            heartbeat_interval: 1_000,
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            history_length: 5,
+            history_gossip: 3,
+            max_transmit_size: 65_536,
+            duplicate_cache_time: 60_000,
+            compression: Libp2pCompression::None,
+            content_message_id: false,
+        }
+    }
+}
+
+impl Default for Libp2pPeerScoreConfig {
+    fn default() -> Self {
+        Libp2pPeerScoreConfig {
+            enabled: false,
+            topic_score_cap: 3600.0,
+            app_specific_weight: 10.0,
+            ip_colocation_factor_weight: -5.0,
+            ip_colocation_factor_threshold: 10.0,
+            behaviour_penalty_weight: -10.0,
+            behaviour_penalty_threshold: 0.0,
+            behaviour_penalty_decay: 0.2,
+            decay_interval: 1_000,
+            decay_to_zero: 0.1,
+            retain_score: 60 * 60_000,
+            gossip_threshold: -10.0,
+            publish_threshold: -50.0,
+            graylist_threshold: -80.0,
+            accept_px_threshold: 10.0,
+            opportunistic_graft_threshold: 20.0,
+            topics: vec![],
+        }
+    }
+}
+
+impl Default for Libp2pTopicScoreConfig {
+    fn default() -> Self {
+        Libp2pTopicScoreConfig {
+            topic: String::new(),
+            topic_weight: 0.5,
+            time_in_mesh_weight: 1.0,
+            time_in_mesh_quantum: 1,
+            time_in_mesh_cap: 3600.0,
+            first_message_deliveries_weight: 1.0,
+            first_message_deliveries_decay: 0.5,
+            first_message_deliveries_cap: 2000.0,
+            mesh_message_deliveries_weight: -1.0,
+            mesh_message_deliveries_decay: 0.5,
+            mesh_message_deliveries_cap: 100.0,
+            mesh_message_deliveries_threshold: 20.0,
+            mesh_message_deliveries_window: 10,
+            mesh_message_deliveries_activation: 5_000,
+            mesh_failure_penalty_weight: -1.0,
+            mesh_failure_penalty_decay: 0.5,
+            invalid_message_deliveries_weight: -1.0,
+            invalid_message_deliveries_decay: 0.3,
+        }
+    }
+}
+
+impl Default for Libp2pIdentifyConfig {
+    fn default() -> Self {
+        Libp2pIdentifyConfig {
+            protocol_version: "rendezvous/1.0.0".to_string(),
+            agent_version: None,
+            interval: 5 * 60_000,
+            push_listen_addr_updates: false,
+            cache_size: 100,
+        }
+    }
+}
+
+impl Default for Libp2pAutonatConfig {
+    fn default() -> Self {
+        Libp2pAutonatConfig {
+            timeout: 30_000,
+            boot_delay: 15_000,
+            refresh_interval: 15 * 60_000,
+            retry_interval: 90_000,
+            only_global_ips: true,
+            use_connected: true,
+            confidence_max: 3,
+        }
+    }
+}
+
+impl Default for Libp2pConnectionLimitsConfig {
+    fn default() -> Self {
+        Libp2pConnectionLimitsConfig {
+            max_established: None,
+            max_established_incoming: None,
+            max_established_outgoing: None,
+            max_pending_incoming: None,
+            max_pending_outgoing: None,
+            max_memory_percentage: 0.9,
+        }
+    }
+}
+
+impl Default for Libp2pRelayServerConfig {
+    fn default() -> Self {
+        Libp2pRelayServerConfig {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration: 60 * 60_000,
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration: 2 * 60_000,
+            max_circuit_bytes: 1 << 17,
+            reservation_rate_limit: 30,
+            circuit_rate_limit: 30,
+            rate_limit_interval: 2 * 60_000,
+        }
+    }
+}
+
+impl Default for Libp2pRendezvousServerConfig {
+    fn default() -> Self {
+        Libp2pRendezvousServerConfig {
+            min_ttl: libp2p::rendezvous::MIN_TTL,
+            max_ttl: libp2p::rendezvous::MAX_TTL,
+        }
+    }
+}
+
+impl Default for Libp2pRequestResponseConfig {
+    fn default() -> Self {
+        Libp2pRequestResponseConfig {
+            protocols: vec![Libp2pRequestProtocolConfig::default()],
+        }
+    }
+}
+
+impl Default for Libp2pRequestProtocolConfig {
+    fn default() -> Self {
+        Libp2pRequestProtocolConfig {
+            name: "/transfer/1.0.0".to_string(),
+            request_timeout: 10_000,
+            max_concurrent_streams: 100,
+            max_request_size: 1024 * 1024,
+            max_response_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for Libp2pKademliaConfig {
+    fn default() -> Self {
+        Libp2pKademliaConfig {
+            protocol_name: "/p2p-db/kad/1.0.0".to_string(),
+            query_timeout: 60_000,
+            replication_factor: 20,
+            record_ttl: Some(36 * 60 * 60),
+            provider_record_ttl: Some(24 * 60 * 60),
+            server_mode: None,
+            bootstrap_peers: vec![],
+        }
+    }
+}
+
+impl Default for Libp2pMdnsConfig {
+    fn default() -> Self {
+        Libp2pMdnsConfig {
+            ttl: 6 * 60_000,
+            query_interval: 5 * 60_000,
+            enable_ipv6: false,
+            auto_dial: true,
+        }
+    }
+}
+
+impl Default for Libp2pSwarmConfig {
+    fn default() -> Self {
+        Libp2pSwarmConfig {
+            idle_connection_timeout: 5_000,
+            event_buffer_size: 1024,
+            whitelist_only: false,
+        }
+    }
+}
+
+//
+// FUNCTIONS
+//
+#[frb(sync)]
+pub fn libp2p_default_config() -> Libp2pConfig {
+    Libp2pConfig::default()
+}
+
+#[frb(sync)]
+pub fn libp2p_default_topic_score_config(topic: String) -> Libp2pTopicScoreConfig {
+    Libp2pTopicScoreConfig {
+        topic,
+        ..Libp2pTopicScoreConfig::default()
+    }
+}
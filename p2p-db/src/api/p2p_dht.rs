@@ -0,0 +1,473 @@
+use super::{
+    p2p_common::{CustomBehaviour, CustomSwarmEvent, GenericError},
+    sled::CustomDB,
+    wrapper::Wrapper,
+};
+use libp2p::kad::{self, store::MemoryStore, GetProvidersOk, GetRecordOk, QueryResult, Quorum};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId, Swarm};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+//
+// CONSTANTS
+//
+const TREE_ROUTING_TABLE: &str = "__dht_routing_table";
+pub(crate) const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+pub(crate) type KadBehaviour = kad::Behaviour<MemoryStore>;
+
+//
+// HELPERS
+//
+pub(crate) fn kad_mode(server_mode: Option<bool>) -> Option<kad::Mode> {
+    server_mode.map(|server| match server {
+        true => kad::Mode::Server,
+        false => kad::Mode::Client,
+    })
+}
+
+/// Split a multiaddress ending with `/p2p/<peer id>` into the peer and its address.
+pub(crate) fn parse_peer_address(address: &str) -> Result<(PeerId, Multiaddr), GenericError> {
+    let mut multiaddr = address
+        .parse::<Multiaddr>()
+        .map_err(|_| GenericError::BadAddress)?;
+    match multiaddr.pop() {
+        Some(Protocol::P2p(peer)) => Ok((peer, multiaddr)),
+        _ => Err(GenericError::BadAddress),
+    }
+}
+
+fn kademlia(swarm: &mut Swarm<CustomBehaviour>) -> Result<&mut KadBehaviour, GenericError> {
+    swarm
+        .behaviour_mut()
+        .kademlia
+        .as_mut()
+        .ok_or(GenericError::BehaviourDisabled)
+}
+
+//
+// QUERIES
+//
+// query ids are returned as strings, the results come as Dht* events
+pub async fn libp2p_dht_add_address(
+    wrapper: &Arc<Wrapper>,
+    address: String,
+) -> Result<bool, GenericError> {
+    let (peer, multiaddr) = parse_peer_address(&address)?;
+    wrapper
+        .with_swarm(move |swarm| {
+            let update = kademlia(swarm)?.add_address(&peer, multiaddr);
+            Ok(!matches!(update, kad::RoutingUpdate::Failed))
+        })
+        .await?
+}
+
+pub async fn libp2p_dht_bootstrap(wrapper: &Arc<Wrapper>) -> Result<String, GenericError> {
+    wrapper
+        .with_swarm(|swarm| {
+            kademlia(swarm)?
+                .bootstrap()
+                .map(|query_id| query_id.to_string())
+                .map_err(|e| GenericError::Other(e.to_string()))
+        })
+        .await?
+}
+
+/// Look for the closest peers to the given peer, the peer is found when it is in the list.
+pub async fn libp2p_dht_find_peer(
+    wrapper: &Arc<Wrapper>,
+    peerid: String,
+) -> Result<String, GenericError> {
+    let peer = peerid
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    wrapper
+        .with_swarm(move |swarm| Ok(kademlia(swarm)?.get_closest_peers(peer).to_string()))
+        .await?
+}
+
+/// Store the record locally and on the closest peers,
+/// `quorum` is the number of peers that must store it (one when none).
+pub async fn libp2p_dht_put_record(
+    wrapper: &Arc<Wrapper>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    quorum: Option<usize>,
+) -> Result<String, GenericError> {
+    let quorum = match quorum.and_then(NonZeroUsize::new) {
+        Some(count) => Quorum::N(count),
+        None => Quorum::One,
+    };
+    wrapper
+        .with_swarm(move |swarm| {
+            let record = kad::Record::new(key, value);
+            kademlia(swarm)?
+                .put_record(record, quorum)
+                .map(|query_id| query_id.to_string())
+                .map_err(|e| GenericError::Other(e.to_string()))
+        })
+        .await?
+}
+
+pub async fn libp2p_dht_get_record(
+    wrapper: &Arc<Wrapper>,
+    key: Vec<u8>,
+) -> Result<String, GenericError> {
+    wrapper
+        .with_swarm(move |swarm| {
+            let query_id = kademlia(swarm)?.get_record(kad::RecordKey::new(&key));
+            Ok(query_id.to_string())
+        })
+        .await?
+}
+
+/// Announce the local peer as a provider of the key.
+pub async fn libp2p_dht_provide(
+    wrapper: &Arc<Wrapper>,
+    key: Vec<u8>,
+) -> Result<String, GenericError> {
+    wrapper
+        .with_swarm(move |swarm| {
+            kademlia(swarm)?
+                .start_providing(kad::RecordKey::new(&key))
+                .map(|query_id| query_id.to_string())
+                .map_err(|e| GenericError::Other(e.to_string()))
+        })
+        .await?
+}
+
+pub async fn libp2p_dht_stop_providing(
+    wrapper: &Arc<Wrapper>,
+    key: Vec<u8>,
+) -> Result<(), GenericError> {
+    wrapper
+        .with_swarm(move |swarm| {
+            kademlia(swarm)?.stop_providing(&kad::RecordKey::new(&key));
+            Ok(())
+        })
+        .await?
+}
+
+pub async fn libp2p_dht_get_providers(
+    wrapper: &Arc<Wrapper>,
+    key: Vec<u8>,
+) -> Result<String, GenericError> {
+    wrapper
+        .with_swarm(move |swarm| {
+            let query_id = kademlia(swarm)?.get_providers(kad::RecordKey::new(&key));
+            Ok(query_id.to_string())
+        })
+        .await?
+}
+
+/// Server (true) or client (false) mode, picked from the confirmed external addresses when none.
+pub async fn libp2p_dht_set_mode(
+    wrapper: &Arc<Wrapper>,
+    server_mode: Option<bool>,
+) -> Result<(), GenericError> {
+    wrapper
+        .with_swarm(move |swarm| {
+            kademlia(swarm)?.set_mode(kad_mode(server_mode));
+            Ok(())
+        })
+        .await?
+}
+
+//
+// ROUTING TABLE
+//
+/// Routing table attached to a swarm, saved by the swarm task every `SAVE_INTERVAL`
+/// and when it stops.
+pub(crate) struct RoutingTableStore {
+    tree: sled::Tree,
+}
+
+impl RoutingTableStore {
+    /// Replace the saved routing table with the current one, returns the number of peers saved.
+    pub(crate) fn save(&self, swarm: &mut Swarm<CustomBehaviour>) -> Result<usize, GenericError> {
+        let mut entries = Vec::new();
+        for bucket in kademlia(swarm)?.kbuckets() {
+            for entry in bucket.iter() {
+                let peer = entry.node.key.preimage();
+                entries.push((peer.to_bytes(), encode_addresses(entry.node.value.iter())));
+            }
+        }
+        let mut batch = sled::Batch::default();
+        for key in self.tree.iter().keys() {
+            batch.remove(key.map_err(|e| GenericError::Other(e.to_string()))?);
+        }
+        for (peer, addresses) in entries.iter() {
+            batch.insert(peer.as_slice(), addresses.as_slice());
+        }
+        self.tree
+            .apply_batch(batch)
+            .map_err(|e| GenericError::Other(e.to_string()))?;
+        Ok(entries.len())
+    }
+
+    /// Add the saved peers to the routing table and bootstrap when there are any,
+    /// returns the number of peers loaded. Entries that cannot be decoded are removed.
+    fn load(&self, swarm: &mut Swarm<CustomBehaviour>) -> Result<usize, GenericError> {
+        let mut entries = Vec::new();
+        let mut bad_entries = sled::Batch::default();
+        for current in self.tree.iter() {
+            let (key, value) = current.map_err(|e| GenericError::Other(e.to_string()))?;
+            match (PeerId::from_bytes(&key), decode_addresses(&value)) {
+                (Ok(peer), Ok(addresses)) => entries.push((peer, addresses)),
+                _ => bad_entries.remove(key),
+            }
+        }
+        self.tree
+            .apply_batch(bad_entries)
+            .map_err(|e| GenericError::Other(e.to_string()))?;
+        let kademlia = kademlia(swarm)?;
+        let count = entries.len();
+        for (peer, addresses) in entries {
+            for address in addresses {
+                kademlia.add_address(&peer, address);
+            }
+        }
+        if count > 0 {
+            // the routing table is not empty, bootstrap cannot fail
+            let _ = kademlia.bootstrap();
+        }
+        Ok(count)
+    }
+}
+
+/// Load the routing table saved in the database, then keep saving it every `SAVE_INTERVAL`
+/// and when the instance stops. Returns the number of peers loaded.
+pub async fn libp2p_dht_attach(
+    wrapper: &Arc<Wrapper>,
+    db: &Arc<CustomDB>,
+) -> Result<usize, GenericError> {
+    let store = open_store(db)?;
+    wrapper
+        .call(move |state| {
+            let count = store.load(&mut state.swarm)?;
+            state.routing_table = Some(store);
+            Ok(count)
+        })
+        .await?
+}
+
+/// Replace the saved routing table with the current one, returns the number of peers saved.
+pub async fn libp2p_dht_save_routing_table(
+    wrapper: &Arc<Wrapper>,
+    db: &Arc<CustomDB>,
+) -> Result<usize, GenericError> {
+    let store = open_store(db)?;
+    wrapper.with_swarm(move |swarm| store.save(swarm)).await?
+}
+
+/// Add the saved peers to the routing table and bootstrap when there are any,
+/// returns the number of peers loaded.
+pub async fn libp2p_dht_load_routing_table(
+    wrapper: &Arc<Wrapper>,
+    db: &Arc<CustomDB>,
+) -> Result<usize, GenericError> {
+    let store = open_store(db)?;
+    wrapper.with_swarm(move |swarm| store.load(swarm)).await?
+}
+
+fn open_store(db: &Arc<CustomDB>) -> Result<RoutingTableStore, GenericError> {
+    let tree = db
+        .db
+        .open_tree(TREE_ROUTING_TABLE)
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    Ok(RoutingTableStore { tree })
+}
+
+// each address is prefixed by its length (u16)
+fn encode_addresses<'a>(addresses: impl Iterator<Item = &'a Multiaddr>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for address in addresses {
+        let address = address.to_vec();
+        bytes.extend_from_slice(&(address.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&address);
+    }
+    bytes
+}
+
+fn decode_addresses(bytes: &[u8]) -> Result<Vec<Multiaddr>, String> {
+    let mut addresses = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < 2 {
+            return Err("truncated routing table entry".to_string());
+        }
+        let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        if rest.len() < 2 + len {
+            return Err("truncated routing table entry".to_string());
+        }
+        let address = Multiaddr::try_from(rest[2..2 + len].to_vec()).map_err(|e| e.to_string())?;
+        addresses.push(address);
+        rest = &rest[2 + len..];
+    }
+    Ok(addresses)
+}
+
+//
+// EVENTS
+//
+/// Convert a kademlia event, a query step may give a result and the end of the query.
+pub(crate) fn dht_events(event: kad::Event) -> Vec<CustomSwarmEvent> {
+    let mut events = Vec::new();
+    match event {
+        kad::Event::RoutingUpdated {
+            peer,
+            is_new_peer,
+            addresses,
+            ..
+        } => events.push(CustomSwarmEvent::DhtRoutingUpdated {
+            peer_id: peer.to_string(),
+            addresses: addresses.iter().map(|f| f.to_string()).collect(),
+            is_new_peer,
+        }),
+        kad::Event::ModeChanged { new_mode } => events.push(CustomSwarmEvent::DhtModeChanged {
+            server: new_mode == kad::Mode::Server,
+        }),
+        kad::Event::OutboundQueryProgressed {
+            id,
+            result,
+            stats,
+            step,
+        } => {
+            let query_id = id.to_string();
+            let failed = |error: String| CustomSwarmEvent::DhtQueryFailed {
+                query_id: query_id.clone(),
+                error,
+            };
+            match result {
+                QueryResult::Bootstrap(Ok(ok)) => {
+                    events.push(CustomSwarmEvent::DhtBootstrapProgressed {
+                        query_id: query_id.clone(),
+                        peer_id: ok.peer.to_string(),
+                        num_remaining: ok.num_remaining,
+                    })
+                }
+                QueryResult::GetClosestPeers(Ok(ok)) => {
+                    events.push(CustomSwarmEvent::DhtPeersFound {
+                        query_id: query_id.clone(),
+                        key: ok.key,
+                        peers: ok.peers.iter().map(|f| f.to_string()).collect(),
+                    })
+                }
+                QueryResult::GetRecord(Ok(GetRecordOk::FoundRecord(found))) => {
+                    events.push(CustomSwarmEvent::DhtRecordFound {
+                        query_id: query_id.clone(),
+                        key: found.record.key.to_vec(),
+                        value: found.record.value,
+                        publisher: found.record.publisher.map(|f| f.to_string()),
+                        peer: found.peer.map(|f| f.to_string()),
+                    })
+                }
+                QueryResult::GetRecord(Ok(GetRecordOk::FinishedWithNoAdditionalRecord {
+                    ..
+                })) => {}
+                QueryResult::PutRecord(Ok(ok)) | QueryResult::RepublishRecord(Ok(ok)) => events
+                    .push(CustomSwarmEvent::DhtRecordStored {
+                        query_id: query_id.clone(),
+                        key: ok.key.to_vec(),
+                    }),
+                QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders {
+                    key,
+                    providers,
+                })) => events.push(CustomSwarmEvent::DhtProvidersFound {
+                    query_id: query_id.clone(),
+                    key: key.to_vec(),
+                    providers: providers.iter().map(|f| f.to_string()).collect(),
+                }),
+                QueryResult::GetProviders(Ok(GetProvidersOk::FinishedWithNoAdditionalRecord {
+                    ..
+                })) => {}
+                QueryResult::StartProviding(Ok(ok)) | QueryResult::RepublishProvider(Ok(ok)) => {
+                    events.push(CustomSwarmEvent::DhtProvidingStarted {
+                        query_id: query_id.clone(),
+                        key: ok.key.to_vec(),
+                    })
+                }
+                QueryResult::Bootstrap(Err(e)) => events.push(failed(e.to_string())),
+                QueryResult::GetClosestPeers(Err(e)) => events.push(failed(e.to_string())),
+                QueryResult::GetRecord(Err(e)) => events.push(failed(e.to_string())),
+                QueryResult::PutRecord(Err(e)) | QueryResult::RepublishRecord(Err(e)) => {
+                    events.push(failed(e.to_string()))
+                }
+                QueryResult::GetProviders(Err(e)) => events.push(failed(e.to_string())),
+                QueryResult::StartProviding(Err(e)) | QueryResult::RepublishProvider(Err(e)) => {
+                    events.push(failed(e.to_string()))
+                }
+            }
+            if step.last {
+                events.push(CustomSwarmEvent::DhtQueryFinished {
+                    query_id,
+                    num_successes: stats.num_successes(),
+                    num_failures: stats.num_failures(),
+                });
+            }
+        }
+        kad::Event::InboundRequest { .. } => {}
+        kad::Event::UnroutablePeer { .. } => {}
+        kad::Event::RoutablePeer { .. } => {}
+        kad::Event::PendingRoutablePeer { .. } => {}
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sled::sled_db_open;
+    use crate::test_utils::{test_config, test_instance};
+
+    fn kbucket_peers(swarm: &mut Swarm<CustomBehaviour>) -> Vec<PeerId> {
+        let mut peers = Vec::new();
+        for bucket in kademlia(swarm).ok().unwrap().kbuckets() {
+            for entry in bucket.iter() {
+                peers.push(*entry.node.key.preimage());
+            }
+        }
+        peers
+    }
+
+    #[test]
+    fn addresses_round_trip() {
+        let addresses: Vec<Multiaddr> = vec![
+            "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            "/ip6/::1/udp/4001/quic-v1".parse().unwrap(),
+            "/dns4/example.com/tcp/443/wss".parse().unwrap(),
+        ];
+        let bytes = encode_addresses(addresses.iter());
+        assert_eq!(decode_addresses(&bytes), Ok(addresses));
+        assert_eq!(decode_addresses(&encode_addresses([].iter())), Ok(vec![]));
+        assert!(decode_addresses(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode_addresses(&[0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn routing_table_saved_and_loaded() {
+        let path = std::env::temp_dir().join(format!("p2p_dht_table_{}", std::process::id()));
+        let db = sled_db_open(path.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap();
+        let mut config = test_config();
+        config.behaviours.kademlia = true;
+        let first = test_instance(config.clone()).await;
+        let peer = PeerId::random();
+        let address = format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", peer);
+        assert!(libp2p_dht_add_address(&first, address).await.is_ok());
+        let saved = libp2p_dht_save_routing_table(&first, &db).await;
+        assert_eq!(saved.ok(), Some(1));
+        // a corrupted entry is dropped instead of failing the load
+        let store = open_store(&db).ok().unwrap();
+        store.tree.insert(b"not a peer", vec![0]).unwrap();
+        let second = test_instance(config).await;
+        let loaded = libp2p_dht_load_routing_table(&second, &db).await;
+        assert_eq!(loaded.ok(), Some(1));
+        assert_eq!(store.tree.len(), 1);
+        let peers = second.with_swarm(kbucket_peers).await.ok().unwrap();
+        assert_eq!(peers, vec![peer]);
+    }
+}
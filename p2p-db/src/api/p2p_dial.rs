@@ -1,32 +1,26 @@
 use super::{
-    p2p_common::{CustomBehaviour, GenericError},
+    p2p_common::GenericError,
     wrapper::Wrapper,
 };
-use libp2p::{swarm::{dial_opts::DialOpts, ConnectionId}, Multiaddr, PeerId, Swarm};
-use std::borrow::Borrow;
+use libp2p::{swarm::{dial_opts::DialOpts, ConnectionId}, Multiaddr, PeerId};
 use std::sync::Arc;
 
 pub async fn libp2p_dial_address(
     wrapper: &Arc<Wrapper>,
     address: String,
 ) -> Result<ConnectionId, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        // dial address
-        let multi_unsafe: Result<Multiaddr, _> = address.parse::<Multiaddr>();
-        if let Ok(multiaddr) = multi_unsafe {
-            let dial_opts: DialOpts = multiaddr.into();
-            let id = dial_opts.connection_id();
-            let res_dial = swarm.dial(dial_opts);
-            match res_dial {
-                Ok(_) => return Ok(id),
-                Err(err) => return Err(GenericError::Other(err.to_string())),
-            }
-        } else {
-            Err(GenericError::BadAddress)
+    // dial address
+    let multi_unsafe: Result<Multiaddr, _> = address.parse::<Multiaddr>();
+    if let Ok(multiaddr) = multi_unsafe {
+        let dial_opts: DialOpts = multiaddr.into();
+        let id = dial_opts.connection_id();
+        let res_dial = wrapper.with_swarm(move |swarm| swarm.dial(dial_opts)).await?;
+        match res_dial {
+            Ok(_) => Ok(id),
+            Err(err) => Err(GenericError::Other(err.to_string())),
         }
     } else {
-        Err(GenericError::InstanceNotFound)
+        Err(GenericError::BadAddress)
     }
 }
 
@@ -34,23 +28,29 @@ pub async fn libp2p_dial_peer(
     wrapper: &Arc<Wrapper>,
     peerid: String,
 ) -> Result<ConnectionId, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        // dial peer
-        let peer_unsafe: Result<PeerId, _> = peerid.parse::<PeerId>();
-        if let Ok(peer) = peer_unsafe {
-            let dial_opts: DialOpts = peer.into();
+    // dial peer
+    let peer_unsafe: Result<PeerId, _> = peerid.parse::<PeerId>();
+    if let Ok(peer) = peer_unsafe {
+        let res_dial = wrapper.call(move |state| {
+            // addresses from the peer store complete the ones known by the behaviours
+            let addresses = state
+                .peer_store
+                .as_ref()
+                .map(|store| store.addresses(&peer))
+                .unwrap_or_default();
+            let dial_opts = DialOpts::peer_id(peer)
+                .addresses(addresses)
+                .extend_addresses_through_behaviour()
+                .build();
             let id = dial_opts.connection_id();
-            let res_dial = swarm.dial(dial_opts);
-            match res_dial {
-                Ok(_) => return Ok(id),
-                Err(err) => return Err(GenericError::Other(err.to_string())),
-            }
-        } else {
-            Err(GenericError::BadAddress)
+            state.swarm.dial(dial_opts).map(|_| id)
+        }).await?;
+        match res_dial {
+            Ok(id) => Ok(id),
+            Err(err) => Err(GenericError::Other(err.to_string())),
         }
     } else {
-        Err(GenericError::InstanceNotFound)
+        Err(GenericError::BadAddress)
     }
 }
 
@@ -61,11 +61,9 @@ pub async fn libp2p_isconnected(
     let peer_unsafe = peer.parse::<PeerId>();
     match peer_unsafe {
         Ok(peer_id) => {
-            let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-            let res = swarm
-                .ok_or(GenericError::InstanceNotFound)?
-                .is_connected(peer_id.borrow());
-            Ok(res)
+            wrapper
+                .with_swarm(move |swarm| swarm.is_connected(&peer_id))
+                .await
         }
         Err(e) => Err(GenericError::Other(e.to_string())),
     }
@@ -74,21 +72,17 @@ pub async fn libp2p_isconnected(
 pub async fn libp2p_connected_peers(
     wrapper: &Arc<Wrapper>,
 ) -> Result<Vec<String>, GenericError> {
-    let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    let res = swarm
-        .ok_or(GenericError::InstanceNotFound)?
-        .connected_peers().map(|e|e.to_string()).collect();
-    Ok(res)
+    wrapper
+        .with_swarm(|swarm| swarm.connected_peers().map(|e|e.to_string()).collect())
+        .await
 }
 
 pub async fn libp2p_connected_peers_count(
     wrapper: &Arc<Wrapper>,
 ) -> Result<usize, GenericError> {
-    let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    let res = swarm
-        .ok_or(GenericError::InstanceNotFound)?
-        .connected_peers().count();
-    Ok(res)
+    wrapper
+        .with_swarm(|swarm| swarm.connected_peers().count())
+        .await
 }
 
 pub async fn libp2p_disconnect_peer(
@@ -98,11 +92,11 @@ pub async fn libp2p_disconnect_peer(
     let peer_unsafe = peerid.parse::<PeerId>();
     match peer_unsafe {
         Ok(peer_id) => {
-            let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-            let was_connected = swarm
-                .ok_or(GenericError::InstanceNotFound)?
-                .disconnect_peer_id(peer_id).map_or_else(|_|false, |_|true);
-            Ok(was_connected)
+            wrapper
+                .with_swarm(move |swarm| {
+                    swarm.disconnect_peer_id(peer_id).map_or_else(|_|false, |_|true)
+                })
+                .await
         }
         Err(e) => Err(GenericError::Other(e.to_string())),
     }
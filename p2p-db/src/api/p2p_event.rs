@@ -1,13 +1,27 @@
 use super::{
-    p2p_common::{CustomBehaviour, CustomBehaviourEvent, CustomPeerRecord, GenericError},
-    wrapper::Wrapper,
+    p2p_common::{
+        CustomBehaviourEvent, CustomPeerRecord, GenericError, RequestError,
+    },
+    p2p_dht::{self, dht_events},
+    p2p_mdns::handle_mdns_event,
+    p2p_outbox::{self, outbox_failure, outbox_response},
+    p2p_rdv,
+    p2p_relay::{dcutr_event, relay_client_event, relay_listener_closed},
+    p2p_request::take_pending_request,
+    p2p_validation::SWEEP_INTERVAL,
+    wrapper::{SwarmCommand, SwarmState, Wrapper},
 };
-use crate::api::p2p_common::{CustomNatStatus, CustomSwarmEvent};
+use crate::api::p2p_common::{CustomNatStatus, CustomResponseChannel, CustomSwarmEvent};
+use crate::frb_generated::StreamSink;
 use flutter_rust_bridge::{frb, DartFnFuture, RustAutoOpaqueNom};
 use libp2p::futures::StreamExt;
+use libp2p::swarm::SwarmEvent;
+use libp2p::gossipsub::MessageAcceptance;
 use libp2p::{autonat, gossipsub, identify, request_response};
-use libp2p::{swarm::SwarmEvent, Swarm};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
 
 #[frb(sync)]
@@ -15,422 +29,555 @@ pub fn libp2p_get_event_name(event: CustomSwarmEvent) -> String {
     let right: &str = event.as_ref();
     return right.to_string();
 }
+
+/// Forward the swarm events to the callback, any number of listeners can run at the same time.
+/// A listener that falls too far behind misses the oldest events.
 pub async fn libp2p_add_event_listener(
     wrapper: &Arc<Wrapper>,
     cancellation_token: &CancellationToken,
     callback: impl Fn(CustomSwarmEvent) -> DartFnFuture<()> + Send + 'static,
 ) -> Result<bool, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    match swarm_opt {
-        None => return Err(GenericError::InstanceNotFound),
-        Some(swarm) => {
-            let boxed_callback: Box<dyn Fn(CustomSwarmEvent) -> DartFnFuture<()> + Send + 'static> =
-                Box::new(callback);
-            loop {
-                tokio::select! {
-                    _ = cancellation_token.cancelled() => {
-                        return Ok(false);
-                    },
-                    unsafe_event = swarm.next() => {
-                        match unsafe_event {
-                            None => return Ok(true),
-                            Some(event) => {
-                                match event {
-                                    SwarmEvent::Behaviour(custom) => match custom {
-                                        //CustomBehaviourEvent::Whitelist{..} => {},
-                                        CustomBehaviourEvent::Blacklist{..} => {},
-                                        CustomBehaviourEvent::MemoryLimits{..} => {},
-                                        CustomBehaviourEvent::ConnectionLimits{..} => {},
-                                        CustomBehaviourEvent::Upnp(e)=>{
-                                            match e {
-                                                libp2p::upnp::Event::NewExternalAddr(val) => {
-                                                    boxed_callback(CustomSwarmEvent::UpnpNewExternalAddr(val.to_string()))
-                                                    .await;
-                                                    ()
-                                                },
-                                                libp2p::upnp::Event::ExpiredExternalAddr(val) => {
-                                                    boxed_callback(CustomSwarmEvent::UpnpExpiredExternalAddr(val.to_string()))
-                                                    .await;
-                                                    ()
-                                                },
-                                                libp2p::upnp::Event::GatewayNotFound => {
-                                                    boxed_callback(CustomSwarmEvent::UpnpGatewayNotFound)
-                                                    .await;
-                                                    ()
-                                                },
-                                                libp2p::upnp::Event::NonRoutableGateway => {
-                                                    boxed_callback(CustomSwarmEvent::UpnpNonRoutableGateway)
-                                                    .await;
-                                                    ()
-                                                },
-                                            }
+    let mut events = wrapper.subscribe();
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => {
+                return Ok(false);
+            },
+            event = events.recv() => {
+                match event {
+                    Ok(event) => callback(event).await,
+                    Err(broadcast::error::RecvError::Lagged(_)) => {},
+                    Err(broadcast::error::RecvError::Closed) => return Ok(true),
+                }
+            }
+        }
+    }
+}
+
+/// Counters of an event subscription, readable while it runs.
+#[frb(opaque)]
+#[derive(Clone, Default)]
+pub struct CustomEventSubscriptionStats {
+    delivered: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+}
+impl CustomEventSubscriptionStats {
+    /// Events pushed to the sink.
+    #[frb(sync)]
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+    /// Events missed because the subscription fell behind the event buffer.
+    #[frb(sync)]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[frb(sync)]
+pub fn create_event_subscription_stats() -> CustomEventSubscriptionStats {
+    CustomEventSubscriptionStats::default()
+}
+
+/// Push the swarm events whose name (see `libp2p_get_event_name`) is in the filter to the sink,
+/// every event when the filter is empty. Runs until the token is cancelled or the sink is closed.
+/// Up to `event_buffer_size` events (see `Libp2pSwarmConfig`) are buffered, older events are
+/// dropped and counted in the stats.
+pub async fn libp2p_subscribe_events(
+    wrapper: &Arc<Wrapper>,
+    filter: Vec<String>,
+    stats: &CustomEventSubscriptionStats,
+    cancellation_token: &CancellationToken,
+    sink: StreamSink<CustomSwarmEvent>,
+) -> Result<bool, GenericError> {
+    let filter: HashSet<String> = filter.into_iter().collect();
+    let mut events = wrapper.subscribe();
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => {
+                return Ok(false);
+            },
+            event = events.recv() => {
+                match event {
+                    Ok(event) => {
+                        let name: &str = event.as_ref();
+                        if filter.is_empty() || filter.contains(name) {
+                            sink.add(event).map_err(|e| GenericError::Other(e.to_string()))?;
+                            stats.delivered.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        stats.dropped.fetch_add(count, Ordering::Relaxed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(true),
+                }
+            }
+        }
+    }
+}
+
+/// Owns the swarm: runs the commands sent by the api and publishes its events.
+/// Stops when every sender of commands is dropped.
+pub(crate) async fn run_swarm(
+    mut state: SwarmState,
+    mut commands: mpsc::UnboundedReceiver<SwarmCommand>,
+    events: broadcast::Sender<CustomSwarmEvent>,
+) {
+    // sending fails when there is no listener
+    let emit = |event: CustomSwarmEvent| {
+        let _ = events.send(event);
+    };
+    let mut validation_sweep = tokio::time::interval(SWEEP_INTERVAL);
+    let mut outbox_sweep = tokio::time::interval(p2p_outbox::SWEEP_INTERVAL);
+    let mut rdv_sweep = tokio::time::interval(p2p_rdv::SWEEP_INTERVAL);
+    let mut routing_table_save = tokio::time::interval_at(
+        tokio::time::Instant::now() + p2p_dht::SAVE_INTERVAL,
+        p2p_dht::SAVE_INTERVAL,
+    );
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                match command {
+                    None => break,
+                    Some(command) => command(&mut state),
+                }
+            },
+            _ = validation_sweep.tick() => {
+                // messages never validated by dart are ignored
+                for (message_id, propagation_source) in state.validation.expired() {
+                    let _ = state.swarm.behaviour_mut().pubsub.report_message_validation_result(
+                        &message_id,
+                        &propagation_source,
+                        MessageAcceptance::Ignore,
+                    );
+                }
+            },
+            _ = outbox_sweep.tick() => {
+                if let Some(outbox) = state.outbox.as_mut() {
+                    for event in outbox.sweep(&mut state.swarm) {
+                        emit(event);
+                    }
+                }
+            },
+            _ = rdv_sweep.tick() => {
+                state.rendezvous.poll(&mut state.swarm);
+            },
+            _ = routing_table_save.tick() => {
+                if let Some(routing_table) = state.routing_table.as_ref() {
+                    // a failed save is retried at the next tick
+                    let _ = routing_table.save(&mut state.swarm);
+                }
+            },
+            unsafe_event = state.swarm.next() => {
+                match unsafe_event {
+                    None => break,
+                    Some(event) => {
+                        if let Some(peer_store) = state.peer_store.as_ref() {
+                            peer_store.on_swarm_event(&event);
+                        }
+                        match event {
+                            SwarmEvent::Behaviour(custom) => match custom {
+                                CustomBehaviourEvent::Whitelist{..} => {},
+                                CustomBehaviourEvent::Stream{..} => {},
+                                CustomBehaviourEvent::Blacklist{..} => {},
+                                CustomBehaviourEvent::MemoryLimits{..} => {},
+                                CustomBehaviourEvent::ConnectionLimits{..} => {},
+                                CustomBehaviourEvent::Upnp(e)=>{
+                                    match e {
+                                        libp2p::upnp::Event::NewExternalAddr(val) => {
+                                            emit(CustomSwarmEvent::UpnpNewExternalAddr(val.to_string()));
                                         },
-                                        CustomBehaviourEvent::AutoNat(e) => {
-                                            match e {
-                                                autonat::Event::InboundProbe(_) => {},
-                                                autonat::Event::OutboundProbe(_) => {},
-                                                autonat::Event::StatusChanged { old, new } => {
-                                                    boxed_callback(CustomSwarmEvent::NATStatusChanged {
-                                                        new_value: CustomNatStatus::from(new),
-                                                        old_value: CustomNatStatus::from(old),
-                                                    })
-                                                    .await;
-                                                    ()
-                                                },
-                                            }
+                                        libp2p::upnp::Event::ExpiredExternalAddr(val) => {
+                                            emit(CustomSwarmEvent::UpnpExpiredExternalAddr(val.to_string()));
                                         },
-                                        CustomBehaviourEvent::Dcutr(_) => {},
-                                        CustomBehaviourEvent::Identify(id_event) => match id_event {
-                                            identify::Event::Received { peer_id, info } => {
-                                                boxed_callback(CustomSwarmEvent::IdentifyReceived {
-                                                    peer_id: peer_id.to_string(),
-                                                    public_key: info.public_key.encode_protobuf(),
-                                                    protocol_version: info.protocol_version,
-                                                    agent_version: info.agent_version,
-                                                    listen_addrs: info
-                                                        .listen_addrs
-                                                        .iter()
-                                                        .map(|f| f.to_string())
-                                                        .collect(),
-                                                    protocols: info
-                                                        .protocols
-                                                        .iter()
-                                                        .map(|f| f.to_string())
-                                                        .collect(),
-                                                    observed_addr: info.observed_addr.to_string(),
-                                                })
-                                                .await;
-                                                ()
-                                            }
-                                            identify::Event::Sent { .. } => {}
-                                            identify::Event::Pushed { .. } => {}
-                                            identify::Event::Error { .. } => {}
+                                        libp2p::upnp::Event::GatewayNotFound => {
+                                            emit(CustomSwarmEvent::UpnpGatewayNotFound);
                                         },
-                                        CustomBehaviourEvent::RequestResponse(req_event) => {
-                                            match req_event {
-                                                request_response::Event::Message { peer, message } => match message
-                                                {
-                                                    request_response::Message::Request {
-                                                        request_id,
-                                                        request,
-                                                        channel,
-                                                    } => {
-                                                        boxed_callback(CustomSwarmEvent::RequestMessage {
-                                                            request_id: request_id.to_string(),
-                                                            peer: peer.to_string(),
-                                                            message: request,
-                                                            channel: RustAutoOpaqueNom::new(channel),
-                                                        })
-                                                        .await;
-                                                        ()
-                                                    }
-                                                    request_response::Message::Response {
-                                                        request_id,
-                                                        response,
-                                                    } => {
-                                                        boxed_callback(CustomSwarmEvent::ResponseMessage {
+                                        libp2p::upnp::Event::NonRoutableGateway => {
+                                            emit(CustomSwarmEvent::UpnpNonRoutableGateway);
+                                        },
+                                    }
+                                },
+                                CustomBehaviourEvent::AutoNat(e) => {
+                                    match e {
+                                        autonat::Event::InboundProbe(_) => {},
+                                        autonat::Event::OutboundProbe(_) => {},
+                                        autonat::Event::StatusChanged { old, new } => {
+                                            emit(CustomSwarmEvent::NATStatusChanged {
+                                                new_value: CustomNatStatus::from(new),
+                                                old_value: CustomNatStatus::from(old),
+                                            });
+                                        },
+                                    }
+                                },
+                                CustomBehaviourEvent::Dcutr(hole_punch) => {
+                                    emit(dcutr_event(hole_punch));
+                                },
+                                CustomBehaviourEvent::Identify(id_event) => match id_event {
+                                    identify::Event::Received { peer_id, info } => {
+                                        // kademlia does not learn the listen addresses by itself
+                                        if let Some(kademlia) = state.swarm.behaviour_mut().kademlia.as_mut() {
+                                            if info.protocols.iter().any(|p| kademlia.protocol_names().contains(p)) {
+                                                for address in info.listen_addrs.iter() {
+                                                    kademlia.add_address(&peer_id, address.clone());
+                                                }
+                                            }
+                                        }
+                                        emit(CustomSwarmEvent::IdentifyReceived {
+                                            peer_id: peer_id.to_string(),
+                                            public_key: info.public_key.encode_protobuf(),
+                                            protocol_version: info.protocol_version,
+                                            agent_version: info.agent_version,
+                                            listen_addrs: info
+                                                .listen_addrs
+                                                .iter()
+                                                .map(|f| f.to_string())
+                                                .collect(),
+                                            protocols: info
+                                                .protocols
+                                                .iter()
+                                                .map(|f| f.to_string())
+                                                .collect(),
+                                            observed_addr: info.observed_addr.to_string(),
+                                        });
+                                    }
+                                    identify::Event::Sent { .. } => {}
+                                    identify::Event::Pushed { .. } => {}
+                                    identify::Event::Error { .. } => {}
+                                },
+                                CustomBehaviourEvent::RequestResponse((protocol, req_event)) => {
+                                    match req_event {
+                                        request_response::Event::Message { peer, message } => match message
+                                        {
+                                            request_response::Message::Request {
+                                                request_id,
+                                                request,
+                                                channel,
+                                            } => {
+                                                emit(CustomSwarmEvent::RequestMessage {
+                                                    protocol,
+                                                    request_id: request_id.to_string(),
+                                                    peer: peer.to_string(),
+                                                    message: request,
+                                                    channel: RustAutoOpaqueNom::new(CustomResponseChannel::new(channel)),
+                                                });
+                                            }
+                                            request_response::Message::Response {
+                                                request_id,
+                                                response,
+                                            } => match take_pending_request(&mut state, &protocol, request_id) {
+                                                // awaited by libp2p_request, the receiver may have timed out
+                                                Some(sender) => {
+                                                    let _ = sender.send(Ok(response));
+                                                }
+                                                None => match outbox_response(&mut state, &protocol, request_id, response) {
+                                                    Ok(event) => emit(event),
+                                                    Err(response) => {
+                                                        emit(CustomSwarmEvent::ResponseMessage {
+                                                            protocol,
                                                             request_id: request_id.to_string(),
                                                             peer: peer.to_string(),
                                                             message: response,
-                                                        })
-                                                        .await;
-                                                        ()
+                                                        });
                                                     }
                                                 },
-                                                request_response::Event::OutboundFailure {
-                                                    peer,
-                                                    request_id,
-                                                    error,
-                                                } => {
-                                                    boxed_callback(CustomSwarmEvent::RequestOutboundFailure {
-                                                        peer: peer.to_string(),
-                                                        request_id: request_id.to_string(),
-                                                        error: error.to_string(),
-                                                    })
-                                                    .await;
-                                                    ()
+                                            },
+                                        },
+                                        request_response::Event::OutboundFailure {
+                                            peer,
+                                            request_id,
+                                            error,
+                                        } => match take_pending_request(&mut state, &protocol, request_id) {
+                                            Some(sender) => {
+                                                let _ = sender.send(Err(RequestError::Failure(error.to_string())));
+                                            }
+                                            None => match outbox_failure(&mut state, &protocol, request_id, error.to_string()) {
+                                                // retried by the outbox when no event
+                                                Some(events) => {
+                                                    for event in events {
+                                                        emit(event);
+                                                    }
                                                 }
-                                                request_response::Event::InboundFailure {
-                                                    peer,
-                                                    request_id,
-                                                    error,
-                                                } => {
-                                                    boxed_callback(CustomSwarmEvent::RequestInboundFailure {
+                                                None => {
+                                                    emit(CustomSwarmEvent::RequestOutboundFailure {
+                                                        protocol,
                                                         peer: peer.to_string(),
                                                         request_id: request_id.to_string(),
                                                         error: error.to_string(),
-                                                    })
-                                                    .await;
-                                                    ()
-                                                }
-                                                request_response::Event::ResponseSent { peer, request_id } => {
-                                                    boxed_callback(CustomSwarmEvent::ResponseSent {
-                                                        peer: peer.to_string(),
-                                                        request_id: request_id.to_string(),
-                                                    })
-                                                    .await;
-                                                    ()
+                                                    });
                                                 }
-                                            }
+                                            },
+                                        },
+                                        request_response::Event::InboundFailure {
+                                            peer,
+                                            request_id,
+                                            error,
+                                        } => {
+                                            emit(CustomSwarmEvent::RequestInboundFailure {
+                                                protocol,
+                                                peer: peer.to_string(),
+                                                request_id: request_id.to_string(),
+                                                error: error.to_string(),
+                                            });
                                         }
-                                        CustomBehaviourEvent::RdvServer(rdv_event) => {
-                                            match rdv_event {
-                                                libp2p::rendezvous::server::Event::DiscoverServed { .. } => {},
-                                                libp2p::rendezvous::server::Event::DiscoverNotServed { .. } => {},
-                                                libp2p::rendezvous::server::Event::PeerNotRegistered { .. } => {},
-                                                libp2p::rendezvous::server::Event::PeerRegistered { peer, registration } => {
-                                                    boxed_callback(CustomSwarmEvent::RdvServerPeerRegistered { peerid: peer.to_string(), addresses: registration.record.addresses().iter().map(|e|e.to_string()).collect() } )
-                                                    .await;
-                                                    ()
-                                                },
-                                                libp2p::rendezvous::server::Event::PeerUnregistered { peer, .. } => {
-                                                    boxed_callback(CustomSwarmEvent::RdvServerPeerUnRegistered { peerid: peer.to_string() } )
-                                                    .await;
-                                                    ()
-                                                },
-                                                libp2p::rendezvous::server::Event::RegistrationExpired(registration) => {
-                                                    boxed_callback(CustomSwarmEvent::RdvServerPeerUnRegistered { peerid: registration.record.peer_id().to_string() } )
-                                                    .await;
-                                                    ()
-                                                },
-                                            }
+                                        request_response::Event::ResponseSent { peer, request_id } => {
+                                            emit(CustomSwarmEvent::ResponseSent {
+                                                protocol,
+                                                peer: peer.to_string(),
+                                                request_id: request_id.to_string(),
+                                            });
                                         }
-                                        CustomBehaviourEvent::RdvClient(rdv_event) => match rdv_event
-                                        {
-                                            libp2p::rendezvous::client::Event::Discovered {
-                                                rendezvous_node,
-                                                registrations,
-                                                ..
-                                            } => {
-                                                boxed_callback(CustomSwarmEvent::RdvClientDiscovered {
-                                                    rendezvous_node: rendezvous_node.to_string(),
-                                                    registrations: registrations
-                                                        .iter()
-                                                        .map(|f| CustomPeerRecord{
-                                                            peer_id:f.record.peer_id().to_string(),
-                                                            addresses: f.record.addresses().iter().map(|e|e.to_string()).collect()
-                                                        })
-                                                        .collect(),
-                                                })
-                                                .await;
-                                                ()
-                                            }
-                                            libp2p::rendezvous::client::Event::DiscoverFailed { rendezvous_node,.. } => {
-                                                boxed_callback(CustomSwarmEvent::RdvClientDiscoveryFail { rendezvous_node: rendezvous_node.to_string() })
-                                                .await;
-                                                ()
-                                            }
-                                            libp2p::rendezvous::client::Event::Registered { rendezvous_node, .. } => {
-                                                boxed_callback(CustomSwarmEvent::RdvClientRegistered { rendezvous_node: rendezvous_node.to_string() })
-                                                .await;
-                                                ()
-                                            }
-                                            libp2p::rendezvous::client::Event::RegisterFailed { rendezvous_node,.. } => {
-                                                boxed_callback(CustomSwarmEvent::RdvClientRegisteredFailed { rendezvous_node: rendezvous_node.to_string() })
-                                                .await;
-                                                ()
-                                            }
-                                            libp2p::rendezvous::client::Event::Expired { peer } => {
-                                                boxed_callback(CustomSwarmEvent::RdvClientDiscoveryExpired { peer_id: peer.to_string() })
-                                                .await;
-                                                ()
-                                            }
+                                    }
+                                }
+                                CustomBehaviourEvent::RdvServer(rdv_event) => {
+                                    state.rendezvous.on_server_event(&rdv_event);
+                                    match rdv_event {
+                                        libp2p::rendezvous::server::Event::DiscoverServed { .. } => {},
+                                        libp2p::rendezvous::server::Event::DiscoverNotServed { .. } => {},
+                                        libp2p::rendezvous::server::Event::PeerNotRegistered { .. } => {},
+                                        libp2p::rendezvous::server::Event::PeerRegistered { peer, registration } => {
+                                            emit(CustomSwarmEvent::RdvServerPeerRegistered { peerid: peer.to_string(), addresses: registration.record.addresses().iter().map(|e|e.to_string()).collect() } );
                                         },
-                                        CustomBehaviourEvent::RelayServer(_) => {}
-                                        CustomBehaviourEvent::RelayClient(_) => {}
-                                        CustomBehaviourEvent::Pubsub(pub_event) => {
-                                            match pub_event {
-                                                gossipsub::Event::Message {
-                                                    propagation_source,
-                                                    message_id,
-                                                    message,
-                                                } => {
-                                                    boxed_callback(CustomSwarmEvent::GossipMessage {
-                                                        propagation_source: propagation_source.to_string(),
-                                                        message_id: message_id.0,
-                                                        message: message.data,
-                                                        source: message.source.map(|f|f.to_string()),
-                                                        topic_hash: message.topic.to_string(),
-                                                    })
-                                                    .await;
-                                                    ()
-                                                }
-                                                gossipsub::Event::Subscribed { peer_id, topic } => {
-                                                    boxed_callback(CustomSwarmEvent::GossipSubscribed {
-                                                        peer_id: peer_id.to_string(),
-                                                        topic: topic.to_string(),
-                                                    })
-                                                    .await;
-                                                    ()
-                                                }
-                                                gossipsub::Event::Unsubscribed { peer_id, topic } => {
-                                                    boxed_callback(CustomSwarmEvent::GossipUnsubscribed {
-                                                        peer_id: peer_id.to_string(),
-                                                        topic: topic.to_string(),
-                                                    })
-                                                    .await;
-                                                    ()
-                                                }
-                                                gossipsub::Event::GossipsubNotSupported { peer_id } => {
-                                                    boxed_callback(CustomSwarmEvent::GossipsubNotSupported {
-                                                        peer_id: peer_id.to_string(),
+                                        libp2p::rendezvous::server::Event::PeerUnregistered { peer, .. } => {
+                                            emit(CustomSwarmEvent::RdvServerPeerUnRegistered { peerid: peer.to_string() } );
+                                        },
+                                        libp2p::rendezvous::server::Event::RegistrationExpired(registration) => {
+                                            emit(CustomSwarmEvent::RdvServerPeerUnRegistered { peerid: registration.record.peer_id().to_string() } );
+                                        },
+                                    }
+                                }
+                                CustomBehaviourEvent::RdvClient(rdv_event) => {
+                                    state.rendezvous.on_client_event(&mut state.swarm, &rdv_event);
+                                    match rdv_event {
+                                        libp2p::rendezvous::client::Event::Discovered {
+                                            rendezvous_node,
+                                            registrations,
+                                            ..
+                                        } => {
+                                            emit(CustomSwarmEvent::RdvClientDiscovered {
+                                                rendezvous_node: rendezvous_node.to_string(),
+                                                registrations: registrations
+                                                    .iter()
+                                                    .map(|f| CustomPeerRecord{
+                                                        peer_id:f.record.peer_id().to_string(),
+                                                        addresses: f.record.addresses().iter().map(|e|e.to_string()).collect()
                                                     })
-                                                    .await;
-                                                    ()
-                                                }
-                                            };
+                                                    .collect(),
+                                            });
+                                        }
+                                        libp2p::rendezvous::client::Event::DiscoverFailed { rendezvous_node,.. } => {
+                                            emit(CustomSwarmEvent::RdvClientDiscoveryFail { rendezvous_node: rendezvous_node.to_string() });
+                                        }
+                                        libp2p::rendezvous::client::Event::Registered { rendezvous_node, .. } => {
+                                            emit(CustomSwarmEvent::RdvClientRegistered { rendezvous_node: rendezvous_node.to_string() });
+                                        }
+                                        libp2p::rendezvous::client::Event::RegisterFailed { rendezvous_node,.. } => {
+                                            emit(CustomSwarmEvent::RdvClientRegisteredFailed { rendezvous_node: rendezvous_node.to_string() });
+                                        }
+                                        libp2p::rendezvous::client::Event::Expired { peer } => {
+                                            emit(CustomSwarmEvent::RdvClientDiscoveryExpired { peer_id: peer.to_string() });
                                         }
-                                    },
-                                    SwarmEvent::ConnectionEstablished {
-                                        peer_id,
-                                        endpoint,
-                                        num_established,
-                                        established_in,
-                                        connection_id,..
-                                    } => {
-                                        boxed_callback(CustomSwarmEvent::ConnectionEstablished {
-                                            peer_id: peer_id.to_string(),
-                                            endpoint: endpoint.get_remote_address().to_string(),
-                                            num_established: num_established.get(),
-                                            established_in: established_in.as_millis(),
-                                            connection_id,
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::ConnectionClosed {
-                                        peer_id,
-                                        endpoint,
-                                        num_established,
-                                        cause,
-                                        connection_id
-                                    } => {
-                                        boxed_callback(CustomSwarmEvent::ConnectionClosed {
-                                            peer_id: peer_id.to_string(),
-                                            endpoint: endpoint.get_remote_address().to_string(),
-                                            num_established: num_established,
-                                            cause: cause.map(|f| f.to_string()),
-                                            connection_id
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::IncomingConnection {
-                                        local_addr,
-                                        send_back_addr,
-                                        connection_id
-                                    } => {
-                                        boxed_callback(CustomSwarmEvent::IncomingConnection {
-                                            local_addr: local_addr.to_string(),
-                                            send_back_addr: send_back_addr.to_string(),
-                                            connection_id
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::IncomingConnectionError {
-                                        local_addr,
-                                        send_back_addr,
-                                        error,
-                                        connection_id
-                                    } => {
-                                        boxed_callback(CustomSwarmEvent::IncomingConnectionError {
-                                            local_addr: local_addr.to_string(),
-                                            send_back_addr: send_back_addr.to_string(),
-                                            error: error.to_string(),
-                                            connection_id
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::OutgoingConnectionError { peer_id, error, connection_id} => {
-                                        boxed_callback(CustomSwarmEvent::OutgoingConnectionError {
-                                            peer_id: peer_id.map(|f| f.to_string()),
-                                            error: error.to_string(),
-                                            connection_id
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::NewListenAddr { address, listener_id } => {
-                                        boxed_callback(CustomSwarmEvent::NewListenAddr {
-                                            address: address.to_string(),
-                                            listener_id: listener_id.to_string()
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::ExpiredListenAddr { address, listener_id } => {
-                                        boxed_callback(CustomSwarmEvent::ExpiredListenAddr {
-                                            address: address.to_string(),
-                                            listener_id: listener_id.to_string()
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::ListenerClosed {
-                                        addresses, reason, listener_id
-                                    } => {
-                                        boxed_callback(CustomSwarmEvent::ListenerClosed {
-                                            addresses: addresses.iter().map(|f| f.to_string()).collect(),
-                                            reason: reason.err().map(|f| f.to_string()),
-                                            listener_id: listener_id.to_string()
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::ListenerError { error, listener_id } => {
-                                        boxed_callback(CustomSwarmEvent::ListenerError {
-                                            error: error.to_string(),
-                                            listener_id: listener_id.to_string()
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::Dialing { peer_id, connection_id } => {
-                                        boxed_callback(CustomSwarmEvent::Dialing {
-                                            peer_id: peer_id.map(|f| f.to_string()),
-                                            connection_id
-                                        })
-                                        .await;
-                                        ()
-                                    }
-                                    SwarmEvent::NewExternalAddrCandidate { address } => {
-                                        boxed_callback(CustomSwarmEvent::NewExternalAddrCandidate {
-                                            address: address.to_string(),
-                                        })
-                                        .await;
-                                        ()
                                     }
-                                    SwarmEvent::ExternalAddrConfirmed { address } => {
-                                        boxed_callback(CustomSwarmEvent::ExternalAddrConfirmed {
-                                            address: address.to_string(),
-                                        })
-                                        .await;
-                                        ()
+                                },
+                                CustomBehaviourEvent::Kademlia(kad_event) => {
+                                    for event in dht_events(kad_event) {
+                                        emit(event);
                                     }
-                                    SwarmEvent::ExternalAddrExpired { address } => {
-                                        boxed_callback(CustomSwarmEvent::ExternalAddrExpired {
-                                            address: address.to_string(),
-                                        })
-                                        .await;
-                                        ()
+                                }
+                                CustomBehaviourEvent::Mdns(mdns_event) => {
+                                    emit(handle_mdns_event(&mut state, mdns_event));
+                                }
+                                CustomBehaviourEvent::RelayServer(relay_event) => {
+                                    if let Some(event) = state.relay_server_stats.on_event(relay_event) {
+                                        emit(event);
                                     }
-                                    SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
-                                        boxed_callback(CustomSwarmEvent::NewExternalAddrOfPeer {
-                                            peer_id: peer_id.to_string(),
-                                            address: address.to_string(),
-                                        })
-                                        .await;
-                                        ()
+                                }
+                                CustomBehaviourEvent::RelayClient(relay_event) => {
+                                    emit(relay_client_event(relay_event));
+                                }
+                                CustomBehaviourEvent::Pubsub(pub_event) => {
+                                    match pub_event {
+                                        gossipsub::Event::Message {
+                                            propagation_source,
+                                            message_id,
+                                            message,
+                                        } => {
+                                            state.topic_counters.on_received(&message.topic, message.data.len());
+                                            // rejected and ignored messages are not forwarded
+                                            let forward = match state.validation.validate(&propagation_source, &message_id, &message) {
+                                                Some(acceptance) => {
+                                                    let accepted = matches!(acceptance, MessageAcceptance::Accept);
+                                                    let _ = state.swarm.behaviour_mut().pubsub.report_message_validation_result(
+                                                        &message_id,
+                                                        &propagation_source,
+                                                        acceptance,
+                                                    );
+                                                    accepted
+                                                }
+                                                None => true,
+                                            };
+                                            if forward {
+                                                emit(CustomSwarmEvent::GossipMessage {
+                                                    propagation_source: propagation_source.to_string(),
+                                                    message_id: message_id.0,
+                                                    message: message.data,
+                                                    source: message.source.map(|f|f.to_string()),
+                                                    topic_hash: message.topic.to_string(),
+                                                });
+                                            }
+                                        }
+                                        gossipsub::Event::Subscribed { peer_id, topic } => {
+                                            emit(CustomSwarmEvent::GossipSubscribed {
+                                                peer_id: peer_id.to_string(),
+                                                topic: topic.to_string(),
+                                            });
+                                        }
+                                        gossipsub::Event::Unsubscribed { peer_id, topic } => {
+                                            emit(CustomSwarmEvent::GossipUnsubscribed {
+                                                peer_id: peer_id.to_string(),
+                                                topic: topic.to_string(),
+                                            });
+                                        }
+                                        gossipsub::Event::GossipsubNotSupported { peer_id } => {
+                                            emit(CustomSwarmEvent::GossipsubNotSupported {
+                                                peer_id: peer_id.to_string(),
+                                            });
+                                        }
+                                    };
+                                }
+                            },
+                            SwarmEvent::ConnectionEstablished {
+                                peer_id,
+                                endpoint,
+                                num_established,
+                                established_in,
+                                connection_id,..
+                            } => {
+                                emit(CustomSwarmEvent::ConnectionEstablished {
+                                    peer_id: peer_id.to_string(),
+                                    endpoint: endpoint.get_remote_address().to_string(),
+                                    num_established: num_established.get(),
+                                    established_in: established_in.as_millis(),
+                                    connection_id,
+                                });
+                                if let Some(outbox) = state.outbox.as_mut() {
+                                    for event in outbox.send_due(&mut state.swarm, &peer_id) {
+                                        emit(event);
                                     }
-                                    _ => {}
                                 }
                             }
+                            SwarmEvent::ConnectionClosed {
+                                peer_id,
+                                endpoint,
+                                num_established,
+                                cause,
+                                connection_id
+                            } => {
+                                emit(CustomSwarmEvent::ConnectionClosed {
+                                    peer_id: peer_id.to_string(),
+                                    endpoint: endpoint.get_remote_address().to_string(),
+                                    num_established,
+                                    cause: cause.map(|f| f.to_string()),
+                                    connection_id
+                                });
+                            }
+                            SwarmEvent::IncomingConnection {
+                                local_addr,
+                                send_back_addr,
+                                connection_id
+                            } => {
+                                emit(CustomSwarmEvent::IncomingConnection {
+                                    local_addr: local_addr.to_string(),
+                                    send_back_addr: send_back_addr.to_string(),
+                                    connection_id
+                                });
+                            }
+                            SwarmEvent::IncomingConnectionError {
+                                local_addr,
+                                send_back_addr,
+                                error,
+                                connection_id
+                            } => {
+                                emit(CustomSwarmEvent::IncomingConnectionError {
+                                    local_addr: local_addr.to_string(),
+                                    send_back_addr: send_back_addr.to_string(),
+                                    error: error.to_string(),
+                                    connection_id
+                                });
+                            }
+                            SwarmEvent::OutgoingConnectionError { peer_id, error, connection_id} => {
+                                emit(CustomSwarmEvent::OutgoingConnectionError {
+                                    peer_id: peer_id.map(|f| f.to_string()),
+                                    error: error.to_string(),
+                                    connection_id
+                                });
+                            }
+                            SwarmEvent::NewListenAddr { address, listener_id } => {
+                                emit(CustomSwarmEvent::NewListenAddr {
+                                    address: address.to_string(),
+                                    listener_id: listener_id.to_string()
+                                });
+                            }
+                            SwarmEvent::ExpiredListenAddr { address, listener_id } => {
+                                emit(CustomSwarmEvent::ExpiredListenAddr {
+                                    address: address.to_string(),
+                                    listener_id: listener_id.to_string()
+                                });
+                            }
+                            SwarmEvent::ListenerClosed {
+                                addresses, reason, listener_id
+                            } => {
+                                let reason = reason.err().map(|f| f.to_string());
+                                emit(CustomSwarmEvent::ListenerClosed {
+                                    addresses: addresses.iter().map(|f| f.to_string()).collect(),
+                                    reason: reason.clone(),
+                                    listener_id: listener_id.to_string()
+                                });
+                                if let Some(event) = relay_listener_closed(&mut state, listener_id, reason) {
+                                    emit(event);
+                                }
+                            }
+                            SwarmEvent::ListenerError { error, listener_id } => {
+                                emit(CustomSwarmEvent::ListenerError {
+                                    error: error.to_string(),
+                                    listener_id: listener_id.to_string()
+                                });
+                            }
+                            SwarmEvent::Dialing { peer_id, connection_id } => {
+                                emit(CustomSwarmEvent::Dialing {
+                                    peer_id: peer_id.map(|f| f.to_string()),
+                                    connection_id
+                                });
+                            }
+                            SwarmEvent::NewExternalAddrCandidate { address } => {
+                                emit(CustomSwarmEvent::NewExternalAddrCandidate {
+                                    address: address.to_string(),
+                                });
+                            }
+                            SwarmEvent::ExternalAddrConfirmed { address } => {
+                                emit(CustomSwarmEvent::ExternalAddrConfirmed {
+                                    address: address.to_string(),
+                                });
+                            }
+                            SwarmEvent::ExternalAddrExpired { address } => {
+                                emit(CustomSwarmEvent::ExternalAddrExpired {
+                                    address: address.to_string(),
+                                });
+                            }
+                            SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
+                                emit(CustomSwarmEvent::NewExternalAddrOfPeer {
+                                    peer_id: peer_id.to_string(),
+                                    address: address.to_string(),
+                                });
+                            }
+                            _ => {}
                         }
                     }
                 }
             }
         }
     }
+    if let Some(routing_table) = state.routing_table.as_ref() {
+        let _ = routing_table.save(&mut state.swarm);
+    }
 }
@@ -1,133 +1,577 @@
-use super::p2p_common::CustomBehaviour;
+use super::p2p_common::{CustomBehaviour, Toggle};
+use super::p2p_config::Libp2pConfig;
+use super::p2p_dht::{kad_mode, parse_peer_address, KadBehaviour};
+use super::p2p_protocols::RequestProtocols;
+use super::p2p_pubsub::{content_message_id, topic_score_params, GossipBehaviour, PubsubTransform};
+use super::p2p_whitelist::Whitelist;
+use crate::api::wrapper::SwarmOptions;
 pub use crate::api::wrapper::Wrapper;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, OptionalTransport};
+use libp2p::core::upgrade::Version;
 pub use libp2p::gossipsub::{
-    self, Behaviour as GossipBehaviour, ConfigBuilder, MessageAuthenticity, MessageId,
+    self, ConfigBuilder, MessageAuthenticity, MessageId, PeerScoreParams, PeerScoreThresholds,
     ValidationMode,
 };
 use libp2p::identify::Behaviour as IdentifyBehaviour;
 pub use libp2p::identity::{Keypair, PeerId, PublicKey};
 use libp2p::rendezvous::client::Behaviour as rdv_clientBehaviour;
 use libp2p::rendezvous::server::{self as rdvserver, Behaviour as RdvServerBehaviour};
-pub use libp2p::request_response::{self as request_response, ProtocolSupport, ResponseChannel};
+pub use libp2p::request_response::ResponseChannel;
 use libp2p::{
-    allow_block_list, autonat, connection_limits, dcutr, identify, memory_connection_limits, noise,
-    relay, tcp, upnp, yamux, StreamProtocol,
+    allow_block_list, autonat, connection_limits, dcutr, dns, identify, kad, mdns,
+    memory_connection_limits, noise, quic, relay, tcp, upnp, websocket, yamux, StreamProtocol,
+    Transport,
 };
+use libp2p_pnet::{PnetConfig, PreSharedKey};
+use std::io;
+use std::num::NonZeroU32;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 pub async fn create_libp2p_instance(
     keypair: Keypair,
-    tcp_nodelay: bool,
-    tcp_reuse_port: bool,
-    pubsub_heartbeat_delay: u64,
-    pubsub_heartbeat_interval: u64,
-    nat_boot_delay: u64,
-    nat_retry_interval: u64,
-    nat_only_global_ips: bool,
-    nat_use_connected: bool,
-    connection_max_inout: Option<u32>,
-    connection_max_inbound: Option<u32>,
-    connection_max_outgoing: Option<u32>,
-    connection_max_pending_inbound: Option<u32>,
-    connection_max_pending_outgoing: Option<u32>,
-    memory_max_percentage: f64,
+    config: Libp2pConfig,
 ) -> Result<Arc<Wrapper>, String> {
+    let Libp2pConfig {
+        transport,
+        behaviours,
+        gossipsub,
+        peer_score,
+        identify: identify_config,
+        autonat: nat,
+        connection_limits: limits,
+        relay_server,
+        rendezvous_server,
+        request_response: request_config,
+        kademlia: kad_config,
+        mdns: mdns_config,
+        swarm: swarm_config,
+    } = config;
     let gossip_factory = |key: &Keypair| -> Result<GossipBehaviour, String> {
-        let gossip_config_unsafe = ConfigBuilder::default()
+        let mut gossip_builder = ConfigBuilder::default();
+        if gossipsub.content_message_id {
+            gossip_builder.message_id_fn(content_message_id);
+        }
+        let gossip_config_unsafe = gossip_builder
             // send unsubscribe to self
             .allow_self_origin(true)
             .validate_messages()
-            .heartbeat_initial_delay(Duration::from_millis(pubsub_heartbeat_delay))
-            .heartbeat_interval(Duration::from_millis(pubsub_heartbeat_interval))
+            .heartbeat_initial_delay(Duration::from_millis(gossipsub.heartbeat_delay))
+            .heartbeat_interval(Duration::from_millis(gossipsub.heartbeat_interval))
+            .mesh_n(gossipsub.mesh_n)
+            .mesh_n_low(gossipsub.mesh_n_low)
+            .mesh_n_high(gossipsub.mesh_n_high)
+            .history_length(gossipsub.history_length)
+            .history_gossip(gossipsub.history_gossip)
+            .max_transmit_size(gossipsub.max_transmit_size)
+            .duplicate_cache_time(Duration::from_millis(gossipsub.duplicate_cache_time))
             .validation_mode(ValidationMode::Strict)
             .build()
             .map_err(|e| e.to_string());
         match gossip_config_unsafe {
             Ok(gossip_config) => {
-                return GossipBehaviour::new(
+                let mut gossip = GossipBehaviour::new_with_transform(
                     MessageAuthenticity::Signed(key.clone()),
                     gossip_config,
-                )
-                .map_err(|e| e.to_string())
+                    None,
+                    PubsubTransform::new(gossipsub.compression, gossipsub.max_transmit_size),
+                )?;
+                if peer_score.enabled {
+                    let mut params = PeerScoreParams {
+                        topic_score_cap: peer_score.topic_score_cap,
+                        app_specific_weight: peer_score.app_specific_weight,
+                        ip_colocation_factor_weight: peer_score.ip_colocation_factor_weight,
+                        ip_colocation_factor_threshold: peer_score.ip_colocation_factor_threshold,
+                        behaviour_penalty_weight: peer_score.behaviour_penalty_weight,
+                        behaviour_penalty_threshold: peer_score.behaviour_penalty_threshold,
+                        behaviour_penalty_decay: peer_score.behaviour_penalty_decay,
+                        decay_interval: Duration::from_millis(peer_score.decay_interval),
+                        decay_to_zero: peer_score.decay_to_zero,
+                        retain_score: Duration::from_millis(peer_score.retain_score),
+                        ..PeerScoreParams::default()
+                    };
+                    for topic in peer_score.topics.iter() {
+                        params.topics.insert(
+                            gossipsub::IdentTopic::new(topic.topic.clone()).hash(),
+                            topic_score_params(topic),
+                        );
+                    }
+                    let thresholds = PeerScoreThresholds {
+                        gossip_threshold: peer_score.gossip_threshold,
+                        publish_threshold: peer_score.publish_threshold,
+                        graylist_threshold: peer_score.graylist_threshold,
+                        accept_px_threshold: peer_score.accept_px_threshold,
+                        opportunistic_graft_threshold: peer_score.opportunistic_graft_threshold,
+                    };
+                    gossip.with_peer_score(params, thresholds)?;
+                }
+                Ok(gossip)
             }
             Err(err) => return Err(err),
         }
     };
+    let identify_factory = |key: &Keypair| -> identify::Config {
+        let config = identify::Config::new(identify_config.protocol_version.clone(), key.public())
+            .with_interval(Duration::from_millis(identify_config.interval))
+            .with_push_listen_addr_updates(identify_config.push_listen_addr_updates)
+            .with_cache_size(identify_config.cache_size);
+        match &identify_config.agent_version {
+            Some(agent_version) => config.with_agent_version(agent_version.clone()),
+            None => config,
+        }
+    };
+    // the relay rate limiters can not refill tokens over an empty interval
+    if relay_server.rate_limit_interval == 0
+        && (relay_server.reservation_rate_limit != 0 || relay_server.circuit_rate_limit != 0)
+    {
+        return Err("relay_rate_limit_interval_zero".to_string());
+    }
+    let relay_server_factory = |key: &Keypair| -> relay::Behaviour {
+        let mut config = relay::Config {
+            max_reservations: relay_server.max_reservations,
+            max_reservations_per_peer: relay_server.max_reservations_per_peer,
+            reservation_duration: Duration::from_millis(relay_server.reservation_duration),
+            max_circuits: relay_server.max_circuits,
+            max_circuits_per_peer: relay_server.max_circuits_per_peer,
+            max_circuit_duration: Duration::from_millis(relay_server.max_circuit_duration),
+            max_circuit_bytes: relay_server.max_circuit_bytes,
+            // replaced by the configured rate limits
+            reservation_rate_limiters: vec![],
+            circuit_src_rate_limiters: vec![],
+        };
+        let interval = Duration::from_millis(relay_server.rate_limit_interval);
+        if let Some(limit) = NonZeroU32::new(relay_server.reservation_rate_limit) {
+            config = config
+                .reservation_rate_per_peer(limit, interval)
+                .reservation_rate_per_ip(limit, interval);
+        }
+        if let Some(limit) = NonZeroU32::new(relay_server.circuit_rate_limit) {
+            config = config
+                .circuit_src_per_peer(limit, interval)
+                .circuit_src_per_ip(limit, interval);
+        }
+        relay::Behaviour::new(key.public().to_peer_id(), config)
+    };
+    let request_response = RequestProtocols::new(request_config.protocols)?;
+    let kad_protocol = StreamProtocol::try_from_owned(kad_config.protocol_name.clone())
+        .map_err(|e| e.to_string())?;
+    let bootstrap_peers = kad_config
+        .bootstrap_peers
+        .iter()
+        .map(|address| {
+            parse_peer_address(address).map_err(|_| format!("bad bootstrap address: {}", address))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let kademlia_factory = |key: &Keypair| -> KadBehaviour {
+        let peer_id = key.public().to_peer_id();
+        let mut config = kad::Config::default();
+        config
+            .set_protocol_names(vec![kad_protocol.clone()])
+            .set_query_timeout(Duration::from_millis(kad_config.query_timeout))
+            .set_record_ttl(kad_config.record_ttl.map(Duration::from_secs))
+            .set_provider_record_ttl(kad_config.provider_record_ttl.map(Duration::from_secs));
+        if let Some(replication_factor) = NonZeroUsize::new(kad_config.replication_factor) {
+            config.set_replication_factor(replication_factor);
+        }
+        let mut kademlia =
+            KadBehaviour::with_config(peer_id, kad::store::MemoryStore::new(peer_id), config);
+        kademlia.set_mode(kad_mode(kad_config.server_mode));
+        for (peer, address) in bootstrap_peers.iter() {
+            kademlia.add_address(peer, address.clone());
+        }
+        if !bootstrap_peers.is_empty() {
+            // the bootstrap peers were just added, it cannot fail
+            let _ = kademlia.bootstrap();
+        }
+        kademlia
+    };
+    let psk = transport
+        .pnet_psk
+        .as_ref()
+        .map(|key| key.parse::<PreSharedKey>())
+        .transpose()
+        .map_err(|e| e.to_string())?;
+    if psk.is_some() && transport.quic_enabled {
+        return Err("pnet_quic_unsupported".to_string());
+    }
+    let tcp_config = tcp::Config::default()
+        .nodelay(transport.tcp_nodelay)
+        .port_reuse(transport.tcp_reuse_port);
+    let (relay_transport, relay_client) = relay::client::new(keypair.public().to_peer_id());
     let swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
-        .with_tcp(
-            tcp::Config::default()
-                .nodelay(tcp_nodelay)
-                .port_reuse(tcp_reuse_port),
-            noise::Config::new,
-            yamux::Config::default,
-        )
-        .map_err(|e| e.to_string())?
-        //.with_quic()
-        .with_dns()
+        // tcp, websocket and relay are built by hand to insert the private network handshake
+        // before noise. Like the libp2p builder the transports that only accept their own
+        // addresses come first, dns accepts any address and fails later for unsupported ones.
+        .with_other_transport(|key| {
+            Ok(secure_transport(
+                relay_transport,
+                psk,
+                noise::Config::new(key)?,
+            ))
+        })
         .map_err(|e| e.to_string())?
-        .with_websocket(noise::Config::new, yamux::Config::default)
-        .await
+        // websocket resolves its own dns addresses so that wss keeps the host name
+        .with_other_transport(|key| {
+            let ws_transport = websocket::WsConfig::new(dns::tokio::Transport::system(
+                tcp::tokio::Transport::new(tcp_config.clone()),
+            )?);
+            // only the private network handshake needs the extra flushes
+            Ok(match psk {
+                Some(_) => secure_transport(
+                    ws_transport.map(|socket, _| FlushingSocket::new(socket)),
+                    psk,
+                    noise::Config::new(key)?,
+                ),
+                None => secure_transport(ws_transport, psk, noise::Config::new(key)?),
+            })
+        })
         .map_err(|e| e.to_string())?
-        .with_relay_client(noise::Config::new, yamux::Config::default)
+        .with_other_transport(|key| {
+            let tcp_transport = secure_transport(
+                tcp::tokio::Transport::new(tcp_config),
+                psk,
+                noise::Config::new(key)?,
+            );
+            // quic is opt-in: listening on a quic-v1 address fails when disabled
+            let quic_transport = if transport.quic_enabled {
+                let mut quic_config = quic::Config::new(key);
+                quic_config.keep_alive_interval =
+                    Duration::from_millis(transport.quic_keep_alive_interval);
+                quic_config.max_idle_timeout = transport.quic_max_idle_timeout;
+                quic_config.max_concurrent_stream_limit =
+                    transport.quic_max_concurrent_stream_limit;
+                OptionalTransport::some(quic::tokio::Transport::new(quic_config))
+            } else {
+                OptionalTransport::none()
+            };
+            let tcp_or_quic = tcp_transport
+                .or_transport(
+                    quic_transport.map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer))),
+                )
+                .map(|either, _| either.into_inner());
+            Ok(dns::tokio::Transport::system(tcp_or_quic)?)
+        })
         .map_err(|e| e.to_string())?
-        .with_behaviour(|key, relay_client: relay::client::Behaviour| {
+        .with_behaviour(|key| {
             let gossip_unsafe = gossip_factory(key);
+            let mdns = behaviours
+                .mdns
+                .then(|| {
+                    mdns::tokio::Behaviour::new(
+                        mdns::Config {
+                            ttl: Duration::from_millis(mdns_config.ttl),
+                            query_interval: Duration::from_millis(mdns_config.query_interval),
+                            enable_ipv6: mdns_config.enable_ipv6,
+                        },
+                        key.public().to_peer_id(),
+                    )
+                })
+                .transpose()?;
             match gossip_unsafe {
                 Ok(gossip) => {
                     return Ok(CustomBehaviour {
                         blacklist: allow_block_list::Behaviour::default(),
-                        //whitelist: allow_block_list::Behaviour::default(),
+                        whitelist: Whitelist::new(swarm_config.whitelist_only),
                         connection_limits: connection_limits::Behaviour::new(
                             connection_limits::ConnectionLimits::default()
-                                .with_max_established_incoming(connection_max_inbound)
-                                .with_max_pending_incoming(connection_max_pending_inbound)
-                                .with_max_established_outgoing(connection_max_outgoing)
-                                .with_max_pending_outgoing(connection_max_pending_outgoing)
-                                .with_max_established(connection_max_inout),
+                                .with_max_established_incoming(limits.max_established_incoming)
+                                .with_max_pending_incoming(limits.max_pending_incoming)
+                                .with_max_established_outgoing(limits.max_established_outgoing)
+                                .with_max_pending_outgoing(limits.max_pending_outgoing)
+                                .with_max_established(limits.max_established),
                         ),
                         memory_limits: memory_connection_limits::Behaviour::with_max_percentage(
-                            memory_max_percentage,
-                        ),
-                        upnp: upnp::tokio::Behaviour::default(),
-                        auto_nat: autonat::Behaviour::new(
-                            key.public().to_peer_id(),
-                            autonat::Config {
-                                boot_delay: Duration::from_millis(nat_boot_delay),
-                                retry_interval: Duration::from_millis(nat_retry_interval),
-                                only_global_ips: nat_only_global_ips,
-                                use_connected: nat_use_connected,
-                                ..autonat::Config::default()
-                            },
+                            limits.max_memory_percentage,
                         ),
-                        identify: IdentifyBehaviour::new(identify::Config::new(
-                            "rendezvous/1.0.0".to_string(),
-                            key.public(),
-                        )),
-                        relay_server: relay::Behaviour::new(
-                            key.public().to_peer_id(),
-                            relay::Config::default(),
+                        upnp: Toggle::from(behaviours.upnp.then(upnp::tokio::Behaviour::default)),
+                        auto_nat: Toggle::from(behaviours.autonat.then(|| {
+                            autonat::Behaviour::new(
+                                key.public().to_peer_id(),
+                                autonat::Config {
+                                    timeout: Duration::from_millis(nat.timeout),
+                                    boot_delay: Duration::from_millis(nat.boot_delay),
+                                    refresh_interval: Duration::from_millis(nat.refresh_interval),
+                                    retry_interval: Duration::from_millis(nat.retry_interval),
+                                    only_global_ips: nat.only_global_ips,
+                                    use_connected: nat.use_connected,
+                                    confidence_max: nat.confidence_max,
+                                    ..autonat::Config::default()
+                                },
+                            )
+                        })),
+                        identify: IdentifyBehaviour::new(identify_factory(key)),
+                        relay_server: Toggle::from(
+                            behaviours.relay_server.then(|| relay_server_factory(key)),
                         ),
                         relay_client,
-                        dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
-                        rdv_server: RdvServerBehaviour::new(rdvserver::Config::default()),
-                        rdv_client: rdv_clientBehaviour::new(key.clone()),
-                        pubsub: gossip,
-                        request_response: request_response::cbor::Behaviour::new(
-                            [(
-                                StreamProtocol::new("/transfer/1.0.0"),
-                                ProtocolSupport::Full,
-                            )],
-                            request_response::Config::default(),
+                        dcutr: Toggle::from(
+                            behaviours
+                                .dcutr
+                                .then(|| dcutr::Behaviour::new(key.public().to_peer_id())),
                         ),
+                        rdv_server: Toggle::from(behaviours.rendezvous_server.then(|| {
+                            RdvServerBehaviour::new(
+                                rdvserver::Config::default()
+                                    .with_min_ttl(rendezvous_server.min_ttl)
+                                    .with_max_ttl(rendezvous_server.max_ttl),
+                            )
+                        })),
+                        rdv_client: Toggle::from(
+                            behaviours
+                                .rendezvous_client
+                                .then(|| rdv_clientBehaviour::new(key.clone())),
+                        ),
+                        mdns: Toggle::from(mdns),
+                        kademlia: Toggle::from(behaviours.kademlia.then(|| kademlia_factory(key))),
+                        pubsub: gossip,
+                        request_response,
+                        stream: libp2p_stream::Behaviour::new(),
                     });
                 }
                 Err(err) => return Err(err.into()),
             };
         })
         .map_err(|e| e.to_string())?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(5)))
+        .with_swarm_config(|cfg| {
+            cfg.with_idle_connection_timeout(Duration::from_millis(
+                swarm_config.idle_connection_timeout,
+            ))
+        })
         .build();
-    Ok(Arc::new(Wrapper::new(swarm)))
+    Ok(Arc::new(Wrapper::new(
+        swarm,
+        SwarmOptions {
+            event_buffer_size: swarm_config.event_buffer_size,
+            mdns_auto_dial: mdns_config.auto_dial,
+        },
+    )))
+}
+
+/// Noise and yamux upgrade, preceded by the private network handshake when there is a key.
+fn secure_transport<T, C>(
+    transport: T,
+    psk: Option<PreSharedKey>,
+    noise_config: noise::Config,
+) -> Boxed<(PeerId, StreamMuxerBox)>
+where
+    T: Transport<Output = C> + Send + Unpin + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    match psk {
+        Some(psk) => transport
+            .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+            .upgrade(Version::V1Lazy)
+            .authenticate(noise_config)
+            .multiplex(yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+            .boxed(),
+        None => transport
+            .upgrade(Version::V1Lazy)
+            .authenticate(noise_config)
+            .multiplex(yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+            .boxed(),
+    }
+}
+
+/// Flush after every write: websocket frames are only sent on flush
+/// and the private network handshake does not flush its nonce.
+struct FlushingSocket<S> {
+    inner: S,
+    flush_pending: bool,
+}
+
+impl<S> FlushingSocket<S> {
+    fn new(inner: S) -> Self {
+        FlushingSocket {
+            inner,
+            flush_pending: false,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> FlushingSocket<S> {
+    fn poll_pending_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.flush_pending {
+            futures::ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+            self.flush_pending = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for FlushingSocket<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        // the flush is polled again when the reader is woken up
+        if let Poll::Ready(Err(e)) = self.poll_pending_flush(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FlushingSocket<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        futures::ready!(self.poll_pending_flush(cx))?;
+        let written = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.flush_pending = true;
+        // a pending flush completes with the next read, write or flush
+        let _ = self.poll_pending_flush(cx)?;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.flush_pending = false;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::p2p_config::Libp2pBehavioursConfig;
+    use crate::api::p2p_dial::{libp2p_dial_address, libp2p_isconnected};
+    use crate::api::p2p_key::{pnet_key_from_bytes, pnet_key_generate};
+    use crate::api::p2p_listen::libp2p_listen;
+
+    fn pnet_config(psk: Option<String>) -> Libp2pConfig {
+        let mut config = Libp2pConfig {
+            behaviours: Libp2pBehavioursConfig {
+                upnp: false,
+                autonat: false,
+                dcutr: false,
+                relay_server: false,
+                rendezvous_server: false,
+                rendezvous_client: false,
+                kademlia: false,
+                mdns: false,
+            },
+            ..Libp2pConfig::default()
+        };
+        config.transport.pnet_psk = psk;
+        config
+    }
+
+    async fn connects(listener: &Arc<Wrapper>, address: &str, psk: Option<String>) -> bool {
+        let dialer = create_libp2p_instance(Keypair::generate_ed25519(), pnet_config(psk))
+            .await
+            .unwrap();
+        let address = format!("{}/p2p/{}", address, listener.peer_id());
+        assert!(libp2p_dial_address(&dialer, address).await.is_ok());
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let peer = listener.peer_id().to_string();
+            if libp2p_isconnected(&dialer, peer).await.ok().unwrap() {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pnet_rejects_other_keys() {
+        let key = pnet_key_generate();
+        let other = pnet_key_from_bytes(vec![7u8; 32]).unwrap();
+        let listener =
+            create_libp2p_instance(Keypair::generate_ed25519(), pnet_config(Some(key.clone())))
+                .await
+                .unwrap();
+        for address in ["/ip4/127.0.0.1/tcp/0", "/ip4/127.0.0.1/tcp/0/ws"] {
+            assert!(libp2p_listen(&listener, address.to_string()).await.is_ok());
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let addresses: Vec<String> = listener
+            .with_swarm(|swarm| swarm.listeners().map(|f| f.to_string()).collect())
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(addresses.len(), 2);
+        for address in addresses.iter() {
+            assert!(connects(&listener, address, Some(key.clone())).await);
+            assert!(!connects(&listener, address, Some(other.clone())).await);
+            assert!(!connects(&listener, address, None).await);
+        }
+        let mut quic = pnet_config(Some(key));
+        quic.transport.quic_enabled = true;
+        assert!(create_libp2p_instance(Keypair::generate_ed25519(), quic)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quic_connects() {
+        let mut config = pnet_config(None);
+        config.transport.quic_enabled = true;
+        let listener = create_libp2p_instance(Keypair::generate_ed25519(), config.clone())
+            .await
+            .unwrap();
+        let address = "/ip4/127.0.0.1/udp/0/quic-v1".to_string();
+        assert!(libp2p_listen(&listener, address).await.is_ok());
+        let mut addresses: Vec<String> = vec![];
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            addresses = listener
+                .with_swarm(|swarm| swarm.listeners().map(|f| f.to_string()).collect())
+                .await
+                .ok()
+                .unwrap();
+            if !addresses.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(addresses.len(), 1);
+        assert!(addresses[0].ends_with("/quic-v1"));
+        let dialer = create_libp2p_instance(Keypair::generate_ed25519(), config)
+            .await
+            .unwrap();
+        let address = format!("{}/p2p/{}", addresses[0], listener.peer_id());
+        assert!(libp2p_dial_address(&dialer, address).await.is_ok());
+        let mut connected = false;
+        for _ in 0..20 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let peer = listener.peer_id().to_string();
+            if libp2p_isconnected(&dialer, peer).await.ok().unwrap() {
+                connected = true;
+                break;
+            }
+        }
+        assert!(connected);
+    }
+
+    #[tokio::test]
+    async fn relay_rate_limit_needs_interval() {
+        let mut config = pnet_config(None);
+        config.relay_server.rate_limit_interval = 0;
+        assert!(
+            create_libp2p_instance(Keypair::generate_ed25519(), config.clone())
+                .await
+                .is_err()
+        );
+        config.relay_server.reservation_rate_limit = 0;
+        config.relay_server.circuit_rate_limit = 0;
+        assert!(create_libp2p_instance(Keypair::generate_ed25519(), config)
+            .await
+            .is_ok());
+    }
 }
@@ -0,0 +1,1145 @@
+use super::{
+    p2p_common::{CustomSwarmEvent, GenericError},
+    p2p_stream::{accept_streams, open_stream},
+    p2p_utils::CancellationToken,
+    sled::CustomDB,
+    wrapper::Wrapper,
+};
+use flutter_rust_bridge::frb;
+use futures::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use libp2p::{PeerId, Stream, StreamProtocol};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{broadcast, OwnedSemaphorePermit, Semaphore};
+
+//
+// CONSTANTS
+//
+const FILE_PROTOCOL: StreamProtocol = StreamProtocol::new("/p2p-db/file/1.0.0");
+const TREE_FILE_TRANSFERS: &str = "__file_transfers";
+const CHUNK_SIZE: u32 = 256 * 1024;
+// larger chunks are refused by the receiver
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+const HASH_SIZE: usize = 32;
+// interrupted transfers are retried after this delay when the peer does not reconnect before
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+// a peer that neither reads nor writes for this delay interrupts the transfer
+const STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+// partial files not written for this delay belong to cancelled or abandoned transfers
+const PARTIAL_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+const PARTIAL_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const PARTIAL_EXTENSION: &str = "part";
+// each inbound transfer holds a chunk in memory, the streams above it are reset
+// and their senders retry later
+const MAX_INBOUND_TRANSFERS: usize = 16;
+// every reply of the receiver is a kind followed by an offset (u64)
+const REPLY_OFFSET: u8 = 0;
+const REPLY_COMPLETED: u8 = 1;
+const REPLY_HASH_MISMATCH: u8 = 2;
+const REPLY_REJECTED: u8 = 3;
+
+//
+// STRUCTS
+//
+/// A file sent by this node, until it is received or abandoned.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct CustomFileTransfer {
+    pub id: String,
+    pub peer_id: String,
+    pub path: String,
+    pub size: u64,
+    /// Sha256 of the file.
+    pub hash: Vec<u8>,
+    /// Bytes confirmed by the receiver.
+    pub transferred: u64,
+    pub paused: bool,
+}
+
+/// Sent at the start of each stream, the id is kept when the transfer resumes.
+struct FileOffer {
+    id: String,
+    name: String,
+    size: u64,
+    chunk_size: u32,
+    hash: Vec<u8>,
+}
+
+pub(crate) struct OutgoingFile {
+    peer: PeerId,
+    path: PathBuf,
+    offer: Arc<FileOffer>,
+    transferred: Arc<AtomicU64>,
+    // none while paused
+    task: Option<CancellationToken>,
+}
+
+impl OutgoingFile {
+    // size (u64) + chunk size (u32) + hash + paused (u8) + peer length (u8) + peer + path
+    fn encode(&self) -> Vec<u8> {
+        let peer = self.peer.to_bytes();
+        let path = self.path.to_string_lossy();
+        let mut bytes = Vec::with_capacity(14 + HASH_SIZE + peer.len() + path.len());
+        bytes.extend_from_slice(&self.offer.size.to_be_bytes());
+        bytes.extend_from_slice(&self.offer.chunk_size.to_be_bytes());
+        bytes.extend_from_slice(&self.offer.hash);
+        bytes.push(self.task.is_none() as u8);
+        bytes.push(peer.len() as u8);
+        bytes.extend_from_slice(&peer);
+        bytes.extend_from_slice(path.as_bytes());
+        bytes
+    }
+
+    // the transfer and whether it was paused
+    fn decode(id: &[u8], bytes: &[u8]) -> Option<(OutgoingFile, bool)> {
+        let header = 12 + HASH_SIZE;
+        let peer_len = *bytes.get(header + 1)? as usize;
+        let rest = bytes.get(header + 2..)?;
+        let peer = PeerId::from_bytes(rest.get(..peer_len)?).ok()?;
+        let path = PathBuf::from(std::str::from_utf8(&rest[peer_len..]).ok()?);
+        let offer = FileOffer {
+            id: std::str::from_utf8(id).ok()?.to_string(),
+            name: path.file_name()?.to_str()?.to_string(),
+            size: u64::from_be_bytes(bytes[0..8].try_into().ok()?),
+            chunk_size: u32::from_be_bytes(bytes[8..12].try_into().ok()?),
+            hash: bytes[12..header].to_vec(),
+        };
+        let transfer = OutgoingFile {
+            peer,
+            path,
+            offer: Arc::new(offer),
+            transferred: Arc::new(AtomicU64::new(0)),
+            task: None,
+        };
+        Some((transfer, bytes[header] != 0))
+    }
+}
+
+/// Files sent by this node by transfer id, saved in the database once attached.
+#[derive(Default)]
+#[frb(ignore)]
+#[frb(opaque)]
+pub(crate) struct FileTransfers {
+    outgoing: HashMap<String, OutgoingFile>,
+    tree: Option<sled::Tree>,
+}
+
+impl FileTransfers {
+    fn insert(&mut self, transfer: OutgoingFile) {
+        self.save(&transfer);
+        self.outgoing.insert(transfer.offer.id.clone(), transfer);
+    }
+
+    fn remove(&mut self, id: &str) -> Option<OutgoingFile> {
+        if let Some(tree) = self.tree.as_ref() {
+            let _ = tree.remove(id);
+        }
+        self.outgoing.remove(id)
+    }
+
+    // saved again when paused or resumed
+    fn save(&self, transfer: &OutgoingFile) {
+        if let Some(tree) = self.tree.as_ref() {
+            let _ = tree.insert(transfer.offer.id.as_bytes(), transfer.encode());
+        }
+    }
+}
+
+enum TransferError {
+    /// The stream was closed, the transfer resumes from the last confirmed chunk.
+    Interrupted(String),
+    Failed(String),
+}
+
+impl From<std::io::Error> for TransferError {
+    // stream errors, the file errors are failures
+    fn from(error: std::io::Error) -> Self {
+        TransferError::Interrupted(error.to_string())
+    }
+}
+
+//
+// FUNCTIONS
+//
+/// Send the file to the peer in chunks, it is dialed when it is not connected. The transfer
+/// resumes from the last chunk written by the receiver when the peer connects again and is
+/// reported by the `FileTransfer*` events. The transfers are lost with the instance unless
+/// `libp2p_file_attach` saves them in a database.
+pub async fn libp2p_file_send(
+    wrapper: &Arc<Wrapper>,
+    peerid: String,
+    path: String,
+) -> Result<String, GenericError> {
+    let peer = peerid
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    let path = PathBuf::from(path);
+    let name = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or_else(|| GenericError::Other("file_bad_name".to_string()))?
+        .to_string();
+    let (size, hash) = hash_file(&path)
+        .await
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    let id = format!("{:016x}", rand::random::<u64>());
+    let offer = FileOffer {
+        id: id.clone(),
+        name,
+        size,
+        chunk_size: CHUNK_SIZE,
+        hash,
+    };
+    let transfer = OutgoingFile {
+        peer,
+        path,
+        offer: Arc::new(offer),
+        transferred: Arc::new(AtomicU64::new(0)),
+        task: None,
+    };
+    wrapper
+        .call(move |state| state.file_transfers.insert(transfer))
+        .await?;
+    start(wrapper, id.clone()).await?;
+    Ok(id)
+}
+
+/// Save the transfers in the database and restart the transfers saved by a previous instance,
+/// except the paused ones. Returns the number of transfers restored.
+pub async fn libp2p_file_attach(
+    wrapper: &Arc<Wrapper>,
+    db: &Arc<CustomDB>,
+) -> Result<usize, GenericError> {
+    let tree = db
+        .db
+        .open_tree(TREE_FILE_TRANSFERS)
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    let mut restored = vec![];
+    for current in tree.iter() {
+        let (key, value) = current.map_err(|e| GenericError::Other(e.to_string()))?;
+        match OutgoingFile::decode(&key, &value) {
+            Some(transfer) => restored.push(transfer),
+            None => {
+                let _ = tree.remove(key);
+            }
+        }
+    }
+    let count = restored.len();
+    let to_start = wrapper
+        .call(move |state| {
+            let transfers = &mut state.file_transfers;
+            transfers.tree = Some(tree);
+            // the transfers started before are saved too
+            for transfer in transfers.outgoing.values() {
+                transfers.save(transfer);
+            }
+            let mut to_start = vec![];
+            for (transfer, paused) in restored {
+                if transfers.outgoing.contains_key(&transfer.offer.id) {
+                    continue;
+                }
+                if !paused {
+                    to_start.push(transfer.offer.id.clone());
+                }
+                transfers
+                    .outgoing
+                    .insert(transfer.offer.id.clone(), transfer);
+            }
+            to_start
+        })
+        .await?;
+    for id in to_start {
+        start(wrapper, id).await?;
+    }
+    Ok(count)
+}
+
+/// Stop sending the file, false when it is already paused.
+pub async fn libp2p_file_pause(wrapper: &Arc<Wrapper>, id: String) -> Result<bool, GenericError> {
+    let paused = wrapper
+        .call(move |state| {
+            let transfers = &mut state.file_transfers;
+            let transfer = transfers
+                .outgoing
+                .get_mut(&id)
+                .ok_or_else(|| GenericError::Other("file_transfer_not_found".to_string()))?;
+            let paused = transfer.task.take().map(|task| {
+                task.cancel();
+                CustomSwarmEvent::FileTransferPaused {
+                    id: id.clone(),
+                    peer: transfer.peer.to_string(),
+                    outbound: true,
+                    transferred: transfer.transferred.load(Ordering::Relaxed),
+                    error: None,
+                }
+            });
+            transfers.save(&transfers.outgoing[&id]);
+            Ok::<_, GenericError>(paused)
+        })
+        .await??;
+    Ok(match paused {
+        Some(event) => {
+            wrapper.emit(event);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Send the rest of a paused file, false when it is not paused.
+pub async fn libp2p_file_resume(wrapper: &Arc<Wrapper>, id: String) -> Result<bool, GenericError> {
+    start(wrapper, id).await
+}
+
+/// Abandon the transfer, the receiver removes the chunks already written once they expire.
+pub async fn libp2p_file_cancel(wrapper: &Arc<Wrapper>, id: String) -> Result<bool, GenericError> {
+    wrapper
+        .call(move |state| match state.file_transfers.remove(&id) {
+            Some(transfer) => {
+                if let Some(task) = transfer.task {
+                    task.cancel();
+                }
+                true
+            }
+            None => false,
+        })
+        .await
+}
+
+pub async fn libp2p_file_transfers(
+    wrapper: &Arc<Wrapper>,
+) -> Result<Vec<CustomFileTransfer>, GenericError> {
+    wrapper
+        .call(|state| {
+            state
+                .file_transfers
+                .outgoing
+                .values()
+                .map(|f| CustomFileTransfer {
+                    id: f.offer.id.clone(),
+                    peer_id: f.peer.to_string(),
+                    path: f.path.to_string_lossy().to_string(),
+                    size: f.offer.size,
+                    hash: f.offer.hash.clone(),
+                    transferred: f.transferred.load(Ordering::Relaxed),
+                    paused: f.task.is_none(),
+                })
+                .collect()
+        })
+        .await
+}
+
+/// Receive the files sent by peers in the directory until cancelled, false when cancelled and
+/// true when the instance is dropped. Files larger than `max_size` bytes are rejected and at most
+/// 16 files are received at once. Partial files are kept next to them to resume the transfers,
+/// and removed after a day without progress.
+pub async fn libp2p_file_accept(
+    wrapper: &Arc<Wrapper>,
+    directory: String,
+    max_size: Option<u64>,
+    cancellation_token: &CancellationToken,
+) -> Result<bool, GenericError> {
+    accept_files(
+        wrapper,
+        PathBuf::from(directory),
+        max_size,
+        MAX_INBOUND_TRANSFERS,
+        cancellation_token,
+    )
+    .await
+}
+
+//
+// SENDER
+//
+// false when the transfer is already running
+async fn start(wrapper: &Arc<Wrapper>, id: String) -> Result<bool, GenericError> {
+    let events = Arc::downgrade(wrapper);
+    wrapper
+        .call(move |state| {
+            let transfers = &mut state.file_transfers;
+            let transfer = transfers
+                .outgoing
+                .get_mut(&id)
+                .ok_or_else(|| GenericError::Other("file_transfer_not_found".to_string()))?;
+            if transfer.task.is_some() {
+                return Ok(false);
+            }
+            let task = CancellationToken::new();
+            transfer.task = Some(task.clone());
+            let transfer = &transfers.outgoing[&id];
+            transfers.save(transfer);
+            tokio::spawn(send_file(
+                events,
+                transfer.peer,
+                transfer.path.clone(),
+                transfer.offer.clone(),
+                transfer.transferred.clone(),
+                task,
+            ));
+            Ok(true)
+        })
+        .await?
+}
+
+async fn send_file(
+    wrapper: Weak<Wrapper>,
+    peer: PeerId,
+    path: PathBuf,
+    offer: Arc<FileOffer>,
+    transferred: Arc<AtomicU64>,
+    task: CancellationToken,
+) {
+    loop {
+        // subscribed before the attempt to not miss the reconnection
+        let Some(mut events) = wrapper.upgrade().map(|f| f.subscribe()) else {
+            return;
+        };
+        let result = tokio::select! {
+            _ = task.cancelled() => return,
+            // the streams of a closed connection are not woken up
+            _ = disconnected(&mut events, peer) => {
+                Err(TransferError::Interrupted("connection_closed".to_string()))
+            }
+            result = send_once(&wrapper, peer, &path, &offer, &transferred) => result,
+        };
+        let event = match result {
+            Ok(()) => CustomSwarmEvent::FileTransferCompleted {
+                id: offer.id.clone(),
+                peer: peer.to_string(),
+                outbound: true,
+                path: path.to_string_lossy().to_string(),
+            },
+            Err(TransferError::Failed(error)) => CustomSwarmEvent::FileTransferFailed {
+                id: offer.id.clone(),
+                peer: peer.to_string(),
+                outbound: true,
+                error,
+            },
+            Err(TransferError::Interrupted(error)) => {
+                emit(
+                    &wrapper,
+                    CustomSwarmEvent::FileTransferPaused {
+                        id: offer.id.clone(),
+                        peer: peer.to_string(),
+                        outbound: true,
+                        transferred: transferred.load(Ordering::Relaxed),
+                        error: Some(error),
+                    },
+                );
+                tokio::select! {
+                    _ = task.cancelled() => return,
+                    _ = tokio::time::timeout(RETRY_DELAY, connected(&mut events, peer)) => continue,
+                }
+            }
+        };
+        let Some(strong) = wrapper.upgrade() else {
+            return;
+        };
+        let id = offer.id.clone();
+        // unless cancelled in the meantime
+        let _ = strong
+            .call(move |state| state.file_transfers.remove(&id))
+            .await;
+        strong.emit(event);
+        return;
+    }
+}
+
+async fn send_once(
+    wrapper: &Weak<Wrapper>,
+    peer: PeerId,
+    path: &Path,
+    offer: &FileOffer,
+    transferred: &AtomicU64,
+) -> Result<(), TransferError> {
+    let mut stream = {
+        let strong = wrapper
+            .upgrade()
+            .ok_or_else(|| TransferError::Failed("instance_dropped".to_string()))?;
+        open_stream(&strong, peer, FILE_PROTOCOL)
+            .await
+            .map_err(|e| TransferError::Interrupted(error_message(e)))?
+    };
+    write_offer(&mut stream, offer).await?;
+    let mut offset = match read_reply(&mut stream).await? {
+        (REPLY_OFFSET, offset) if offset <= offer.size => offset,
+        (REPLY_REJECTED, _) => return Err(TransferError::Failed("file_rejected".to_string())),
+        _ => return Err(TransferError::Failed("file_bad_reply".to_string())),
+    };
+    transferred.store(offset, Ordering::Relaxed);
+    emit(
+        wrapper,
+        CustomSwarmEvent::FileTransferStarted {
+            id: offer.id.clone(),
+            peer: peer.to_string(),
+            outbound: true,
+            name: offer.name.clone(),
+            size: offer.size,
+            offset,
+        },
+    );
+    let mut file = tokio::fs::File::open(path).await.map_err(file_error)?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(file_error)?;
+    let mut buffer = vec![0u8; offer.chunk_size as usize];
+    // one chunk at a time, the receiver confirms each chunk once written to disk
+    while offset < offer.size {
+        let size = (offer.size - offset).min(offer.chunk_size as u64) as usize;
+        file.read_exact(&mut buffer[..size])
+            .await
+            .map_err(file_error)?;
+        timed(async {
+            stream.write_all(&(size as u32).to_be_bytes()).await?;
+            stream.write_all(&buffer[..size]).await?;
+            stream.flush().await
+        })
+        .await?;
+        offset = match read_reply(&mut stream).await? {
+            (REPLY_OFFSET, confirmed) if confirmed == offset + size as u64 => confirmed,
+            _ => return Err(TransferError::Failed("file_bad_reply".to_string())),
+        };
+        transferred.store(offset, Ordering::Relaxed);
+        emit(
+            wrapper,
+            CustomSwarmEvent::FileTransferProgress {
+                id: offer.id.clone(),
+                peer: peer.to_string(),
+                outbound: true,
+                transferred: offset,
+                size: offer.size,
+            },
+        );
+    }
+    let result = match read_reply(&mut stream).await? {
+        (REPLY_COMPLETED, _) => Ok(()),
+        (REPLY_HASH_MISMATCH, _) => Err(TransferError::Failed("file_hash_mismatch".to_string())),
+        _ => Err(TransferError::Failed("file_bad_reply".to_string())),
+    };
+    let _ = stream.close().await;
+    result
+}
+
+// wait for the last connection to the peer to be closed, until the instance is dropped
+async fn disconnected(events: &mut broadcast::Receiver<CustomSwarmEvent>, peer: PeerId) {
+    let peer = peer.to_string();
+    loop {
+        match events.recv().await {
+            Ok(CustomSwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            }) if peer_id == peer => return,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+// wait for a connection to the peer, until the instance is dropped
+async fn connected(events: &mut broadcast::Receiver<CustomSwarmEvent>, peer: PeerId) {
+    let peer = peer.to_string();
+    loop {
+        match events.recv().await {
+            Ok(CustomSwarmEvent::ConnectionEstablished { peer_id, .. }) if peer_id == peer => {
+                return;
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+//
+// RECEIVER
+//
+async fn accept_files(
+    wrapper: &Arc<Wrapper>,
+    directory: PathBuf,
+    max_size: Option<u64>,
+    max_transfers: usize,
+    cancellation_token: &CancellationToken,
+) -> Result<bool, GenericError> {
+    tokio::fs::create_dir_all(&directory)
+        .await
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    let events = Arc::downgrade(wrapper);
+    let transfers = Arc::new(Semaphore::new(max_transfers));
+    let accept = accept_streams(
+        wrapper,
+        FILE_PROTOCOL,
+        cancellation_token,
+        |peer, stream| {
+            // the stream is dropped, hence reset, when too many files are received
+            if let Ok(permit) = transfers.clone().try_acquire_owned() {
+                tokio::spawn(receive_file(
+                    events.clone(),
+                    directory.clone(),
+                    max_size,
+                    peer,
+                    stream,
+                    permit,
+                ));
+            }
+            Ok(())
+        },
+    );
+    tokio::select! {
+        result = accept => result,
+        never = sweep_partial_files(&directory) => match never {},
+    }
+}
+
+async fn receive_file(
+    wrapper: Weak<Wrapper>,
+    directory: PathBuf,
+    max_size: Option<u64>,
+    peer: PeerId,
+    mut stream: Stream,
+    _permit: OwnedSemaphorePermit,
+) {
+    let Ok(offer) = timed(read_offer(&mut stream)).await else {
+        return;
+    };
+    let transferred = AtomicU64::new(0);
+    let result = receive_once(
+        &wrapper,
+        &directory,
+        max_size,
+        peer,
+        &offer,
+        &mut stream,
+        &transferred,
+    )
+    .await;
+    let event = match result {
+        Ok(path) => CustomSwarmEvent::FileTransferCompleted {
+            id: offer.id,
+            peer: peer.to_string(),
+            outbound: false,
+            path: path.to_string_lossy().to_string(),
+        },
+        Err(TransferError::Failed(error)) => CustomSwarmEvent::FileTransferFailed {
+            id: offer.id,
+            peer: peer.to_string(),
+            outbound: false,
+            error,
+        },
+        Err(TransferError::Interrupted(error)) => CustomSwarmEvent::FileTransferPaused {
+            id: offer.id,
+            peer: peer.to_string(),
+            outbound: false,
+            transferred: transferred.load(Ordering::Relaxed),
+            error: Some(error),
+        },
+    };
+    emit(&wrapper, event);
+    let _ = stream.close().await;
+}
+
+async fn receive_once(
+    wrapper: &Weak<Wrapper>,
+    directory: &Path,
+    max_size: Option<u64>,
+    peer: PeerId,
+    offer: &FileOffer,
+    stream: &mut Stream,
+    transferred: &AtomicU64,
+) -> Result<PathBuf, TransferError> {
+    // the name must not escape the directory
+    let valid_name = Path::new(&offer.name).file_name() == Some(offer.name.as_ref());
+    let valid_id = offer.id.chars().all(|f| f.is_ascii_alphanumeric());
+    if !valid_name || !valid_id || offer.chunk_size == 0 || offer.chunk_size > MAX_CHUNK_SIZE {
+        write_reply(stream, REPLY_REJECTED, 0).await?;
+        return Err(TransferError::Failed("file_bad_offer".to_string()));
+    }
+    if max_size.is_some_and(|max| offer.size > max) {
+        write_reply(stream, REPLY_REJECTED, 0).await?;
+        return Err(TransferError::Failed("file_too_large".to_string()));
+    }
+    // the chunks of a previous attempt are kept in the partial file, ids are chosen by the peers
+    let partial = directory.join(partial_name(offer, &peer));
+    let result = receive_partial(
+        wrapper,
+        directory,
+        &partial,
+        peer,
+        offer,
+        stream,
+        transferred,
+    )
+    .await;
+    // failed transfers are not resumed by the sender
+    if let Err(TransferError::Failed(_)) = result {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    result
+}
+
+async fn receive_partial(
+    wrapper: &Weak<Wrapper>,
+    directory: &Path,
+    partial: &Path,
+    peer: PeerId,
+    offer: &FileOffer,
+    stream: &mut Stream,
+    transferred: &AtomicU64,
+) -> Result<PathBuf, TransferError> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(false)
+        .open(partial)
+        .await
+        .map_err(file_error)?;
+    let length = file.metadata().await.map_err(file_error)?.len();
+    let mut offset = (length - length % offer.chunk_size as u64).min(offer.size);
+    file.set_len(offset).await.map_err(file_error)?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(file_error)?;
+    transferred.store(offset, Ordering::Relaxed);
+    write_reply(stream, REPLY_OFFSET, offset).await?;
+    emit(
+        wrapper,
+        CustomSwarmEvent::FileTransferStarted {
+            id: offer.id.clone(),
+            peer: peer.to_string(),
+            outbound: false,
+            name: offer.name.clone(),
+            size: offer.size,
+            offset,
+        },
+    );
+    let mut buffer = vec![0u8; offer.chunk_size as usize];
+    while offset < offer.size {
+        let expected = (offer.size - offset).min(offer.chunk_size as u64) as usize;
+        let mut size = [0u8; 4];
+        timed(stream.read_exact(&mut size)).await?;
+        if u32::from_be_bytes(size) as usize != expected {
+            return Err(TransferError::Failed("file_bad_chunk".to_string()));
+        }
+        timed(stream.read_exact(&mut buffer[..expected])).await?;
+        file.write_all(&buffer[..expected])
+            .await
+            .map_err(file_error)?;
+        // confirmed chunks must survive a crash
+        file.sync_data().await.map_err(file_error)?;
+        offset += expected as u64;
+        transferred.store(offset, Ordering::Relaxed);
+        write_reply(stream, REPLY_OFFSET, offset).await?;
+        emit(
+            wrapper,
+            CustomSwarmEvent::FileTransferProgress {
+                id: offer.id.clone(),
+                peer: peer.to_string(),
+                outbound: false,
+                transferred: offset,
+                size: offer.size,
+            },
+        );
+    }
+    drop(file);
+    let (_, hash) = hash_file(partial).await.map_err(file_error)?;
+    if hash != offer.hash {
+        write_reply(stream, REPLY_HASH_MISMATCH, offset).await?;
+        return Err(TransferError::Failed("file_hash_mismatch".to_string()));
+    }
+    // existing files are not replaced
+    let mut path = directory.join(&offer.name);
+    if tokio::fs::try_exists(&path).await.unwrap_or(true) {
+        path = directory.join(format!("{}-{}", offer.id, offer.name));
+    }
+    tokio::fs::rename(partial, &path)
+        .await
+        .map_err(file_error)?;
+    write_reply(stream, REPLY_COMPLETED, offset).await?;
+    Ok(path)
+}
+
+// remove the partial files that are not written anymore, until the future is dropped
+async fn sweep_partial_files(directory: &Path) -> Infallible {
+    let mut interval = tokio::time::interval(PARTIAL_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let _ = remove_expired_partial_files(directory, SystemTime::now()).await;
+    }
+}
+
+async fn remove_expired_partial_files(directory: &Path, now: SystemTime) -> std::io::Result<()> {
+    let mut entries = tokio::fs::read_dir(directory).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|f| f.to_str()) != Some(PARTIAL_EXTENSION) {
+            continue;
+        }
+        let modified = entry.metadata().await.and_then(|f| f.modified());
+        let expired = modified
+            .ok()
+            .and_then(|f| now.duration_since(f).ok())
+            .is_some_and(|f| f >= PARTIAL_EXPIRY);
+        if expired {
+            let _ = tokio::fs::remove_file(&path).await;
+        }
+    }
+    Ok(())
+}
+
+//
+// UTILS
+//
+fn partial_name(offer: &FileOffer, peer: &PeerId) -> String {
+    format!("{}.{}.{}.{}", offer.name, peer, offer.id, PARTIAL_EXTENSION)
+}
+
+fn emit(wrapper: &Weak<Wrapper>, event: CustomSwarmEvent) {
+    if let Some(wrapper) = wrapper.upgrade() {
+        wrapper.emit(event);
+    }
+}
+
+fn file_error(error: std::io::Error) -> TransferError {
+    TransferError::Failed(error.to_string())
+}
+
+fn error_message(error: GenericError) -> String {
+    match error {
+        GenericError::Other(message) => message,
+        _ => "instance_dropped".to_string(),
+    }
+}
+
+async fn timed<T>(future: impl Future<Output = std::io::Result<T>>) -> std::io::Result<T> {
+    tokio::time::timeout(STREAM_TIMEOUT, future)
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+}
+
+async fn hash_file(path: &Path) -> std::io::Result<(u64, Vec<u8>)> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE as usize];
+    let mut size = 0u64;
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok((size, hasher.finalize().to_vec()));
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+}
+
+async fn write_offer(stream: &mut Stream, offer: &FileOffer) -> std::io::Result<()> {
+    let mut bytes = Vec::with_capacity(offer.id.len() + offer.name.len() + HASH_SIZE + 15);
+    bytes.push(offer.id.len() as u8);
+    bytes.extend_from_slice(offer.id.as_bytes());
+    bytes.extend_from_slice(&(offer.name.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(offer.name.as_bytes());
+    bytes.extend_from_slice(&offer.size.to_be_bytes());
+    bytes.extend_from_slice(&offer.chunk_size.to_be_bytes());
+    bytes.extend_from_slice(&offer.hash);
+    stream.write_all(&bytes).await?;
+    stream.flush().await
+}
+
+async fn read_offer(stream: &mut Stream) -> std::io::Result<FileOffer> {
+    let mut id_size = [0u8; 1];
+    stream.read_exact(&mut id_size).await?;
+    let mut id = vec![0u8; id_size[0] as usize];
+    stream.read_exact(&mut id).await?;
+    let mut name_size = [0u8; 2];
+    stream.read_exact(&mut name_size).await?;
+    let mut name = vec![0u8; u16::from_be_bytes(name_size) as usize];
+    stream.read_exact(&mut name).await?;
+    let mut size = [0u8; 8];
+    stream.read_exact(&mut size).await?;
+    let mut chunk_size = [0u8; 4];
+    stream.read_exact(&mut chunk_size).await?;
+    let mut hash = vec![0u8; HASH_SIZE];
+    stream.read_exact(&mut hash).await?;
+    let invalid = |_| std::io::Error::from(std::io::ErrorKind::InvalidData);
+    Ok(FileOffer {
+        id: String::from_utf8(id).map_err(invalid)?,
+        name: String::from_utf8(name).map_err(invalid)?,
+        size: u64::from_be_bytes(size),
+        chunk_size: u32::from_be_bytes(chunk_size),
+        hash,
+    })
+}
+
+async fn write_reply(stream: &mut Stream, kind: u8, offset: u64) -> std::io::Result<()> {
+    let mut bytes = [0u8; 9];
+    bytes[0] = kind;
+    bytes[1..].copy_from_slice(&offset.to_be_bytes());
+    stream.write_all(&bytes).await?;
+    stream.flush().await
+}
+
+async fn read_reply(stream: &mut Stream) -> std::io::Result<(u8, u64)> {
+    let mut bytes = [0u8; 9];
+    timed(stream.read_exact(&mut bytes)).await?;
+    let mut offset = [0u8; 8];
+    offset.copy_from_slice(&bytes[1..]);
+    Ok((bytes[0], u64::from_be_bytes(offset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sled::sled_db_open;
+    use crate::test_utils::{connect, listen, test_config, test_instance, TemporaryPath};
+
+    fn temporary_directory(name: &str) -> TemporaryPath {
+        let directory = TemporaryPath::new(&format!("file_{}", name));
+        std::fs::create_dir_all(&directory.0).unwrap();
+        directory
+    }
+
+    /// Connect the sender to a receiver accepting the files in `directory`.
+    async fn connected_pair(
+        directory: &Path,
+        max_size: Option<u64>,
+        max_transfers: usize,
+    ) -> (Arc<Wrapper>, Arc<Wrapper>) {
+        let sender = test_instance(test_config()).await;
+        let receiver = test_instance(test_config()).await;
+        let address = listen(&receiver, "/ip4/127.0.0.1/tcp/0").await;
+        let accepting = receiver.clone();
+        let directory = directory.to_path_buf();
+        tokio::spawn(async move {
+            let token = CancellationToken::new();
+            let _ = accept_files(&accepting, directory, max_size, max_transfers, &token).await;
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(connect(&sender, &receiver, &address).await);
+        (sender, receiver)
+    }
+
+    /// Send the file with a known transfer id, the events of the transfer until it ends.
+    async fn send(
+        sender: &Arc<Wrapper>,
+        peer: PeerId,
+        path: &Path,
+        id: &str,
+    ) -> Vec<CustomSwarmEvent> {
+        let (size, hash) = hash_file(path).await.unwrap();
+        let offer = FileOffer {
+            id: id.to_string(),
+            name: path.file_name().unwrap().to_string_lossy().to_string(),
+            size,
+            chunk_size: CHUNK_SIZE,
+            hash,
+        };
+        let transfer = OutgoingFile {
+            peer,
+            path: path.to_path_buf(),
+            offer: Arc::new(offer),
+            transferred: Arc::new(AtomicU64::new(0)),
+            task: None,
+        };
+        let mut events = sender.subscribe();
+        assert!(sender
+            .call(move |state| state.file_transfers.insert(transfer))
+            .await
+            .is_ok());
+        assert!(start(sender, id.to_string()).await.ok().unwrap());
+        let mut received = vec![];
+        let wait = async {
+            loop {
+                match events.recv().await.unwrap() {
+                    event @ (CustomSwarmEvent::FileTransferStarted { .. }
+                    | CustomSwarmEvent::FileTransferPaused { .. }) => received.push(event),
+                    event @ (CustomSwarmEvent::FileTransferCompleted { .. }
+                    | CustomSwarmEvent::FileTransferFailed { .. }) => {
+                        received.push(event);
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap();
+        received
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transfer_resumes_from_the_partial_file() {
+        let (source, target) = (
+            temporary_directory("resume_source"),
+            temporary_directory("resume_target"),
+        );
+        let (source, target) = (&source.0, &target.0);
+        let content: Vec<u8> = (0..CHUNK_SIZE * 5 / 2).map(|f| f as u8).collect();
+        let path = source.join("data.bin");
+        std::fs::write(&path, &content).unwrap();
+        let (sender, receiver) = connected_pair(target, None, MAX_INBOUND_TRANSFERS).await;
+        // a chunk and a half written by a previous attempt
+        let id = "0123456789abcdef";
+        let offer = FileOffer {
+            id: id.to_string(),
+            name: "data.bin".to_string(),
+            size: 0,
+            chunk_size: CHUNK_SIZE,
+            hash: vec![],
+        };
+        let partial = target.join(partial_name(&offer, &sender.peer_id()));
+        std::fs::write(&partial, &content[..(CHUNK_SIZE * 3 / 2) as usize]).unwrap();
+
+        let events = send(&sender, receiver.peer_id(), &path, id).await;
+        assert!(matches!(
+            events[0],
+            CustomSwarmEvent::FileTransferStarted { offset, .. } if offset == CHUNK_SIZE as u64
+        ));
+        assert!(matches!(
+            events[1],
+            CustomSwarmEvent::FileTransferCompleted { .. }
+        ));
+        assert_eq!(std::fs::read(target.join("data.bin")).unwrap(), content);
+        assert!(!partial.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_files_are_rejected() {
+        let (source, target) = (
+            temporary_directory("large_source"),
+            temporary_directory("large_target"),
+        );
+        let (source, target) = (&source.0, &target.0);
+        let path = source.join("large.bin");
+        std::fs::write(&path, vec![0u8; 1024]).unwrap();
+        let (sender, receiver) = connected_pair(target, Some(1023), MAX_INBOUND_TRANSFERS).await;
+        let events = send(&sender, receiver.peer_id(), &path, "0000000000000001").await;
+        assert!(matches!(
+            &events[..],
+            [CustomSwarmEvent::FileTransferFailed { error, .. }] if error == "file_rejected"
+        ));
+        assert_eq!(std::fs::read_dir(target).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn expired_partial_files_are_removed() {
+        let temporary = temporary_directory("expired");
+        let directory = &temporary.0;
+        let partial = directory.join("data.bin.peer.id.part");
+        let file = directory.join("data.bin");
+        std::fs::write(&partial, b"partial").unwrap();
+        std::fs::write(&file, b"file").unwrap();
+        let now = SystemTime::now();
+        assert!(remove_expired_partial_files(directory, now).await.is_ok());
+        assert!(partial.exists());
+        let later = now + PARTIAL_EXPIRY;
+        assert!(remove_expired_partial_files(directory, later).await.is_ok());
+        assert!(!partial.exists());
+        assert!(file.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn inbound_transfers_are_capped() {
+        let target = temporary_directory("capped");
+        let (sender, receiver) = connected_pair(&target.0, None, 1).await;
+        let offer = |id: &str| FileOffer {
+            id: id.to_string(),
+            name: format!("{}.bin", id),
+            size: 1,
+            chunk_size: CHUNK_SIZE,
+            hash: vec![0; HASH_SIZE],
+        };
+        let mut first = open_stream(&sender, receiver.peer_id(), FILE_PROTOCOL)
+            .await
+            .ok()
+            .unwrap();
+        write_offer(&mut first, &offer("first")).await.unwrap();
+        assert_eq!(read_reply(&mut first).await.unwrap(), (REPLY_OFFSET, 0));
+        // the first transfer is still running
+        let mut second = open_stream(&sender, receiver.peer_id(), FILE_PROTOCOL)
+            .await
+            .ok()
+            .unwrap();
+        let _ = write_offer(&mut second, &offer("second")).await;
+        assert!(read_reply(&mut second).await.is_err());
+        drop(first);
+        let mut third = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let mut stream = open_stream(&sender, receiver.peer_id(), FILE_PROTOCOL)
+                    .await
+                    .ok()
+                    .unwrap();
+                if write_offer(&mut stream, &offer("third")).await.is_ok() {
+                    if let Ok(reply) = read_reply(&mut stream).await {
+                        return (stream, reply);
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(third.1, (REPLY_OFFSET, 0));
+        let _ = third.0.close().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transfers_survive_a_restart() {
+        let source = temporary_directory("restart");
+        let path = source.0.join("data.bin");
+        std::fs::write(&path, b"data").unwrap();
+        let db_path = TemporaryPath::new("file_restart_db");
+        let db = sled_db_open(db_path.0.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap();
+        let first = test_instance(test_config()).await;
+        assert_eq!(libp2p_file_attach(&first, &db).await.ok(), Some(0));
+        // the peer is offline, the transfer waits for it
+        let peer = PeerId::random().to_string();
+        let path_name = path.to_string_lossy().to_string();
+        let id = libp2p_file_send(&first, peer.clone(), path_name.clone())
+            .await
+            .ok()
+            .unwrap();
+        let paused = libp2p_file_send(&first, peer.clone(), path_name.clone())
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(
+            libp2p_file_pause(&first, paused.clone()).await.ok(),
+            Some(true)
+        );
+        let sent = libp2p_file_transfers(&first).await.ok().unwrap();
+        drop(first);
+
+        let second = test_instance(test_config()).await;
+        assert_eq!(libp2p_file_attach(&second, &db).await.ok(), Some(2));
+        let mut restored = libp2p_file_transfers(&second).await.ok().unwrap();
+        restored.sort_by_key(|f| f.id != id);
+        assert_eq!(restored.len(), 2);
+        for transfer in restored.iter() {
+            let original = sent.iter().find(|f| f.id == transfer.id).unwrap();
+            assert_eq!(transfer.peer_id, peer);
+            assert_eq!(transfer.path, path_name);
+            assert_eq!(transfer.hash, original.hash);
+            assert_eq!(transfer.size, 4);
+        }
+        assert_eq!(
+            restored.iter().map(|f| f.paused).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        assert_eq!(libp2p_file_cancel(&second, id).await.ok(), Some(true));
+        assert_eq!(libp2p_file_cancel(&second, paused).await.ok(), Some(true));
+        let third = test_instance(test_config()).await;
+        assert_eq!(libp2p_file_attach(&third, &db).await.ok(), Some(0));
+    }
+}
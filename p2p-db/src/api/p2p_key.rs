@@ -1,5 +1,7 @@
 use flutter_rust_bridge::frb;
 pub use libp2p::identity::{Keypair, PeerId, PublicKey};
+use libp2p_pnet::PreSharedKey;
+use rand::RngCore;
 //
 // ENUM
 //
@@ -84,3 +86,39 @@ pub fn keypair_sign(keypair: &Keypair, msg: Vec<u8>) -> Result<Vec<u8>, String>
 pub fn keypair_verify(key: &PublicKey, msg: Vec<u8>, sig: Vec<u8>) -> bool {
     key.verify(msg.as_slice(), sig.as_slice())
 }
+
+//
+// PRIVATE NETWORK
+//
+/// Random private network key, in the swarm.key format used by go-libp2p.
+#[frb(sync)]
+pub fn pnet_key_generate() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    PreSharedKey::new(bytes).to_string()
+}
+
+/// Private network key from its 32 raw bytes.
+#[frb(sync)]
+pub fn pnet_key_from_bytes(bytes: Vec<u8>) -> Result<String, String> {
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| "pnet_bad_key_length".to_string())?;
+    Ok(PreSharedKey::new(bytes).to_string())
+}
+
+/// Check an imported swarm.key content, returns it normalized.
+#[frb(sync)]
+pub fn pnet_key_import(key: String) -> Result<String, String> {
+    key.parse::<PreSharedKey>()
+        .map(|psk| psk.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Fingerprint of the key, can be shown to compare keys without exposing them.
+#[frb(sync)]
+pub fn pnet_key_fingerprint(key: String) -> Result<String, String> {
+    key.parse::<PreSharedKey>()
+        .map(|psk| psk.fingerprint().to_string())
+        .map_err(|e| e.to_string())
+}
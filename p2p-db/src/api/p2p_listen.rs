@@ -1,7 +1,7 @@
 use std::sync::Arc;
-use libp2p::{core::transport::ListenerId, Swarm};
+use libp2p::core::transport::ListenerId;
 use super::{
-    p2p_common::{CustomBehaviour, GenericError},
+    p2p_common::GenericError,
     wrapper::Wrapper,
 };
 
@@ -9,30 +9,22 @@ pub async fn libp2p_listen(
     wrapper: &Arc<Wrapper>,
     address: String,
 ) -> Result<ListenerId, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        if let Ok(multiaddr) = address.parse() {
-            let res = swarm.listen_on(multiaddr);
-            match res {
-                Ok(res_ok) => return Ok(res_ok),
-                Err(err) => return Err(GenericError::Other(err.to_string())),
-            }
-        } else {
-            Err(GenericError::BadAddress)
+    if let Ok(multiaddr) = address.parse() {
+        let res = wrapper.with_swarm(move |swarm| swarm.listen_on(multiaddr)).await?;
+        match res {
+            Ok(res_ok) => Ok(res_ok),
+            Err(err) => Err(GenericError::Other(err.to_string())),
         }
     } else {
-        Err(GenericError::InstanceNotFound)
+        Err(GenericError::BadAddress)
     }
 }
 pub async fn libp2p_unlisten(
     wrapper: &Arc<Wrapper>,
     listener_id: &ListenerId,
 ) -> Result<bool, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        let res = swarm.remove_listener(*listener_id);
-        Ok(res)
-    } else {
-        Err(GenericError::InstanceNotFound)
-    }
+    let listener_id = *listener_id;
+    wrapper
+        .with_swarm(move |swarm| swarm.remove_listener(listener_id))
+        .await
 }
@@ -0,0 +1,135 @@
+use super::{
+    p2p_common::{CustomPeerRecord, CustomSwarmEvent, GenericError},
+    wrapper::{SwarmState, Wrapper},
+};
+use libp2p::mdns;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::{Multiaddr, PeerId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Peers currently known on the local network.
+pub async fn libp2p_mdns_discovered_peers(
+    wrapper: &Arc<Wrapper>,
+) -> Result<Vec<String>, GenericError> {
+    wrapper
+        .with_swarm(|swarm| match swarm.behaviour().mdns.as_ref() {
+            Some(mdns) => Ok(mdns.discovered_nodes().map(|f| f.to_string()).collect()),
+            None => Err(GenericError::BehaviourDisabled),
+        })
+        .await?
+}
+
+/// Convert an mdns event, discovered peers are dialed when auto dial is enabled.
+pub(crate) fn handle_mdns_event(state: &mut SwarmState, event: mdns::Event) -> CustomSwarmEvent {
+    match event {
+        mdns::Event::Discovered(list) => {
+            let peers = group_by_peer(list);
+            if state.mdns_auto_dial {
+                for (peer, addresses) in peers.iter() {
+                    let dial_opts = DialOpts::peer_id(*peer)
+                        .addresses(addresses.clone())
+                        .condition(PeerCondition::DisconnectedAndNotDialing)
+                        .build();
+                    // failures are reported as OutgoingConnectionError
+                    let _ = state.swarm.dial(dial_opts);
+                }
+            }
+            CustomSwarmEvent::MdnsDiscovered {
+                peers: peer_records(peers),
+            }
+        }
+        mdns::Event::Expired(list) => CustomSwarmEvent::MdnsExpired {
+            peers: peer_records(group_by_peer(list)),
+        },
+    }
+}
+
+fn group_by_peer(list: Vec<(PeerId, Multiaddr)>) -> HashMap<PeerId, Vec<Multiaddr>> {
+    let mut peers: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+    for (peer, address) in list {
+        peers.entry(peer).or_default().push(address);
+    }
+    peers
+}
+
+fn peer_records(peers: HashMap<PeerId, Vec<Multiaddr>>) -> Vec<CustomPeerRecord> {
+    peers
+        .into_iter()
+        .map(|(peer, addresses)| CustomPeerRecord {
+            peer_id: peer.to_string(),
+            addresses: addresses.iter().map(|f| f.to_string()).collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::p2p_config::{Libp2pBehavioursConfig, Libp2pConfig};
+    use crate::api::p2p_dial::libp2p_isconnected;
+    use crate::api::p2p_factory::create_libp2p_instance;
+    use crate::api::p2p_listen::libp2p_listen;
+    use libp2p::identity::Keypair;
+    use std::time::Duration;
+
+    fn mdns_config() -> Libp2pConfig {
+        let mut config = Libp2pConfig {
+            behaviours: Libp2pBehavioursConfig {
+                upnp: false,
+                autonat: false,
+                dcutr: false,
+                relay_server: false,
+                rendezvous_server: false,
+                rendezvous_client: false,
+                kademlia: false,
+                mdns: true,
+            },
+            ..Libp2pConfig::default()
+        };
+        config.mdns.query_interval = 500;
+        config
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn discover_and_dial_on_loopback() {
+        let first = create_libp2p_instance(Keypair::generate_ed25519(), mdns_config())
+            .await
+            .unwrap();
+        let second = create_libp2p_instance(Keypair::generate_ed25519(), mdns_config())
+            .await
+            .unwrap();
+        let second_peer = second.peer_id().to_string();
+        let mut events = first.subscribe();
+        for instance in [&first, &second] {
+            assert!(libp2p_listen(instance, "/ip4/0.0.0.0/tcp/0".to_string())
+                .await
+                .is_ok());
+        }
+        let discovered = tokio::time::timeout(Duration::from_secs(15), async {
+            loop {
+                if let Ok(CustomSwarmEvent::MdnsDiscovered { peers }) = events.recv().await {
+                    if peers.iter().any(|f| f.peer_id == second_peer) {
+                        return;
+                    }
+                }
+            }
+        })
+        .await;
+        assert!(discovered.is_ok(), "second instance not discovered");
+        let known = libp2p_mdns_discovered_peers(&first).await.ok().unwrap();
+        assert!(known.contains(&second_peer));
+        // auto dial
+        let connected = tokio::time::timeout(Duration::from_secs(5), async {
+            while !libp2p_isconnected(&first, second_peer.clone())
+                .await
+                .ok()
+                .unwrap()
+            {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await;
+        assert!(connected.is_ok(), "second instance not dialed");
+    }
+}
@@ -1,8 +1,8 @@
 use super::{
-    p2p_common::{CustomBehaviour, CustomNatStatus, GenericError},
+    p2p_common::{CustomNatStatus, GenericError},
     wrapper::Wrapper,
 };
-use libp2p::{Multiaddr, PeerId, Swarm};
+use libp2p::{Multiaddr, PeerId};
 use std::sync::Arc;
 
 pub async fn libp2p_autonat_add_server(
@@ -13,17 +13,19 @@ pub async fn libp2p_autonat_add_server(
     let peer_unsafe = peer.parse::<PeerId>();
     match peer_unsafe {
         Ok(peer_safe) => {
-            let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-            if let Some(swarm) = swarm_opt {
-                let behaviour = swarm.behaviour_mut();
-                let safe_address = address.map(|f| f.parse::<Multiaddr>().ok());
-                let res = behaviour
-                    .auto_nat
-                    .add_server(peer_safe, safe_address.flatten());
-                Ok(res)
-            } else {
-                Err(GenericError::InstanceNotFound)
-            }
+            let safe_address = address.map(|f| f.parse::<Multiaddr>().ok());
+            wrapper
+                .with_swarm(move |swarm| {
+                    let behaviour = swarm.behaviour_mut();
+                    match behaviour.auto_nat.as_mut() {
+                        Some(auto_nat) => {
+                            auto_nat.add_server(peer_safe, safe_address.flatten());
+                            Ok(())
+                        }
+                        None => Err(GenericError::BehaviourDisabled),
+                    }
+                })
+                .await?
         }
         Err(e) => Err(GenericError::Other(e.to_string())),
     }
@@ -32,11 +34,10 @@ pub async fn libp2p_autonat_add_server(
 pub async fn libp2p_autonat_status(
     wrapper: &Arc<Wrapper>,
 ) -> Result<CustomNatStatus, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        let status = swarm.behaviour().auto_nat.nat_status();
-        Ok(CustomNatStatus::from(status))
-    } else {
-        Err(GenericError::InstanceNotFound)
-    }
+    wrapper
+        .with_swarm(|swarm| match swarm.behaviour().auto_nat.as_ref() {
+            Some(auto_nat) => Ok(CustomNatStatus::from(auto_nat.nat_status())),
+            None => Err(GenericError::BehaviourDisabled),
+        })
+        .await?
 }
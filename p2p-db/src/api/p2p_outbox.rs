@@ -0,0 +1,738 @@
+use super::{
+    p2p_common::{CustomBehaviour, CustomSwarmEvent, GenericError},
+    sled::CustomDB,
+    wrapper::{SwarmState, Wrapper},
+};
+use flutter_rust_bridge::frb;
+use libp2p::request_response::OutboundRequestId;
+use libp2p::{PeerId, Swarm};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//
+// CONSTANTS
+//
+const TREE_OUTBOX: &str = "__outbox";
+const TREE_OUTBOX_DUE: &str = "__outbox_due";
+/// Interval between two checks of the expired and due messages.
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// created at (u64) + expires at (u64) + next attempt (u64) + attempts (u32)
+const RECORD_HEADER_SIZE: usize = 28;
+
+//
+// STRUCTS
+//
+/// Retry policy of the outbox, durations are in milliseconds.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct CustomOutboxConfig {
+    /// Delay before the first retry, doubled after each failed attempt.
+    pub initial_backoff: u64,
+    /// Upper bound of the delay between two attempts.
+    pub max_backoff: u64,
+    /// Messages fail after this number of attempts, none to retry until they expire.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for CustomOutboxConfig {
+    fn default() -> Self {
+        CustomOutboxConfig {
+            initial_backoff: 1_000,
+            max_backoff: 60_000,
+            max_attempts: Some(10),
+        }
+    }
+}
+
+/// A message waiting in the outbox, times are unix timestamps in milliseconds.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct CustomOutboxMessage {
+    pub id: u64,
+    pub peer_id: String,
+    pub protocol: String,
+    pub payload: Vec<u8>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub attempts: u32,
+    /// The message is not sent again before this time.
+    pub next_attempt: u64,
+}
+
+struct OutboxRecord {
+    created_at: u64,
+    expires_at: u64,
+    next_attempt: u64,
+    attempts: u32,
+    protocol: String,
+    payload: Vec<u8>,
+}
+
+impl OutboxRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes =
+            Vec::with_capacity(RECORD_HEADER_SIZE + 2 + self.protocol.len() + self.payload.len());
+        bytes.extend_from_slice(&self.created_at.to_be_bytes());
+        bytes.extend_from_slice(&self.expires_at.to_be_bytes());
+        bytes.extend_from_slice(&self.next_attempt.to_be_bytes());
+        bytes.extend_from_slice(&self.attempts.to_be_bytes());
+        bytes.extend_from_slice(&(self.protocol.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.protocol.as_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<OutboxRecord, String> {
+        if bytes.len() < RECORD_HEADER_SIZE + 2 {
+            return Err("outbox_bad_record".to_string());
+        }
+        let mut attempts = [0u8; 4];
+        attempts.copy_from_slice(&bytes[24..RECORD_HEADER_SIZE]);
+        let len =
+            u16::from_be_bytes([bytes[RECORD_HEADER_SIZE], bytes[RECORD_HEADER_SIZE + 1]]) as usize;
+        let rest = &bytes[RECORD_HEADER_SIZE + 2..];
+        if rest.len() < len {
+            return Err("outbox_bad_record".to_string());
+        }
+        Ok(OutboxRecord {
+            created_at: decode_u64(&bytes[0..8]),
+            expires_at: decode_u64(&bytes[8..16]),
+            next_attempt: decode_u64(&bytes[16..24]),
+            attempts: u32::from_be_bytes(attempts),
+            protocol: String::from_utf8_lossy(&rest[..len]).to_string(),
+            payload: rest[len..].to_vec(),
+        })
+    }
+
+    fn into_message(self, peer: &PeerId, id: u64) -> CustomOutboxMessage {
+        CustomOutboxMessage {
+            id,
+            peer_id: peer.to_string(),
+            protocol: self.protocol,
+            payload: self.payload,
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            attempts: self.attempts,
+            next_attempt: self.next_attempt,
+        }
+    }
+}
+
+/// Outbox attached to a swarm, messages are sent by the swarm task when their peer is connected.
+pub(crate) struct Outbox {
+    // generates the ids of the messages
+    db: sled::Db,
+    tree: sled::Tree,
+    // due time (u64) + key of a message, the sweep only reads the entries that are due.
+    // An entry is a hint: the message may have been sent, rescheduled or removed since.
+    due: sled::Tree,
+    config: CustomOutboxConfig,
+    // requests sent by the outbox => key of their message
+    in_flight: HashMap<(String, OutboundRequestId), Vec<u8>>,
+    // keys of the messages in flight
+    in_flight_keys: HashSet<Vec<u8>>,
+}
+
+impl Outbox {
+    fn queue(
+        &mut self,
+        swarm: &mut Swarm<CustomBehaviour>,
+        peer: PeerId,
+        protocol: String,
+        payload: Vec<u8>,
+        ttl: u64,
+    ) -> Result<(u64, Vec<CustomSwarmEvent>), GenericError> {
+        let id = self
+            .db
+            .generate_id()
+            .map_err(|e| GenericError::Other(e.to_string()))?;
+        let now = now_millis();
+        let record = OutboxRecord {
+            created_at: now,
+            expires_at: now.saturating_add(ttl),
+            next_attempt: now,
+            attempts: 0,
+            protocol,
+            payload,
+        };
+        let key = encode_key(&peer, id);
+        self.tree
+            .insert(key.as_slice(), record.encode())
+            .map_err(|e| GenericError::Other(e.to_string()))?;
+        self.schedule(now, &key);
+        // offline peers are not dialed, the message waits for their next connection
+        if swarm.is_connected(&peer) {
+            return Ok((id, self.send_due(swarm, &peer)));
+        }
+        Ok((id, vec![]))
+    }
+
+    fn cancel(&mut self, id: u64) -> Result<bool, GenericError> {
+        for current in self.tree.iter() {
+            let (key, _) = current.map_err(|e| GenericError::Other(e.to_string()))?;
+            if key.len() >= 8 && decode_u64(&key[key.len() - 8..]) == id {
+                self.tree
+                    .remove(&key)
+                    .map_err(|e| GenericError::Other(e.to_string()))?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Send the due messages of a connected peer, in the order they were queued.
+    pub(crate) fn send_due(
+        &mut self,
+        swarm: &mut Swarm<CustomBehaviour>,
+        peer: &PeerId,
+    ) -> Vec<CustomSwarmEvent> {
+        let now = now_millis();
+        let mut events = vec![];
+        let due: Vec<(Vec<u8>, OutboxRecord)> = self
+            .tree
+            .scan_prefix(peer_prefix(peer))
+            .filter_map(|current| current.ok())
+            .filter_map(|(key, value)| Some((key.to_vec(), OutboxRecord::decode(&value).ok()?)))
+            .filter(|(key, record)| {
+                record.next_attempt <= now && record.expires_at > now && !self.is_in_flight(key)
+            })
+            .collect();
+        for (key, record) in due {
+            events.extend(self.send(swarm, peer, key, record));
+        }
+        events
+    }
+
+    /// Fail the expired messages and send the due messages of the connected peers.
+    pub(crate) fn sweep(&mut self, swarm: &mut Swarm<CustomBehaviour>) -> Vec<CustomSwarmEvent> {
+        let now = now_millis();
+        let mut events = vec![];
+        let due: Vec<Vec<u8>> = self
+            .due
+            .range(..=now.to_be_bytes())
+            .keys()
+            .filter_map(|current| current.ok())
+            .map(|due_key| due_key.to_vec())
+            .collect();
+        for due_key in due {
+            let _ = self.due.remove(&due_key);
+            let key = &due_key[8..];
+            // messages in flight are failed or scheduled again when their request ends
+            if self.is_in_flight(key) {
+                continue;
+            }
+            let (Some((peer, id)), Some(record)) = (decode_key(key), self.get_record(key)) else {
+                continue;
+            };
+            if record.expires_at <= now {
+                let _ = self.tree.remove(key);
+                events.push(failed_event(
+                    &peer,
+                    id,
+                    record,
+                    "outbox_expired".to_string(),
+                ));
+            } else if record.next_attempt > now {
+                self.schedule(record.next_attempt.min(record.expires_at), key);
+            } else if swarm.is_connected(&peer) {
+                events.extend(self.send(swarm, &peer, key.to_vec(), record));
+            } else {
+                // sent by `send_due` when the peer connects, only the expiry is left to check
+                self.schedule(record.expires_at, key);
+            }
+        }
+        events
+    }
+
+    fn on_response(
+        &mut self,
+        protocol: &str,
+        request_id: OutboundRequestId,
+        response: Vec<u8>,
+    ) -> Result<CustomSwarmEvent, Vec<u8>> {
+        let Some(key) = self.in_flight.remove(&(protocol.to_string(), request_id)) else {
+            return Err(response);
+        };
+        self.in_flight_keys.remove(&key);
+        let _ = self.tree.remove(&key);
+        let (peer, id) = decode_key(&key).ok_or(response.clone())?;
+        Ok(CustomSwarmEvent::OutboxDelivered {
+            id,
+            peer: peer.to_string(),
+            protocol: protocol.to_string(),
+            response,
+        })
+    }
+
+    fn on_failure(
+        &mut self,
+        protocol: &str,
+        request_id: OutboundRequestId,
+        error: String,
+    ) -> Option<Vec<CustomSwarmEvent>> {
+        let key = self.in_flight.remove(&(protocol.to_string(), request_id))?;
+        self.in_flight_keys.remove(&key);
+        // cancelled while in flight
+        let (Some(mut record), Some((peer, id))) = (self.get_record(&key), decode_key(&key)) else {
+            return Some(vec![]);
+        };
+        let now = now_millis();
+        let exhausted = self
+            .config
+            .max_attempts
+            .is_some_and(|max| record.attempts >= max);
+        if exhausted || record.expires_at <= now {
+            let _ = self.tree.remove(&key);
+            return Some(vec![failed_event(&peer, id, record, error)]);
+        }
+        record.next_attempt = now.saturating_add(self.backoff(record.attempts));
+        let _ = self.tree.insert(&key, record.encode());
+        self.schedule(record.next_attempt.min(record.expires_at), &key);
+        Some(vec![])
+    }
+
+    fn send(
+        &mut self,
+        swarm: &mut Swarm<CustomBehaviour>,
+        peer: &PeerId,
+        key: Vec<u8>,
+        mut record: OutboxRecord,
+    ) -> Option<CustomSwarmEvent> {
+        let id = decode_key(&key)?.1;
+        let sent = swarm.behaviour_mut().request_response.send_request(
+            Some(&record.protocol),
+            peer,
+            record.payload.clone(),
+        );
+        match sent {
+            Some(request) => {
+                record.attempts = record.attempts.saturating_add(1);
+                let _ = self.tree.insert(&key, record.encode());
+                self.in_flight_keys.insert(key.clone());
+                self.in_flight.insert(request, key);
+                None
+            }
+            // the protocol is not registered anymore
+            None => {
+                let _ = self.tree.remove(&key);
+                Some(failed_event(
+                    peer,
+                    id,
+                    record,
+                    "unknown_protocol".to_string(),
+                ))
+            }
+        }
+    }
+
+    fn is_in_flight(&self, key: &[u8]) -> bool {
+        self.in_flight_keys.contains(key)
+    }
+
+    fn get_record(&self, key: &[u8]) -> Option<OutboxRecord> {
+        let value = self.tree.get(key).ok()??;
+        OutboxRecord::decode(&value).ok()
+    }
+
+    /// Check the message again at `due`.
+    fn schedule(&self, due: u64, key: &[u8]) {
+        let mut due_key = Vec::with_capacity(8 + key.len());
+        due_key.extend_from_slice(&due.to_be_bytes());
+        due_key.extend_from_slice(key);
+        let _ = self.due.insert(due_key, vec![]);
+    }
+
+    fn backoff(&self, attempts: u32) -> u64 {
+        let factor = 1u64 << attempts.saturating_sub(1).min(32);
+        self.config
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.config.max_backoff)
+    }
+}
+
+//
+// FUNCTIONS
+//
+/// Persist the messages of `libp2p_outbox_send` in the database,
+/// messages queued before are sent again once their peer connects.
+pub async fn libp2p_outbox_attach(
+    wrapper: &Arc<Wrapper>,
+    db: &Arc<CustomDB>,
+    config: CustomOutboxConfig,
+) -> Result<(), GenericError> {
+    let tree = open_tree(db).map_err(GenericError::Other)?;
+    let due = db
+        .db
+        .open_tree(TREE_OUTBOX_DUE)
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    let outbox = Outbox {
+        db: db.db.clone(),
+        tree,
+        due,
+        config,
+        in_flight: HashMap::new(),
+        in_flight_keys: HashSet::new(),
+    };
+    // the messages of a lost index are checked at the next sweep
+    if outbox.due.is_empty() {
+        for key in outbox.tree.iter().keys() {
+            let key = key.map_err(|e| GenericError::Other(e.to_string()))?;
+            outbox.schedule(0, &key);
+        }
+    }
+    wrapper.call(move |state| state.outbox = Some(outbox)).await
+}
+
+/// Queue a request for the peer, it is sent when the peer is connected and retried until it is
+/// answered or expires after `ttl` milliseconds. The result is reported by `OutboxDelivered` or
+/// `OutboxFailed`, the default protocol is used when none.
+///
+/// `libp2p_send_request` is left as is: it makes a single attempt whose request id is reported
+/// by the response events, while a message of the outbox is persisted, may be sent many times
+/// and is reported by its own id. Its responses and failures are not reported by
+/// `ResponseMessage` or `RequestOutboundFailure`.
+pub async fn libp2p_outbox_send(
+    wrapper: &Arc<Wrapper>,
+    peerid: String,
+    request: Vec<u8>,
+    ttl: u64,
+    protocol: Option<String>,
+) -> Result<u64, GenericError> {
+    let peer = peerid
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    let (id, events) = wrapper
+        .call(move |state| {
+            let name = state
+                .swarm
+                .behaviour()
+                .request_response
+                .protocol_name(protocol.as_deref())
+                .ok_or(GenericError::UnknownProtocol)?;
+            let outbox = state
+                .outbox
+                .as_mut()
+                .ok_or(GenericError::Other("outbox_not_attached".to_string()))?;
+            outbox.queue(&mut state.swarm, peer, name, request, ttl)
+        })
+        .await??;
+    for event in events {
+        wrapper.emit(event);
+    }
+    Ok(id)
+}
+
+/// Remove a message from the outbox, a request already in flight may still be delivered.
+pub async fn libp2p_outbox_cancel(wrapper: &Arc<Wrapper>, id: u64) -> Result<bool, GenericError> {
+    wrapper
+        .call(move |state| match state.outbox.as_mut() {
+            Some(outbox) => outbox.cancel(id),
+            None => Err(GenericError::Other("outbox_not_attached".to_string())),
+        })
+        .await?
+}
+
+/// Messages waiting in the outbox for the peer, every message when none.
+pub async fn outbox_list(
+    db: &Arc<CustomDB>,
+    peer_id: Option<String>,
+) -> Result<Vec<CustomOutboxMessage>, String> {
+    let tree = open_tree(db)?;
+    let iter = match peer_id {
+        Some(peer_id) => {
+            let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+            tree.scan_prefix(peer_prefix(&peer))
+        }
+        None => tree.iter(),
+    };
+    let mut messages = Vec::new();
+    for current in iter {
+        let (key, value) = current.map_err(|e| e.to_string())?;
+        let (peer, id) = decode_key(&key).ok_or("outbox_bad_key")?;
+        messages.push(OutboxRecord::decode(&value)?.into_message(&peer, id));
+    }
+    Ok(messages)
+}
+
+#[frb(sync)]
+pub fn libp2p_default_outbox_config() -> CustomOutboxConfig {
+    CustomOutboxConfig::default()
+}
+
+/// The delivered event, the response is given back when the request was not sent by the outbox.
+pub(crate) fn outbox_response(
+    state: &mut SwarmState,
+    protocol: &str,
+    request_id: OutboundRequestId,
+    response: Vec<u8>,
+) -> Result<CustomSwarmEvent, Vec<u8>> {
+    match state.outbox.as_mut() {
+        Some(outbox) => outbox.on_response(protocol, request_id, response),
+        None => Err(response),
+    }
+}
+
+/// None when the request was not sent by the outbox, the message is scheduled again
+/// unless it used all its attempts or expired.
+pub(crate) fn outbox_failure(
+    state: &mut SwarmState,
+    protocol: &str,
+    request_id: OutboundRequestId,
+    error: String,
+) -> Option<Vec<CustomSwarmEvent>> {
+    state
+        .outbox
+        .as_mut()?
+        .on_failure(protocol, request_id, error)
+}
+
+//
+// UTILS
+//
+fn open_tree(db: &Arc<CustomDB>) -> Result<sled::Tree, String> {
+    db.db.open_tree(TREE_OUTBOX).map_err(|e| e.to_string())
+}
+
+fn failed_event(peer: &PeerId, id: u64, record: OutboxRecord, error: String) -> CustomSwarmEvent {
+    CustomSwarmEvent::OutboxFailed {
+        id,
+        peer: peer.to_string(),
+        protocol: record.protocol,
+        attempts: record.attempts,
+        error,
+    }
+}
+
+// peer length (u8) + peer + id (u64), the messages of a peer are contiguous and in FIFO order
+fn peer_prefix(peer: &PeerId) -> Vec<u8> {
+    let peer = peer.to_bytes();
+    let mut prefix = Vec::with_capacity(1 + peer.len() + 8);
+    prefix.push(peer.len() as u8);
+    prefix.extend_from_slice(&peer);
+    prefix
+}
+
+fn encode_key(peer: &PeerId, id: u64) -> Vec<u8> {
+    let mut key = peer_prefix(peer);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn decode_key(key: &[u8]) -> Option<(PeerId, u64)> {
+    let len = *key.first()? as usize;
+    if key.len() != 1 + len + 8 {
+        return None;
+    }
+    let peer = PeerId::from_bytes(&key[1..1 + len]).ok()?;
+    Some((peer, decode_u64(&key[1 + len..])))
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(value)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::p2p_config::{Libp2pBehavioursConfig, Libp2pConfig};
+    use crate::api::p2p_factory::create_libp2p_instance;
+    use crate::api::sled::sled_db_open;
+    use libp2p::identity::Keypair;
+
+    async fn outbox_instance(
+        name: &str,
+        config: CustomOutboxConfig,
+    ) -> (Arc<Wrapper>, Arc<CustomDB>) {
+        let path = std::env::temp_dir().join(format!("p2p_outbox_{}_{}", name, std::process::id()));
+        let db = sled_db_open(path.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap();
+        let libp2p_config = Libp2pConfig {
+            behaviours: Libp2pBehavioursConfig {
+                upnp: false,
+                autonat: false,
+                dcutr: false,
+                relay_server: false,
+                rendezvous_server: false,
+                rendezvous_client: false,
+                kademlia: false,
+                mdns: false,
+            },
+            ..Libp2pConfig::default()
+        };
+        let wrapper = create_libp2p_instance(Keypair::generate_ed25519(), libp2p_config)
+            .await
+            .ok()
+            .unwrap();
+        assert!(libp2p_outbox_attach(&wrapper, &db, config).await.is_ok());
+        (wrapper, db)
+    }
+
+    /// Send the first message of the outbox even though its peer is offline, the dial fails.
+    async fn attempt(wrapper: &Arc<Wrapper>) {
+        wrapper
+            .call(|state| {
+                let outbox = state.outbox.as_mut().unwrap();
+                let (key, value) = outbox.tree.iter().next().unwrap().unwrap();
+                let (peer, _) = decode_key(&key).unwrap();
+                let record = OutboxRecord::decode(&value).unwrap();
+                assert!(outbox
+                    .send(&mut state.swarm, &peer, key.to_vec(), record)
+                    .is_none());
+            })
+            .await
+            .ok()
+            .unwrap();
+    }
+
+    async fn failed_event(
+        events: &mut tokio::sync::broadcast::Receiver<CustomSwarmEvent>,
+    ) -> (u64, u32, String) {
+        let wait = async {
+            loop {
+                if let Ok(CustomSwarmEvent::OutboxFailed {
+                    id,
+                    attempts,
+                    error,
+                    ..
+                }) = events.recv().await
+                {
+                    return (id, attempts, error);
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_attempts_back_off() {
+        let config = CustomOutboxConfig {
+            initial_backoff: 60_000,
+            max_backoff: 90_000,
+            max_attempts: Some(2),
+        };
+        let (wrapper, db) = outbox_instance("backoff", config).await;
+        let backoffs: Vec<u64> = wrapper
+            .call(|state| {
+                let outbox = state.outbox.as_ref().unwrap();
+                [1, 2, 3, u32::MAX]
+                    .map(|attempts| outbox.backoff(attempts))
+                    .to_vec()
+            })
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(backoffs, vec![60_000, 90_000, 90_000, 90_000]);
+
+        let mut events = wrapper.subscribe();
+        let peer = PeerId::random().to_string();
+        let id = libp2p_outbox_send(&wrapper, peer.clone(), b"ping".to_vec(), 3_600_000, None)
+            .await
+            .ok()
+            .unwrap();
+        // offline peers are not dialed
+        let queued = outbox_list(&db, Some(peer.clone())).await.unwrap();
+        assert_eq!(queued[0].attempts, 0);
+
+        let before = now_millis();
+        attempt(&wrapper).await;
+        let retry = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let message = outbox_list(&db, None).await.unwrap().remove(0);
+                if message.next_attempt > before {
+                    return message;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(retry.attempts, 1);
+        assert!(retry.next_attempt >= before + 60_000);
+
+        // the last attempt fails the message
+        attempt(&wrapper).await;
+        let (failed, attempts, _) = failed_event(&mut events).await;
+        assert_eq!((failed, attempts), (id, 2));
+        assert!(outbox_list(&db, None).await.unwrap().is_empty());
+    }
+
+    fn due_times(db: &Arc<CustomDB>) -> Vec<u64> {
+        db.db
+            .open_tree(TREE_OUTBOX_DUE)
+            .unwrap()
+            .iter()
+            .keys()
+            .map(|key| decode_u64(&key.unwrap()))
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn offline_messages_wait_for_expiry() {
+        let (wrapper, db) = outbox_instance("due", CustomOutboxConfig::default()).await;
+        let peer = PeerId::random().to_string();
+        libp2p_outbox_send(&wrapper, peer, vec![], 3_600_000, None)
+            .await
+            .ok()
+            .unwrap();
+        let expires_at = outbox_list(&db, None).await.unwrap()[0].expires_at;
+        // the sweep checks the message again when it expires, not at every sweep
+        let rescheduled = tokio::time::timeout(Duration::from_secs(10), async {
+            while due_times(&db) != vec![expires_at] {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        assert!(rescheduled.is_ok());
+        // a lost index is rebuilt when the outbox is attached
+        db.db.open_tree(TREE_OUTBOX_DUE).unwrap().clear().unwrap();
+        let config = CustomOutboxConfig::default();
+        assert!(libp2p_outbox_attach(&wrapper, &db, config).await.is_ok());
+        assert_eq!(due_times(&db).len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expired_messages_fail() {
+        let (wrapper, db) = outbox_instance("expiry", CustomOutboxConfig::default()).await;
+        let mut events = wrapper.subscribe();
+        let expired = libp2p_outbox_send(&wrapper, PeerId::random().to_string(), vec![], 0, None)
+            .await
+            .ok()
+            .unwrap();
+        let kept = libp2p_outbox_send(
+            &wrapper,
+            PeerId::random().to_string(),
+            vec![],
+            3_600_000,
+            None,
+        )
+        .await
+        .ok()
+        .unwrap();
+        let (failed, attempts, error) = failed_event(&mut events).await;
+        assert_eq!((failed, attempts), (expired, 0));
+        assert_eq!(error, "outbox_expired");
+        let remaining: Vec<u64> = outbox_list(&db, None)
+            .await
+            .unwrap()
+            .iter()
+            .map(|m| m.id)
+            .collect();
+        assert_eq!(remaining, vec![kept]);
+    }
+}
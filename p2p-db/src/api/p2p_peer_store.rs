@@ -0,0 +1,362 @@
+use super::{
+    p2p_common::{CustomBehaviourEvent, GenericError},
+    p2p_dht::parse_peer_address,
+    sled::CustomDB,
+    wrapper::Wrapper,
+};
+use flutter_rust_bridge::frb;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{identify, mdns, rendezvous, Multiaddr, PeerId};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//
+// CONSTANTS
+//
+const TREE_PEERS: &str = "__peer_store";
+// older addresses are forgotten first
+const MAX_ADDRESSES: usize = 16;
+// last seen (u64) + successes (u32) + failures (u32)
+const RECORD_HEADER_SIZE: usize = 16;
+
+//
+// STRUCTS
+//
+/// What is known about a peer, times are unix timestamps in milliseconds.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug, Default)]
+pub struct CustomPeerInfo {
+    pub peer_id: String,
+    /// Most recent first.
+    pub addresses: Vec<String>,
+    pub protocols: Vec<String>,
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub last_seen: u64,
+    /// Outgoing connections established.
+    pub dial_successes: u32,
+    /// Outgoing connections that failed.
+    pub dial_failures: u32,
+}
+
+impl CustomPeerInfo {
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(RECORD_HEADER_SIZE);
+        bytes.extend_from_slice(&self.last_seen.to_be_bytes());
+        bytes.extend_from_slice(&self.dial_successes.to_be_bytes());
+        bytes.extend_from_slice(&self.dial_failures.to_be_bytes());
+        encode_strings(&mut bytes, self.agent_version.iter())?;
+        encode_strings(&mut bytes, self.protocol_version.iter())?;
+        encode_strings(&mut bytes, self.addresses.iter())?;
+        encode_strings(&mut bytes, self.protocols.iter())?;
+        Ok(bytes)
+    }
+
+    fn decode(peer_id: String, bytes: &[u8]) -> Result<CustomPeerInfo, String> {
+        if bytes.len() < RECORD_HEADER_SIZE {
+            return Err("peer_store_bad_record".to_string());
+        }
+        let mut last_seen = [0u8; 8];
+        last_seen.copy_from_slice(&bytes[0..8]);
+        let mut dial_successes = [0u8; 4];
+        dial_successes.copy_from_slice(&bytes[8..12]);
+        let mut dial_failures = [0u8; 4];
+        dial_failures.copy_from_slice(&bytes[12..RECORD_HEADER_SIZE]);
+        let mut rest = &bytes[RECORD_HEADER_SIZE..];
+        let agent_version = decode_strings(&mut rest)?.pop();
+        let protocol_version = decode_strings(&mut rest)?.pop();
+        let addresses = decode_strings(&mut rest)?;
+        let protocols = decode_strings(&mut rest)?;
+        Ok(CustomPeerInfo {
+            peer_id,
+            addresses,
+            protocols,
+            agent_version,
+            protocol_version,
+            last_seen: u64::from_be_bytes(last_seen),
+            dial_successes: u32::from_be_bytes(dial_successes),
+            dial_failures: u32::from_be_bytes(dial_failures),
+        })
+    }
+
+    fn add_address(&mut self, address: &Multiaddr) {
+        let address = address.to_string();
+        self.addresses.retain(|f| *f != address);
+        self.addresses.insert(0, address);
+        self.addresses.truncate(MAX_ADDRESSES);
+    }
+}
+
+/// Peer store attached to a swarm, updated by the swarm task.
+pub(crate) struct PeerStore {
+    tree: sled::Tree,
+}
+
+impl PeerStore {
+    /// `seen` updates the last seen time, only connections count as seeing the peer.
+    fn update<F>(&self, peer: &PeerId, seen: bool, update: F) -> Result<(), String>
+    where
+        F: Fn(&mut CustomPeerInfo),
+    {
+        let mut encode_error = None;
+        self.tree
+            .fetch_and_update(peer.to_bytes(), |current| {
+                let mut info = current
+                    .and_then(|bytes| CustomPeerInfo::decode(peer.to_string(), bytes).ok())
+                    .unwrap_or_else(|| CustomPeerInfo {
+                        peer_id: peer.to_string(),
+                        ..CustomPeerInfo::default()
+                    });
+                update(&mut info);
+                if seen {
+                    info.last_seen = now_millis();
+                }
+                match info.encode() {
+                    Ok(bytes) => Some(bytes),
+                    Err(e) => {
+                        // keep the current record
+                        encode_error = Some(e);
+                        current.map(|bytes| bytes.to_vec())
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        encode_error.map_or(Ok(()), Err)
+    }
+
+    fn add_addresses<'a>(
+        &self,
+        peer: &PeerId,
+        addresses: impl Iterator<Item = &'a Multiaddr>,
+    ) -> Result<(), String> {
+        let addresses: Vec<&Multiaddr> = addresses.collect();
+        self.update(peer, false, |info| {
+            for address in addresses.iter().rev() {
+                info.add_address(address);
+            }
+        })
+    }
+
+    /// Known addresses of the peer, most recent first.
+    pub(crate) fn addresses(&self, peer: &PeerId) -> Vec<Multiaddr> {
+        let info_opt = self
+            .tree
+            .get(peer.to_bytes())
+            .ok()
+            .flatten()
+            .and_then(|bytes| CustomPeerInfo::decode(peer.to_string(), &bytes).ok());
+        match info_opt {
+            Some(info) => info
+                .addresses
+                .iter()
+                .filter_map(|f| f.parse::<Multiaddr>().ok())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Record what the swarm learns about peers.
+    pub(crate) fn on_swarm_event(&self, event: &SwarmEvent<CustomBehaviourEvent>) {
+        // a failed write only loses an update, the swarm must keep running
+        let _ = self.record_swarm_event(event);
+    }
+
+    fn record_swarm_event(&self, event: &SwarmEvent<CustomBehaviourEvent>) -> Result<(), String> {
+        match event {
+            SwarmEvent::Behaviour(CustomBehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => self.update(peer_id, false, |peer_info| {
+                for address in info.listen_addrs.iter().rev() {
+                    peer_info.add_address(address);
+                }
+                peer_info.protocols = info.protocols.iter().map(|f| f.to_string()).collect();
+                peer_info.agent_version = Some(info.agent_version.clone());
+                peer_info.protocol_version = Some(info.protocol_version.clone());
+            }),
+            SwarmEvent::Behaviour(CustomBehaviourEvent::RdvClient(
+                rendezvous::client::Event::Discovered { registrations, .. },
+            )) => {
+                for registration in registrations.iter() {
+                    self.add_addresses(
+                        &registration.record.peer_id(),
+                        registration.record.addresses().iter(),
+                    )?;
+                }
+                Ok(())
+            }
+            SwarmEvent::Behaviour(CustomBehaviourEvent::Mdns(mdns::Event::Discovered(list))) => {
+                for (peer, address) in list.iter() {
+                    self.add_addresses(peer, [address].into_iter())?;
+                }
+                Ok(())
+            }
+            SwarmEvent::NewExternalAddrOfPeer { peer_id, address } => {
+                self.add_addresses(peer_id, [address].into_iter())
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => self.update(peer_id, true, |info| {
+                if endpoint.is_dialer() {
+                    info.dial_successes = info.dial_successes.saturating_add(1);
+                }
+            }),
+            SwarmEvent::ConnectionClosed { peer_id, .. } => self.update(peer_id, true, |_| {}),
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                ..
+            } => self.update(peer_id, false, |info| {
+                info.dial_failures = info.dial_failures.saturating_add(1);
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+//
+// FUNCTIONS
+//
+/// Record the peers seen by the instance in the database,
+/// `libp2p_dial_peer` then uses the known addresses.
+pub async fn libp2p_peer_store_attach(
+    wrapper: &Arc<Wrapper>,
+    db: &Arc<CustomDB>,
+) -> Result<(), GenericError> {
+    let tree = open_tree(db).map_err(GenericError::Other)?;
+    wrapper
+        .call(move |state| state.peer_store = Some(PeerStore { tree }))
+        .await
+}
+
+/// Add an address ending with `/p2p/<peer id>` to the store.
+pub async fn peer_store_add_address(db: &Arc<CustomDB>, address: String) -> Result<(), String> {
+    let (peer, multiaddr) = parse_peer_address(&address).map_err(|_| "bad_address".to_string())?;
+    let store = PeerStore {
+        tree: open_tree(db)?,
+    };
+    store.add_addresses(&peer, [&multiaddr].into_iter())
+}
+
+pub async fn peer_store_get(
+    db: &Arc<CustomDB>,
+    peer_id: String,
+) -> Result<Option<CustomPeerInfo>, String> {
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let tree = open_tree(db)?;
+    match tree.get(peer.to_bytes()).map_err(|e| e.to_string())? {
+        Some(bytes) => Ok(Some(CustomPeerInfo::decode(peer_id, &bytes)?)),
+        None => Ok(None),
+    }
+}
+
+/// Every known peer, most recently seen first.
+pub async fn peer_store_list(db: &Arc<CustomDB>) -> Result<Vec<CustomPeerInfo>, String> {
+    let tree = open_tree(db)?;
+    let mut peers = Vec::new();
+    for current in tree.iter() {
+        let (key, value) = current.map_err(|e| e.to_string())?;
+        let peer = PeerId::from_bytes(&key).map_err(|e| e.to_string())?;
+        peers.push(CustomPeerInfo::decode(peer.to_string(), &value)?);
+    }
+    peers.sort_by_key(|f| std::cmp::Reverse(f.last_seen));
+    Ok(peers)
+}
+
+pub async fn peer_store_remove(db: &Arc<CustomDB>, peer_id: String) -> Result<bool, String> {
+    let peer = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    let tree = open_tree(db)?;
+    let removed = tree.remove(peer.to_bytes()).map_err(|e| e.to_string())?;
+    Ok(removed.is_some())
+}
+
+//
+// UTILS
+//
+fn open_tree(db: &Arc<CustomDB>) -> Result<sled::Tree, String> {
+    db.db.open_tree(TREE_PEERS).map_err(|e| e.to_string())
+}
+
+// count (u16) then each string prefixed by its length (u16)
+fn encode_strings<'a>(
+    bytes: &mut Vec<u8>,
+    strings: impl ExactSizeIterator<Item = &'a String>,
+) -> Result<(), String> {
+    bytes.extend_from_slice(&encode_u16(strings.len())?);
+    for string in strings {
+        bytes.extend_from_slice(&encode_u16(string.len())?);
+        bytes.extend_from_slice(string.as_bytes());
+    }
+    Ok(())
+}
+
+fn encode_u16(value: usize) -> Result<[u8; 2], String> {
+    u16::try_from(value)
+        .map(|e| e.to_be_bytes())
+        .map_err(|_| "peer_store_record_too_large".to_string())
+}
+
+fn decode_strings(bytes: &mut &[u8]) -> Result<Vec<String>, String> {
+    let count = decode_u16(bytes)?;
+    let mut strings = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = decode_u16(bytes)?;
+        if bytes.len() < len {
+            return Err("peer_store_bad_record".to_string());
+        }
+        strings.push(String::from_utf8_lossy(&bytes[..len]).to_string());
+        *bytes = &bytes[len..];
+    }
+    Ok(strings)
+}
+
+fn decode_u16(bytes: &mut &[u8]) -> Result<usize, String> {
+    if bytes.len() < 2 {
+        return Err("peer_store_bad_record".to_string());
+    }
+    let value = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+    *bytes = &bytes[2..];
+    Ok(value)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sled::sled_db_open;
+
+    #[tokio::test]
+    async fn discovery_does_not_update_last_seen() {
+        let path = std::env::temp_dir().join(format!("peer_store_seen_{}", std::process::id()));
+        let db = sled_db_open(path.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap();
+        let peer = PeerId::random();
+        let address = format!("/ip4/127.0.0.1/tcp/4001/p2p/{}", peer);
+        peer_store_add_address(&db, address).await.unwrap();
+        let info = peer_store_get(&db, peer.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.addresses, vec!["/ip4/127.0.0.1/tcp/4001".to_string()]);
+        assert_eq!(info.last_seen, 0);
+    }
+
+    #[test]
+    fn oversized_record_is_rejected() {
+        let info = CustomPeerInfo {
+            agent_version: Some("a".repeat(u16::MAX as usize + 1)),
+            ..CustomPeerInfo::default()
+        };
+        assert_eq!(
+            info.encode(),
+            Err("peer_store_record_too_large".to_string())
+        );
+    }
+}
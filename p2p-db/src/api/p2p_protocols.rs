@@ -0,0 +1,329 @@
+use super::p2p_config::Libp2pRequestProtocolConfig;
+use super::sled_blob::{blob_protocol_config, BLOB_PROTOCOL};
+use async_trait::async_trait;
+use flutter_rust_bridge::frb;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::core::{Endpoint, Multiaddr};
+use libp2p::request_response::{
+    self as request_response, Codec, OutboundRequestId, ProtocolSupport, ResponseChannel,
+};
+use libp2p::swarm::handler::multi::MultiHandler;
+use libp2p::swarm::{
+    ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+    THandlerOutEvent, ToSwarm,
+};
+use libp2p::{PeerId, StreamProtocol};
+use std::collections::HashMap;
+use std::io;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+//
+// CODEC
+//
+/// Payloads are cbor encoded like the `cbor` codec of libp2p so that peers using it
+/// (such as the former `/transfer/1.0.0` behaviour) still understand each other,
+/// unlike the libp2p codec the size limits are configurable.
+#[derive(Clone)]
+#[frb(ignore)]
+pub struct CborCodec {
+    max_request_size: u64,
+    max_response_size: u64,
+}
+
+async fn read_limited<T>(io: &mut T, limit: u64) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut data = Vec::new();
+    io.take(limit.saturating_add(1))
+        .read_to_end(&mut data)
+        .await?;
+    if data.len() as u64 > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message exceeds {} bytes", limit),
+        ));
+    }
+    cbor4ii::serde::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_encoded<T>(io: &mut T, message: Vec<u8>) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    let data = cbor4ii::serde::to_vec(Vec::new(), &message)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    io.write_all(&data).await
+}
+
+#[async_trait]
+impl Codec for CborCodec {
+    type Protocol = StreamProtocol;
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_limited(io, self.max_request_size).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_limited(io, self.max_response_size).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_encoded(io, request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_encoded(io, response).await
+    }
+}
+
+//
+// BEHAVIOUR
+//
+type CborBehaviour = request_response::Behaviour<CborCodec>;
+
+/// One request-response behaviour per protocol name, events are tagged with the protocol.
+#[frb(ignore)]
+pub struct RequestProtocols {
+    behaviours: HashMap<String, CborBehaviour>,
+    default_protocol: String,
+}
+
+impl RequestProtocols {
+    /// The first protocol is the default one, the blob protocol is added when it is not listed.
+    pub fn new(mut protocols: Vec<Libp2pRequestProtocolConfig>) -> Result<Self, String> {
+        let default_protocol = protocols
+            .first()
+            .map(|protocol| protocol.name.clone())
+            .ok_or("at least one request-response protocol is required")?;
+        if !protocols
+            .iter()
+            .any(|protocol| protocol.name == BLOB_PROTOCOL)
+        {
+            protocols.push(blob_protocol_config());
+        }
+        let mut behaviours = HashMap::new();
+        for protocol in protocols {
+            let stream_protocol =
+                StreamProtocol::try_from_owned(protocol.name.clone()).map_err(|e| e.to_string())?;
+            let codec = CborCodec {
+                max_request_size: protocol.max_request_size,
+                max_response_size: protocol.max_response_size,
+            };
+            let behaviour = CborBehaviour::with_codec(
+                codec,
+                [(stream_protocol, ProtocolSupport::Full)],
+                request_response::Config::default()
+                    .with_request_timeout(Duration::from_millis(protocol.request_timeout))
+                    .with_max_concurrent_streams(protocol.max_concurrent_streams),
+            );
+            if behaviours
+                .insert(protocol.name.clone(), behaviour)
+                .is_some()
+            {
+                return Err(format!("duplicate protocol name: {}", protocol.name));
+            }
+        }
+        Ok(RequestProtocols {
+            behaviours,
+            default_protocol,
+        })
+    }
+
+    /// Name of the given protocol (the default one when none), none when it is unknown.
+    pub fn protocol_name(&self, protocol: Option<&str>) -> Option<String> {
+        let name = protocol.unwrap_or(&self.default_protocol);
+        self.behaviours.contains_key(name).then(|| name.to_string())
+    }
+
+    /// Send a request using the given protocol (the default one when none).
+    /// Returns the protocol used and the request id, none when the protocol is unknown.
+    pub fn send_request(
+        &mut self,
+        protocol: Option<&str>,
+        peer: &PeerId,
+        request: Vec<u8>,
+    ) -> Option<(String, OutboundRequestId)> {
+        let name = protocol.unwrap_or(&self.default_protocol).to_string();
+        let behaviour = self.behaviours.get_mut(&name)?;
+        let request_id = behaviour.send_request(peer, request);
+        Some((name, request_id))
+    }
+
+    /// The channel holds its own sender, any protocol can answer it.
+    pub fn send_response(
+        &mut self,
+        channel: ResponseChannel<Vec<u8>>,
+        response: Vec<u8>,
+    ) -> Result<(), Vec<u8>> {
+        match self.behaviours.get_mut(&self.default_protocol) {
+            Some(behaviour) => behaviour.send_response(channel, response),
+            None => Err(response),
+        }
+    }
+}
+
+impl NetworkBehaviour for RequestProtocols {
+    type ConnectionHandler = MultiHandler<String, THandler<CborBehaviour>>;
+    type ToSwarm = (String, request_response::Event<Vec<u8>, Vec<u8>>);
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        for behaviour in self.behaviours.values_mut() {
+            behaviour.handle_pending_inbound_connection(connection_id, local_addr, remote_addr)?;
+        }
+        Ok(())
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let mut handlers = Vec::with_capacity(self.behaviours.len());
+        for (name, behaviour) in self.behaviours.iter_mut() {
+            let handler = behaviour.handle_established_inbound_connection(
+                connection_id,
+                peer,
+                local_addr,
+                remote_addr,
+            )?;
+            handlers.push((name.clone(), handler));
+        }
+        MultiHandler::try_from_iter(handlers).map_err(ConnectionDenied::new)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        let mut all_addresses = Vec::new();
+        for behaviour in self.behaviours.values_mut() {
+            all_addresses.extend(behaviour.handle_pending_outbound_connection(
+                connection_id,
+                maybe_peer,
+                addresses,
+                effective_role,
+            )?);
+        }
+        Ok(all_addresses)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let mut handlers = Vec::with_capacity(self.behaviours.len());
+        for (name, behaviour) in self.behaviours.iter_mut() {
+            let handler = behaviour.handle_established_outbound_connection(
+                connection_id,
+                peer,
+                addr,
+                role_override,
+            )?;
+            handlers.push((name.clone(), handler));
+        }
+        MultiHandler::try_from_iter(handlers).map_err(ConnectionDenied::new)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        for behaviour in self.behaviours.values_mut() {
+            behaviour.on_swarm_event(event);
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        (name, event): THandlerOutEvent<Self>,
+    ) {
+        if let Some(behaviour) = self.behaviours.get_mut(&name) {
+            behaviour.on_connection_handler_event(peer_id, connection_id, event);
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        for (name, behaviour) in self.behaviours.iter_mut() {
+            if let Poll::Ready(event) = behaviour.poll(cx) {
+                let handler_name = name.clone();
+                return Poll::Ready(
+                    event
+                        .map_out(|event| (name.clone(), event))
+                        .map_in(|event| (handler_name, event)),
+                );
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+
+    #[tokio::test]
+    async fn cbor_wire_format() {
+        let protocol = StreamProtocol::new("/transfer/1.0.0");
+        let mut codec = CborCodec {
+            max_request_size: 3,
+            max_response_size: 3,
+        };
+        let mut io = Cursor::new(Vec::new());
+        codec
+            .write_request(&protocol, &mut io, vec![1, 2])
+            .await
+            .unwrap();
+        // a cbor array of two integers
+        assert_eq!(io.get_ref(), &vec![0x82, 0x01, 0x02]);
+        io.set_position(0);
+        let request = codec.read_request(&protocol, &mut io).await.unwrap();
+        assert_eq!(request, vec![1, 2]);
+        let mut too_large = Cursor::new(vec![0x83, 0x01, 0x02, 0x03]);
+        assert!(codec
+            .read_response(&protocol, &mut too_large)
+            .await
+            .is_err());
+    }
+}
@@ -1,14 +1,22 @@
-use super::p2p_common::{CustomBehaviour, GenericError};
+use super::p2p_common::GenericError;
+use super::p2p_config::{Libp2pCompression, Libp2pTopicScoreConfig};
 pub use crate::api::wrapper::Wrapper;
 use flutter_rust_bridge::frb;
 pub use libp2p::gossipsub::{
-    self, Behaviour as GossipBehaviour, ConfigBuilder, MessageAcceptance, MessageAuthenticity,
-    MessageId, ValidationMode,
+    self, ConfigBuilder, DataTransform, MessageAcceptance, MessageAuthenticity, MessageId,
+    RawMessage, TopicHash, TopicScoreParams, ValidationMode,
 };
 pub use libp2p::identity::PeerId;
-use libp2p::Swarm;
+use sha2::{Digest, Sha256};
 use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) type GossipBehaviour = gossipsub::Behaviour<PubsubTransform>;
+
+// decompressed payloads can not exceed this ratio of the maximum transmit size
+const MAX_COMPRESSION_RATIO: usize = 32;
 //
 // ENUMS
 //
@@ -32,55 +40,197 @@ impl CustomMessageAcceptance {
     }
 }
 //
+// STRUCTS
+//
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct CustomPubsubPeer {
+    pub peer_id: String,
+    pub topics: Vec<String>,
+}
+
+/// Messages counted since the instance was created.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug, Default)]
+pub struct CustomTopicCounters {
+    pub topic: String,
+    pub published: u64,
+    pub published_bytes: u64,
+    pub received: u64,
+    pub received_bytes: u64,
+}
+
+/// Per topic counters, updated by the swarm task.
+#[derive(Default)]
+#[frb(ignore)]
+#[frb(opaque)]
+pub(crate) struct TopicCounters(HashMap<TopicHash, CustomTopicCounters>);
+
+impl TopicCounters {
+    fn entry(&mut self, topic: &TopicHash) -> &mut CustomTopicCounters {
+        self.0
+            .entry(topic.clone())
+            .or_insert_with(|| CustomTopicCounters {
+                topic: topic.to_string(),
+                ..CustomTopicCounters::default()
+            })
+    }
+
+    pub(crate) fn on_published(&mut self, topic: &TopicHash, len: usize) {
+        let counters = self.entry(topic);
+        counters.published += 1;
+        counters.published_bytes += len as u64;
+    }
+
+    pub(crate) fn on_received(&mut self, topic: &TopicHash, len: usize) {
+        let counters = self.entry(topic);
+        counters.received += 1;
+        counters.received_bytes += len as u64;
+    }
+}
+
+/// Compress the payloads, they are published compressed and delivered decompressed.
+#[derive(Clone)]
+#[frb(ignore)]
+pub struct PubsubTransform {
+    compression: Libp2pCompression,
+    max_size: usize,
+}
+
+impl PubsubTransform {
+    pub(crate) fn new(compression: Libp2pCompression, max_transmit_size: usize) -> Self {
+        PubsubTransform {
+            compression,
+            max_size: max_transmit_size.saturating_mul(MAX_COMPRESSION_RATIO),
+        }
+    }
+}
+
+impl DataTransform for PubsubTransform {
+    fn inbound_transform(
+        &self,
+        raw_message: RawMessage,
+    ) -> Result<gossipsub::Message, std::io::Error> {
+        let data = match self.compression {
+            Libp2pCompression::None => raw_message.data,
+            Libp2pCompression::Zstd => zstd::block::decompress(&raw_message.data, self.max_size)?,
+        };
+        Ok(gossipsub::Message {
+            source: raw_message.source,
+            data,
+            sequence_number: raw_message.sequence_number,
+            topic: raw_message.topic,
+        })
+    }
+
+    fn outbound_transform(
+        &self,
+        _topic: &TopicHash,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        match self.compression {
+            Libp2pCompression::None => Ok(data),
+            // receivers would fail to decompress larger payloads
+            Libp2pCompression::Zstd if data.len() > self.max_size => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "pubsub_message_too_large",
+            )),
+            // level 0 is the zstd default level
+            Libp2pCompression::Zstd => zstd::block::compress(&data, 0),
+        }
+    }
+}
+
+/// Message id computed from the topic and the decompressed payload.
+pub(crate) fn content_message_id(message: &gossipsub::Message) -> MessageId {
+    let mut hasher = Sha256::new();
+    hasher.update(message.topic.as_str().as_bytes());
+    hasher.update(&message.data);
+    MessageId::new(&hasher.finalize())
+}
+
+pub(crate) fn topic_score_params(config: &Libp2pTopicScoreConfig) -> TopicScoreParams {
+    TopicScoreParams {
+        topic_weight: config.topic_weight,
+        time_in_mesh_weight: config.time_in_mesh_weight,
+        time_in_mesh_quantum: Duration::from_millis(config.time_in_mesh_quantum),
+        time_in_mesh_cap: config.time_in_mesh_cap,
+        first_message_deliveries_weight: config.first_message_deliveries_weight,
+        first_message_deliveries_decay: config.first_message_deliveries_decay,
+        first_message_deliveries_cap: config.first_message_deliveries_cap,
+        mesh_message_deliveries_weight: config.mesh_message_deliveries_weight,
+        mesh_message_deliveries_decay: config.mesh_message_deliveries_decay,
+        mesh_message_deliveries_cap: config.mesh_message_deliveries_cap,
+        mesh_message_deliveries_threshold: config.mesh_message_deliveries_threshold,
+        mesh_message_deliveries_window: Duration::from_millis(
+            config.mesh_message_deliveries_window,
+        ),
+        mesh_message_deliveries_activation: Duration::from_millis(
+            config.mesh_message_deliveries_activation,
+        ),
+        mesh_failure_penalty_weight: config.mesh_failure_penalty_weight,
+        mesh_failure_penalty_decay: config.mesh_failure_penalty_decay,
+        invalid_message_deliveries_weight: config.invalid_message_deliveries_weight,
+        invalid_message_deliveries_decay: config.invalid_message_deliveries_decay,
+    }
+}
+//
 // FUNCTIONS
 //
 pub async fn libp2p_unsubscribe(
     wrapper: &Arc<Wrapper>,
     topic: String,
 ) -> Result<bool, GenericError> {
-    let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
     let into_topic = gossipsub::IdentTopic::new(topic);
-    let res = swarm
-        .ok_or(GenericError::InstanceNotFound)?
-        .behaviour_mut()
-        .pubsub
-        .unsubscribe(into_topic.borrow())
+    let res = wrapper
+        .with_swarm(move |swarm| swarm.behaviour_mut().pubsub.unsubscribe(&into_topic))
+        .await?
         .map_err(|e| GenericError::Other(e.to_string()));
     res
 }
 
+/// The score parameters apply to `topic` whatever their own topic, peer scoring must be enabled.
 pub async fn libp2p_subscribe(
     wrapper: &Arc<Wrapper>,
     topic: String,
+    score: Option<Libp2pTopicScoreConfig>,
 ) -> Result<Option<String>, GenericError> {
-    let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
     let into_topic = gossipsub::IdentTopic::new(topic);
-    let res = swarm
-        .ok_or(GenericError::InstanceNotFound)?
-        .behaviour_mut()
-        .pubsub
-        .subscribe(into_topic.borrow())
-        .map_err(|e| GenericError::Other(e.to_string()));
-    res.map(|f| {
-        if f {
-            Some(into_topic.hash().to_string())
-        } else {
-            None
-        }
-    })
+    let hash = into_topic.hash();
+    let res = wrapper
+        .with_swarm(move |swarm| {
+            let pubsub = &mut swarm.behaviour_mut().pubsub;
+            if let Some(score) = score {
+                pubsub
+                    .set_topic_params(into_topic.clone(), topic_score_params(&score))
+                    .map_err(|e| GenericError::Other(e.to_string()))?;
+            }
+            pubsub
+                .subscribe(&into_topic)
+                .map_err(|e| GenericError::Other(e.to_string()))
+        })
+        .await?;
+    res.map(|f| if f { Some(hash.to_string()) } else { None })
 }
 pub async fn libp2p_publish(
     wrapper: &Arc<Wrapper>,
     topic: String,
     data: Vec<u8>,
 ) -> Result<MessageId, GenericError> {
-    let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
     let into_topic = gossipsub::IdentTopic::new(topic);
-    let res = swarm
-        .ok_or(GenericError::InstanceNotFound)?
-        .behaviour_mut()
-        .pubsub
-        .publish(into_topic, data)
+    let res = wrapper
+        .call(move |state| {
+            let hash = into_topic.hash();
+            let len = data.len();
+            let res = state.swarm.behaviour_mut().pubsub.publish(into_topic, data);
+            if res.is_ok() {
+                state.topic_counters.on_published(&hash, len);
+            }
+            res
+        })
+        .await?
         .map_err(|e| GenericError::Other(e.to_string()));
     res
 }
@@ -98,14 +248,12 @@ pub async fn libp2p_pubsub_add_peer(
     let peer_unsafe = peer.parse::<PeerId>();
     match peer_unsafe {
         Ok(peer_id) => {
-            let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-            if let Some(swarm) = swarm_opt {
-                let behaviour = swarm.behaviour_mut();
-                let res = behaviour.pubsub.add_explicit_peer(peer_id.borrow());
-                Ok(res)
-            } else {
-                Err(GenericError::InstanceNotFound)
-            }
+            wrapper
+                .with_swarm(move |swarm| {
+                    let behaviour = swarm.behaviour_mut();
+                    behaviour.pubsub.add_explicit_peer(peer_id.borrow())
+                })
+                .await
         }
         Err(e) => Err(GenericError::Other(e.to_string())),
     }
@@ -118,14 +266,88 @@ pub async fn libp2p_pubsub_remove_peer(
     let peer_unsafe = peer.parse::<PeerId>();
     match peer_unsafe {
         Ok(peer_id) => {
-            let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-            if let Some(swarm) = swarm_opt {
-                let behaviour = swarm.behaviour_mut();
-                let res = behaviour.pubsub.remove_explicit_peer(peer_id.borrow());
-                Ok(res)
-            } else {
-                Err(GenericError::InstanceNotFound)
-            }
+            wrapper
+                .with_swarm(move |swarm| {
+                    let behaviour = swarm.behaviour_mut();
+                    behaviour.pubsub.remove_explicit_peer(peer_id.borrow())
+                })
+                .await
+        }
+        Err(e) => Err(GenericError::Other(e.to_string())),
+    }
+}
+
+/// Topics the instance is subscribed to.
+pub async fn libp2p_pubsub_topics(wrapper: &Arc<Wrapper>) -> Result<Vec<String>, GenericError> {
+    wrapper
+        .with_swarm(|swarm| {
+            swarm
+                .behaviour()
+                .pubsub
+                .topics()
+                .map(|f| f.to_string())
+                .collect()
+        })
+        .await
+}
+
+/// Peers of the mesh of a subscribed topic.
+pub async fn libp2p_pubsub_mesh_peers(
+    wrapper: &Arc<Wrapper>,
+    topic: String,
+) -> Result<Vec<String>, GenericError> {
+    let hash = gossipsub::IdentTopic::new(topic).hash();
+    wrapper
+        .with_swarm(move |swarm| {
+            swarm
+                .behaviour()
+                .pubsub
+                .mesh_peers(&hash)
+                .map(|f| f.to_string())
+                .collect()
+        })
+        .await
+}
+
+/// Every gossipsub peer with the topics it is subscribed to.
+pub async fn libp2p_pubsub_all_peers(
+    wrapper: &Arc<Wrapper>,
+) -> Result<Vec<CustomPubsubPeer>, GenericError> {
+    wrapper
+        .with_swarm(|swarm| {
+            swarm
+                .behaviour()
+                .pubsub
+                .all_peers()
+                .map(|(peer, topics)| CustomPubsubPeer {
+                    peer_id: peer.to_string(),
+                    topics: topics.iter().map(|f| f.to_string()).collect(),
+                })
+                .collect()
+        })
+        .await
+}
+
+/// Counters of every topic with published or received messages.
+pub async fn libp2p_pubsub_topic_counters(
+    wrapper: &Arc<Wrapper>,
+) -> Result<Vec<CustomTopicCounters>, GenericError> {
+    wrapper
+        .call(|state| state.topic_counters.0.values().cloned().collect())
+        .await
+}
+
+/// Score of the peer, none when peer scoring is disabled.
+pub async fn libp2p_pubsub_peer_score(
+    wrapper: &Arc<Wrapper>,
+    peer: String,
+) -> Result<Option<f64>, GenericError> {
+    let peer_unsafe = peer.parse::<PeerId>();
+    match peer_unsafe {
+        Ok(peer_id) => {
+            wrapper
+                .with_swarm(move |swarm| swarm.behaviour().pubsub.peer_score(&peer_id))
+                .await
         }
         Err(e) => Err(GenericError::Other(e.to_string())),
     }
@@ -140,22 +362,49 @@ pub async fn libp2p_pubsub_validate(
     let peer_unsafe = propagation_source.parse::<PeerId>();
     match peer_unsafe {
         Ok(propagation_source_safe) => {
-            let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-            if let Some(swarm) = swarm_opt {
-                let behaviour = swarm.behaviour_mut();
-                let msg_id_safe = MessageId::new(msg_id.borrow());
-                let acceptance_safe = acceptance.to_acceptance();
-                let res = behaviour.pubsub.report_message_validation_result(
-                    msg_id_safe.borrow(),
-                    propagation_source_safe.borrow(),
-                    acceptance_safe,
-                );
-                let res_safe = res.map_err(|e| GenericError::Other(e.to_string()));
-                res_safe
-            } else {
-                Err(GenericError::InstanceNotFound)
-            }
+            let msg_id_safe = MessageId::new(msg_id.borrow());
+            let acceptance_safe = acceptance.to_acceptance();
+            let res = wrapper
+                .call(move |state| {
+                    state.validation.validated(&msg_id_safe);
+                    let behaviour = state.swarm.behaviour_mut();
+                    behaviour.pubsub.report_message_validation_result(
+                        msg_id_safe.borrow(),
+                        propagation_source_safe.borrow(),
+                        acceptance_safe,
+                    )
+                })
+                .await?;
+            res.map_err(|e| GenericError::Other(e.to_string()))
         }
         Err(e) => Err(GenericError::Other(e.to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompressed_size_is_capped() {
+        let transform = PubsubTransform::new(Libp2pCompression::Zstd, 4);
+        let topic = gossipsub::IdentTopic::new("topic").hash();
+        let data = vec![0u8; 4 * MAX_COMPRESSION_RATIO];
+        let compressed = transform.outbound_transform(&topic, data.clone()).unwrap();
+        let message = transform
+            .inbound_transform(RawMessage {
+                source: None,
+                data: compressed,
+                sequence_number: None,
+                signature: None,
+                key: None,
+                topic: topic.clone(),
+                validated: false,
+            })
+            .unwrap();
+        assert_eq!(message.data, data);
+        assert!(transform
+            .outbound_transform(&topic, vec![0u8; data.len() + 1])
+            .is_err());
+    }
+}
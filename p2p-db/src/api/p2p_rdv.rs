@@ -1,68 +1,551 @@
-use std::sync::Arc;
-use flutter_rust_bridge::frb;
-use libp2p::{rendezvous::Namespace, Swarm};
 use super::{
     p2p_common::{CustomBehaviour, GenericError},
     wrapper::Wrapper,
 };
+use flutter_rust_bridge::frb;
 pub use libp2p::rendezvous::Cookie;
+use libp2p::rendezvous::{client, server, ErrorCode, Namespace};
+use libp2p::{PeerId, Swarm};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+//
+// CONSTANTS
+//
+/// Interval between two checks of the registrations to renew and the discoveries to run.
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// failed registrations are sent again after this delay
+const RETRY_DELAY: Duration = Duration::from_secs(30);
 
+//
+// STRUCTS
+//
 #[frb(external)]
 #[frb(opaque)]
 impl Cookie {
     #[frb(sync)]
     pub fn clone(&self) -> Self {}
 }
+
+/// A registration served by the rendezvous server, `expires_at` is a unix timestamp in milliseconds.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct CustomRdvRegistration {
+    pub namespace: String,
+    pub peer_id: String,
+    pub addresses: Vec<String>,
+    /// In seconds.
+    pub ttl: u64,
+    pub expires_at: u64,
+}
+
+struct AutoRegistration {
+    ttl: Option<u64>,
+    // while the registration is sent, the time it is sent again when no answer comes
+    renew_at: Option<Instant>,
+}
+
+struct AutoDiscovery {
+    limit: Option<u64>,
+    interval: Duration,
+    // only the registrations added since the previous discovery are returned
+    cookie: Option<Cookie>,
+    // none while the discovery is sent
+    next_at: Option<Instant>,
+}
+
+/// Registrations renewed and discoveries run by the swarm task,
+/// and registrations served when the rendezvous server is enabled.
+#[derive(Default)]
+#[frb(ignore)]
+#[frb(opaque)]
+pub(crate) struct Rendezvous {
+    registrations: HashMap<(PeerId, Namespace), AutoRegistration>,
+    discoveries: HashMap<(PeerId, Option<Namespace>), AutoDiscovery>,
+    served: HashMap<(PeerId, Namespace), CustomRdvRegistration>,
+}
+
+impl Rendezvous {
+    /// Send the registrations and discoveries that are due.
+    pub(crate) fn poll(&mut self, swarm: &mut Swarm<CustomBehaviour>) {
+        let Some(rdv_client) = swarm.behaviour_mut().rdv_client.as_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        for ((node, namespace), registration) in self.registrations.iter_mut() {
+            if registration.renew_at.is_some_and(|f| f <= now) {
+                registration.renew_at =
+                    match rdv_client.register(namespace.clone(), *node, registration.ttl) {
+                        Ok(_) => now.checked_add(RETRY_DELAY),
+                        // no external address yet
+                        Err(_) => now.checked_add(RETRY_DELAY),
+                    };
+            }
+        }
+        for ((node, namespace), discovery) in self.discoveries.iter_mut() {
+            if discovery.next_at.is_some_and(|f| f <= now) {
+                rdv_client.discover(
+                    namespace.clone(),
+                    discovery.cookie.clone(),
+                    discovery.limit,
+                    *node,
+                );
+                discovery.next_at = None;
+            }
+        }
+    }
+
+    pub(crate) fn on_client_event(
+        &mut self,
+        swarm: &mut Swarm<CustomBehaviour>,
+        event: &client::Event,
+    ) {
+        let now = Instant::now();
+        match event {
+            client::Event::Registered {
+                rendezvous_node,
+                ttl,
+                namespace,
+            } => {
+                match self
+                    .registrations
+                    .get_mut(&(*rendezvous_node, namespace.clone()))
+                {
+                    Some(registration) => {
+                        // renewed when 80% of the ttl elapsed, never when it overflows an instant
+                        let renew_in = Duration::from_millis(ttl.saturating_mul(800));
+                        registration.renew_at = now.checked_add(renew_in);
+                    }
+                    // unregistered while the renewal was sent
+                    None => {
+                        if let Some(rdv_client) = swarm.behaviour_mut().rdv_client.as_mut() {
+                            rdv_client.unregister(namespace.clone(), *rendezvous_node);
+                        }
+                    }
+                }
+            }
+            client::Event::RegisterFailed {
+                rendezvous_node,
+                namespace,
+                ..
+            } => {
+                if let Some(registration) = self
+                    .registrations
+                    .get_mut(&(*rendezvous_node, namespace.clone()))
+                {
+                    registration.renew_at = Some(now + RETRY_DELAY);
+                }
+            }
+            client::Event::Discovered {
+                rendezvous_node,
+                cookie,
+                ..
+            } => {
+                if let Some(discovery) = self
+                    .discoveries
+                    .get_mut(&(*rendezvous_node, cookie.namespace().cloned()))
+                {
+                    discovery.cookie = Some(cookie.clone());
+                    // an interval overflowing an instant stops the discovery
+                    discovery.next_at = now.checked_add(discovery.interval);
+                }
+            }
+            client::Event::DiscoverFailed {
+                rendezvous_node,
+                namespace,
+                error,
+            } => {
+                if let Some(discovery) = self
+                    .discoveries
+                    .get_mut(&(*rendezvous_node, namespace.clone()))
+                {
+                    if *error == ErrorCode::InvalidCookie {
+                        discovery.cookie = None;
+                    }
+                    discovery.next_at = now.checked_add(discovery.interval);
+                }
+            }
+            client::Event::Expired { .. } => {}
+        }
+    }
+
+    pub(crate) fn on_server_event(&mut self, event: &server::Event) {
+        match event {
+            server::Event::PeerRegistered { peer, registration } => {
+                self.served.insert(
+                    (*peer, registration.namespace.clone()),
+                    CustomRdvRegistration {
+                        namespace: registration.namespace.to_string(),
+                        peer_id: peer.to_string(),
+                        addresses: registration
+                            .record
+                            .addresses()
+                            .iter()
+                            .map(|f| f.to_string())
+                            .collect(),
+                        ttl: registration.ttl,
+                        expires_at: now_millis()
+                            .saturating_add(registration.ttl.saturating_mul(1000)),
+                    },
+                );
+            }
+            server::Event::PeerUnregistered { peer, namespace } => {
+                self.served.remove(&(*peer, namespace.clone()));
+            }
+            server::Event::RegistrationExpired(registration) => {
+                self.served.remove(&(
+                    registration.record.peer_id(),
+                    registration.namespace.clone(),
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+//
+// FUNCTIONS
+//
 #[frb(sync)]
 pub fn libp2p_rdv_cookie() -> Cookie {
     Cookie::for_all_namespaces()
 }
 
+/// Discover the peers registered in the namespace, in every namespace when none.
 pub async fn libp2p_rdv_discover(
     wrapper: &Arc<Wrapper>,
     rdv_peerid: String,
+    namespace: Option<String>,
     limit: Option<u64>,
     cookie: Option<Cookie>,
 ) -> Result<bool, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        if let Ok(multiaddr) = rdv_peerid.parse() {
-            let behaviour = swarm.behaviour_mut();
-            behaviour.rdv_client.discover(None, cookie, limit, multiaddr);
-            return Ok(true);
-        } else {
-            Err(GenericError::BadAddress)
-        }
+    if let Ok(multiaddr) = rdv_peerid.parse() {
+        let namespace = namespace.map(parse_namespace).transpose()?;
+        wrapper
+            .with_swarm(move |swarm| {
+                let behaviour = swarm.behaviour_mut();
+                match behaviour.rdv_client.as_mut() {
+                    Some(rdv_client) => {
+                        rdv_client.discover(namespace, cookie, limit, multiaddr);
+                        Ok(true)
+                    }
+                    None => Err(GenericError::BehaviourDisabled),
+                }
+            })
+            .await?
     } else {
-        Err(GenericError::InstanceNotFound)
+        Err(GenericError::BadAddress)
     }
 }
 
+/// Register in the namespace, the registration is renewed before its TTL (in seconds) expires
+/// until `libp2p_rdv_unregister`.
 pub async fn libp2p_rdv_register(
     wrapper: &Arc<Wrapper>,
     rdv_peerid: String,
     namespace: String,
     ttl: Option<u64>,
 ) -> Result<bool, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        if let Ok(multiaddr) = rdv_peerid.parse() {
-            let ns_unsafe = Namespace::new(namespace);
-            match ns_unsafe {
-                Ok(ns) => {
-                    let behaviour = swarm.behaviour_mut();
-                    let res = behaviour.rdv_client.register(ns, multiaddr, ttl);
-                    let safe = res
-                        .map(|_| true)
-                        .map_err(|e| GenericError::Other(e.to_string()));
-                    safe
+    if let Ok(multiaddr) = rdv_peerid.parse() {
+        let ns = parse_namespace(namespace)?;
+        wrapper
+            .call(move |state| {
+                let behaviour = state.swarm.behaviour_mut();
+                match behaviour.rdv_client.as_mut() {
+                    Some(rdv_client) => {
+                        rdv_client
+                            .register(ns.clone(), multiaddr, ttl)
+                            .map_err(|e| GenericError::Other(e.to_string()))?;
+                        state.rendezvous.registrations.insert(
+                            (multiaddr, ns),
+                            AutoRegistration {
+                                ttl,
+                                renew_at: Instant::now().checked_add(RETRY_DELAY),
+                            },
+                        );
+                        Ok(true)
+                    }
+                    None => Err(GenericError::BehaviourDisabled),
                 }
-                Err(err) => return Err(GenericError::Other(err.to_string())),
-            }
-        } else {
-            Err(GenericError::BadAddress)
-        }
+            })
+            .await?
     } else {
-        Err(GenericError::InstanceNotFound)
+        Err(GenericError::BadAddress)
+    }
+}
+
+/// Remove the registration and stop renewing it, false when it was not registered.
+pub async fn libp2p_rdv_unregister(
+    wrapper: &Arc<Wrapper>,
+    rdv_peerid: String,
+    namespace: String,
+) -> Result<bool, GenericError> {
+    let peer = rdv_peerid
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    let ns = parse_namespace(namespace)?;
+    wrapper
+        .call(
+            move |state| match state.swarm.behaviour_mut().rdv_client.as_mut() {
+                Some(rdv_client) => {
+                    rdv_client.unregister(ns.clone(), peer);
+                    Ok(state.rendezvous.registrations.remove(&(peer, ns)).is_some())
+                }
+                None => Err(GenericError::BehaviourDisabled),
+            },
+        )
+        .await?
+}
+
+/// Discover the namespace (every namespace when none) now and then every `interval`
+/// milliseconds, each discovery only returns the peers registered since the previous one.
+/// Replaces the previous discovery of the namespace.
+pub async fn libp2p_rdv_start_discovery(
+    wrapper: &Arc<Wrapper>,
+    rdv_peerid: String,
+    namespace: Option<String>,
+    interval: u64,
+    limit: Option<u64>,
+) -> Result<(), GenericError> {
+    let peer = rdv_peerid
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    let ns = namespace.map(parse_namespace).transpose()?;
+    wrapper
+        .call(move |state| {
+            if !state.swarm.behaviour().rdv_client.is_enabled() {
+                return Err(GenericError::BehaviourDisabled);
+            }
+            let discovery = AutoDiscovery {
+                limit,
+                interval: Duration::from_millis(interval),
+                cookie: None,
+                next_at: Some(Instant::now()),
+            };
+            state.rendezvous.discoveries.insert((peer, ns), discovery);
+            Ok(())
+        })
+        .await?
+}
+
+/// Stop the discovery started by `libp2p_rdv_start_discovery`, false when there was none.
+pub async fn libp2p_rdv_stop_discovery(
+    wrapper: &Arc<Wrapper>,
+    rdv_peerid: String,
+    namespace: Option<String>,
+) -> Result<bool, GenericError> {
+    let peer = rdv_peerid
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    let ns = namespace.map(parse_namespace).transpose()?;
+    wrapper
+        .call(move |state| state.rendezvous.discoveries.remove(&(peer, ns)).is_some())
+        .await
+}
+
+/// Registrations served by the rendezvous server of this node, in every namespace when none.
+pub async fn libp2p_rdv_registrations(
+    wrapper: &Arc<Wrapper>,
+    namespace: Option<String>,
+) -> Result<Vec<CustomRdvRegistration>, GenericError> {
+    wrapper
+        .call(move |state| {
+            if !state.swarm.behaviour().rdv_server.is_enabled() {
+                return Err(GenericError::BehaviourDisabled);
+            }
+            let now = now_millis();
+            Ok(state
+                .rendezvous
+                .served
+                .values()
+                .filter(|f| f.expires_at > now)
+                .filter(|f| namespace.as_ref().is_none_or(|ns| *ns == f.namespace))
+                .cloned()
+                .collect())
+        })
+        .await?
+}
+
+//
+// UTILS
+//
+fn parse_namespace(namespace: String) -> Result<Namespace, GenericError> {
+    Namespace::new(namespace).map_err(|e| GenericError::Other(e.to_string()))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{test_config, test_instance};
+    use libp2p::core::PeerRecord;
+    use libp2p::identity::Keypair;
+    use libp2p::rendezvous::Registration;
+
+    async fn rdv_instance() -> Arc<Wrapper> {
+        let mut config = test_config();
+        config.behaviours.rendezvous_client = true;
+        config.behaviours.rendezvous_server = true;
+        test_instance(config).await
+    }
+
+    fn namespace(name: &str) -> Namespace {
+        Namespace::new(name.to_string()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn registrations_renewed_before_expiry() {
+        let wrapper = rdv_instance().await;
+        let node = PeerId::random();
+        let ns = namespace("renew");
+        let renew_at = wrapper
+            .call(move |state| {
+                state
+                    .swarm
+                    .add_external_address("/ip4/127.0.0.1/tcp/4001".parse().unwrap());
+                let registration = AutoRegistration {
+                    ttl: Some(100),
+                    renew_at: Some(Instant::now()),
+                };
+                let key = (node, ns.clone());
+                state
+                    .rendezvous
+                    .registrations
+                    .insert(key.clone(), registration);
+                // a registration sent without answer is sent again
+                let before = Instant::now();
+                state.rendezvous.poll(&mut state.swarm);
+                let retry_at = state.rendezvous.registrations[&key].renew_at.unwrap();
+                assert!(retry_at >= before + RETRY_DELAY);
+                let event = client::Event::Registered {
+                    rendezvous_node: node,
+                    ttl: 100,
+                    namespace: ns,
+                };
+                let before = Instant::now();
+                state.rendezvous.on_client_event(&mut state.swarm, &event);
+                let renew_at = state.rendezvous.registrations[&key].renew_at.unwrap();
+                (renew_at - before).as_secs()
+            })
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(renew_at, 80);
+    }
+
+    #[tokio::test]
+    async fn unregistered_during_renewal() {
+        let wrapper = rdv_instance().await;
+        let node = PeerId::random();
+        let ns = namespace("gone");
+        let unregistered =
+            libp2p_rdv_unregister(&wrapper, node.to_string(), "gone".to_string()).await;
+        assert_eq!(unregistered.ok(), Some(false));
+        // the answer of a renewal sent before the unregistration does not register again
+        let registrations = wrapper
+            .call(move |state| {
+                let event = client::Event::Registered {
+                    rendezvous_node: node,
+                    ttl: 100,
+                    namespace: ns,
+                };
+                state.rendezvous.on_client_event(&mut state.swarm, &event);
+                state.rendezvous.registrations.len()
+            })
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(registrations, 0);
+    }
+
+    #[tokio::test]
+    async fn discovery_cookie_kept_until_invalid() {
+        let wrapper = rdv_instance().await;
+        let node = PeerId::random();
+        let started = libp2p_rdv_start_discovery(
+            &wrapper,
+            node.to_string(),
+            Some("cookie".to_string()),
+            60_000,
+            None,
+        )
+        .await;
+        assert!(started.is_ok());
+        let cookies = wrapper
+            .call(move |state| {
+                let ns = namespace("cookie");
+                let key = (node, Some(ns.clone()));
+                let mut cookies = vec![];
+                let discovered = client::Event::Discovered {
+                    rendezvous_node: node,
+                    registrations: vec![],
+                    cookie: Cookie::for_namespace(ns.clone()),
+                };
+                state
+                    .rendezvous
+                    .on_client_event(&mut state.swarm, &discovered);
+                let discovery = &state.rendezvous.discoveries[&key];
+                assert!(discovery.next_at.unwrap() > Instant::now() + Duration::from_secs(59));
+                cookies.push(discovery.cookie.is_some());
+                for error in [ErrorCode::Unavailable, ErrorCode::InvalidCookie] {
+                    let failed = client::Event::DiscoverFailed {
+                        rendezvous_node: node,
+                        namespace: Some(ns.clone()),
+                        error,
+                    };
+                    state.rendezvous.on_client_event(&mut state.swarm, &failed);
+                    cookies.push(state.rendezvous.discoveries[&key].cookie.is_some());
+                }
+                cookies
+            })
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(cookies, vec![true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn expired_served_registrations_hidden() {
+        let wrapper = rdv_instance().await;
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        wrapper
+            .call(move |state| {
+                for (name, ttl) in [("expired", 0), ("active", 3600), ("other", 3600)] {
+                    let record = PeerRecord::new(&keypair, vec![]).unwrap();
+                    let event = server::Event::PeerRegistered {
+                        peer,
+                        registration: Registration {
+                            namespace: namespace(name),
+                            record,
+                            ttl,
+                        },
+                    };
+                    state.rendezvous.on_server_event(&event);
+                }
+            })
+            .await
+            .ok()
+            .unwrap();
+        let names = |registrations: Vec<CustomRdvRegistration>| {
+            let mut names: Vec<String> = registrations.into_iter().map(|f| f.namespace).collect();
+            names.sort();
+            names
+        };
+        let all = libp2p_rdv_registrations(&wrapper, None).await.ok().unwrap();
+        assert_eq!(names(all), vec!["active", "other"]);
+        let active = libp2p_rdv_registrations(&wrapper, Some("active".to_string()))
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(names(active), vec!["active"]);
     }
 }
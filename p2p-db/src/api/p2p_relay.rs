@@ -0,0 +1,303 @@
+use super::{
+    p2p_common::{CustomSwarmEvent, GenericError},
+    p2p_dht::parse_peer_address,
+    wrapper::{SwarmState, Wrapper},
+};
+use flutter_rust_bridge::frb;
+use libp2p::core::transport::ListenerId;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{dial_opts::DialOpts, ConnectionId};
+use libp2p::{dcutr, relay, PeerId};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+//
+// STRUCTS
+//
+/// Relay of the `/p2p-circuit` listeners opened by `libp2p_relay_reserve`.
+pub(crate) type RelayReservations = HashMap<ListenerId, PeerId>;
+
+/// Load of the relay server since the instance was created.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug, Default)]
+pub struct CustomRelayServerStats {
+    pub reservations_accepted: u64,
+    pub reservations_denied: u64,
+    pub reservations_timed_out: u64,
+    pub circuits_accepted: u64,
+    pub circuits_denied: u64,
+    pub circuits_closed: u64,
+    /// Circuits currently relayed.
+    pub active_circuits: u64,
+}
+
+/// Accounting of the relay server, updated by the swarm task.
+#[derive(Default)]
+#[frb(ignore)]
+#[frb(opaque)]
+pub(crate) struct RelayServerStats {
+    stats: CustomRelayServerStats,
+    // opening time of the active circuits by source and destination, never empty
+    circuits: HashMap<(PeerId, PeerId), Vec<Instant>>,
+}
+
+impl RelayServerStats {
+    /// Count the event, none for the events that are not reported.
+    #[allow(deprecated)]
+    pub(crate) fn on_event(&mut self, event: relay::Event) -> Option<CustomSwarmEvent> {
+        let stats = &mut self.stats;
+        match event {
+            relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed,
+            } => {
+                stats.reservations_accepted += 1;
+                Some(CustomSwarmEvent::RelayServerReservationAccepted {
+                    src_peer_id: src_peer_id.to_string(),
+                    renewed,
+                })
+            }
+            relay::Event::ReservationReqDenied { src_peer_id } => {
+                stats.reservations_denied += 1;
+                Some(CustomSwarmEvent::RelayServerReservationDenied {
+                    src_peer_id: src_peer_id.to_string(),
+                })
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                stats.reservations_timed_out += 1;
+                Some(CustomSwarmEvent::RelayServerReservationTimedOut {
+                    src_peer_id: src_peer_id.to_string(),
+                })
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                stats.circuits_accepted += 1;
+                stats.active_circuits += 1;
+                self.circuits
+                    .entry((src_peer_id, dst_peer_id))
+                    .or_default()
+                    .push(Instant::now());
+                Some(CustomSwarmEvent::RelayServerCircuitAccepted {
+                    src_peer_id: src_peer_id.to_string(),
+                    dst_peer_id: dst_peer_id.to_string(),
+                })
+            }
+            relay::Event::CircuitReqDenied {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                stats.circuits_denied += 1;
+                Some(CustomSwarmEvent::RelayServerCircuitDenied {
+                    src_peer_id: src_peer_id.to_string(),
+                    dst_peer_id: dst_peer_id.to_string(),
+                })
+            }
+            relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            } => {
+                stats.circuits_closed += 1;
+                stats.active_circuits = stats.active_circuits.saturating_sub(1);
+                let key = (src_peer_id, dst_peer_id);
+                // circuits of the same peers are closed in the order they were opened
+                let opened_opt = self.circuits.get_mut(&key).map(|opened| opened.remove(0));
+                if self.circuits.get(&key).is_some_and(|f| f.is_empty()) {
+                    self.circuits.remove(&key);
+                }
+                Some(CustomSwarmEvent::RelayServerCircuitClosed {
+                    src_peer_id: src_peer_id.to_string(),
+                    dst_peer_id: dst_peer_id.to_string(),
+                    duration: opened_opt
+                        .map(|f| f.elapsed().as_millis() as u64)
+                        .unwrap_or(0),
+                    error: error.map(|f| f.to_string()),
+                })
+            }
+            // failures to answer a request, the request is counted by the accepted or denied event
+            relay::Event::ReservationReqAcceptFailed { .. }
+            | relay::Event::ReservationReqDenyFailed { .. }
+            | relay::Event::CircuitReqDenyFailed { .. }
+            | relay::Event::CircuitReqOutboundConnectFailed { .. }
+            | relay::Event::CircuitReqAcceptFailed { .. } => None,
+        }
+    }
+}
+
+//
+// FUNCTIONS
+//
+/// Reserve a slot on the relay (a multiaddress ending with `/p2p/<relay id>`) and listen on it,
+/// peers can then reach us through the relay. `libp2p_unlisten` releases the reservation.
+pub async fn libp2p_relay_reserve(
+    wrapper: &Arc<Wrapper>,
+    relay_addr: String,
+) -> Result<ListenerId, GenericError> {
+    let (relay_peer, address) = parse_peer_address(&relay_addr)?;
+    let circuit = address
+        .with(Protocol::P2p(relay_peer))
+        .with(Protocol::P2pCircuit);
+    wrapper
+        .call(move |state| {
+            let listener_id = state
+                .swarm
+                .listen_on(circuit)
+                .map_err(|e| GenericError::Other(e.to_string()))?;
+            state.relay_reservations.insert(listener_id, relay_peer);
+            Ok(listener_id)
+        })
+        .await?
+}
+
+/// Dial a peer through the relay (a multiaddress ending with `/p2p/<relay id>`),
+/// the connection is upgraded to a direct one when dcutr succeeds.
+pub async fn libp2p_dial_via_relay(
+    wrapper: &Arc<Wrapper>,
+    relay: String,
+    peer: String,
+) -> Result<ConnectionId, GenericError> {
+    let (relay_peer, address) = parse_peer_address(&relay)?;
+    let peer = peer
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    let circuit = address
+        .with(Protocol::P2p(relay_peer))
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(peer));
+    let dial_opts = DialOpts::peer_id(peer).addresses(vec![circuit]).build();
+    let id = dial_opts.connection_id();
+    wrapper
+        .with_swarm(move |swarm| swarm.dial(dial_opts))
+        .await?
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    Ok(id)
+}
+
+/// Relays on which a reservation was requested and is still listened on.
+pub async fn libp2p_relay_reservations(
+    wrapper: &Arc<Wrapper>,
+) -> Result<Vec<String>, GenericError> {
+    wrapper
+        .call(|state| {
+            state
+                .relay_reservations
+                .values()
+                .map(|f| f.to_string())
+                .collect()
+        })
+        .await
+}
+
+pub async fn libp2p_relay_server_stats(
+    wrapper: &Arc<Wrapper>,
+) -> Result<CustomRelayServerStats, GenericError> {
+    wrapper
+        .call(|state| {
+            if state.swarm.behaviour().relay_server.is_enabled() {
+                Ok(state.relay_server_stats.stats.clone())
+            } else {
+                Err(GenericError::BehaviourDisabled)
+            }
+        })
+        .await?
+}
+
+//
+// EVENTS
+//
+pub(crate) fn relay_client_event(event: relay::client::Event) -> CustomSwarmEvent {
+    match event {
+        relay::client::Event::ReservationReqAccepted {
+            relay_peer_id,
+            renewal,
+            limit,
+        } => CustomSwarmEvent::RelayReservationAccepted {
+            relay_peer_id: relay_peer_id.to_string(),
+            renewal,
+            limit_duration: limit
+                .and_then(|f| f.duration())
+                .map(|f| f.as_millis() as u64),
+            limit_bytes: limit.and_then(|f| f.data_in_bytes()),
+        },
+        relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+            CustomSwarmEvent::RelayOutboundCircuitEstablished {
+                relay_peer_id: relay_peer_id.to_string(),
+            }
+        }
+        relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+            CustomSwarmEvent::RelayInboundCircuitEstablished {
+                src_peer_id: src_peer_id.to_string(),
+            }
+        }
+    }
+}
+
+pub(crate) fn dcutr_event(event: dcutr::Event) -> CustomSwarmEvent {
+    match event.result {
+        Ok(connection_id) => CustomSwarmEvent::DcutrSucceeded {
+            remote_peer_id: event.remote_peer_id.to_string(),
+            connection_id,
+        },
+        Err(error) => CustomSwarmEvent::DcutrFailed {
+            remote_peer_id: event.remote_peer_id.to_string(),
+            error: error.to_string(),
+        },
+    }
+}
+
+/// The relay transport reports a refused reservation by closing its listener.
+pub(crate) fn relay_listener_closed(
+    state: &mut SwarmState,
+    listener_id: ListenerId,
+    error: Option<String>,
+) -> Option<CustomSwarmEvent> {
+    let relay_peer = state.relay_reservations.remove(&listener_id)?;
+    error.map(|error| CustomSwarmEvent::RelayReservationFailed {
+        relay_peer_id: relay_peer.to_string(),
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn closed_duration(stats: &mut RelayServerStats, src: PeerId, dst: PeerId) -> u64 {
+        let event = stats.on_event(relay::Event::CircuitClosed {
+            src_peer_id: src,
+            dst_peer_id: dst,
+            error: None,
+        });
+        match event {
+            Some(CustomSwarmEvent::RelayServerCircuitClosed { duration, .. }) => duration,
+            _ => panic!("not a closed circuit"),
+        }
+    }
+
+    #[test]
+    fn closed_circuits_report_their_duration() {
+        let mut stats = RelayServerStats::default();
+        let (src, dst) = (PeerId::random(), PeerId::random());
+        let accept = |stats: &mut RelayServerStats| {
+            stats.on_event(relay::Event::CircuitReqAccepted {
+                src_peer_id: src,
+                dst_peer_id: dst,
+            })
+        };
+        accept(&mut stats);
+        std::thread::sleep(Duration::from_millis(50));
+        accept(&mut stats);
+        // the oldest circuit of the peers is closed first
+        assert!(closed_duration(&mut stats, src, dst) >= 50);
+        assert!(closed_duration(&mut stats, src, dst) < 50);
+        assert!(stats.circuits.is_empty());
+        assert_eq!(stats.stats.active_circuits, 0);
+        // circuits opened before the stats were created have no known duration
+        assert_eq!(closed_duration(&mut stats, src, dst), 0);
+    }
+}
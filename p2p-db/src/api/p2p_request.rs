@@ -1,46 +1,112 @@
 use super::{
-    p2p_common::{CustomBehaviour, GenericError},
-    wrapper::Wrapper,
+    p2p_common::{CustomResponseChannel, GenericError, RequestError},
+    wrapper::{SwarmState, Wrapper},
 };
-use libp2p::{request_response::ResponseChannel, Swarm};
-use std::borrow::Borrow;
+use libp2p::{request_response::OutboundRequestId, PeerId};
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
 
+/// Requests awaited by `libp2p_request` by protocol and id, resolved by the swarm task.
+pub(crate) type PendingRequests =
+    HashMap<(String, OutboundRequestId), oneshot::Sender<Result<Vec<u8>, RequestError>>>;
+
+/// Send a request using the given protocol, the default (first registered) one when none.
 pub async fn libp2p_send_request(
     wrapper: &Arc<Wrapper>,
     peerid: String,
     request: Vec<u8>,
+    protocol: Option<String>,
 ) -> Result<String, GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        if let Ok(peer) = peerid.parse().borrow() {
-            let behaviour = swarm.behaviour_mut();
-            let res = behaviour.request_response.send_request(peer, request);
-            Ok(res.to_string())
-        } else {
-            Err(GenericError::BadAddress)
+    if let Ok(peer) = peerid.parse::<PeerId>() {
+        let res = wrapper
+            .with_swarm(move |swarm| {
+                swarm.behaviour_mut().request_response.send_request(
+                    protocol.as_deref(),
+                    &peer,
+                    request,
+                )
+            })
+            .await?;
+        match res {
+            Some((_, request_id)) => Ok(request_id.to_string()),
+            None => Err(GenericError::UnknownProtocol),
         }
     } else {
-        Err(GenericError::InstanceNotFound)
+        Err(GenericError::BadAddress)
     }
 }
 
+/// Answer an inbound request, fails with the response when it was already answered.
 pub async fn libp2p_send_response(
     wrapper: &Arc<Wrapper>,
-    channel: ResponseChannel<Vec<u8>>,
+    channel: &CustomResponseChannel,
     response: Vec<u8>,
 ) -> Result<(), GenericError> {
-    let swarm_opt: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    if let Some(swarm) = swarm_opt {
-        let behaviour = swarm.behaviour_mut();
-        let res = behaviour.request_response.send_response(channel, response);
-        let res_safe = res.map_err(|e| GenericError::Bytes(e));
-        res_safe
-    } else {
-        Err(GenericError::InstanceNotFound)
+    let Some(channel) = channel.take() else {
+        return Err(GenericError::Bytes(response));
+    };
+    let res = wrapper
+        .with_swarm(move |swarm| {
+            swarm
+                .behaviour_mut()
+                .request_response
+                .send_response(channel, response)
+        })
+        .await?;
+    res.map_err(GenericError::Bytes)
+}
+
+/// Send a request and wait for the matching response.
+/// The timeout is in milliseconds, the default protocol is used when none.
+pub async fn libp2p_request(
+    wrapper: &Arc<Wrapper>,
+    peerid: String,
+    request: Vec<u8>,
+    timeout: u64,
+    protocol: Option<String>,
+) -> Result<Vec<u8>, RequestError> {
+    let peer = peerid
+        .parse::<PeerId>()
+        .map_err(|_| RequestError::BadAddress)?;
+    let (sender, receiver) = oneshot::channel();
+    // registered by the swarm task before it can see the response
+    let key = wrapper
+        .call(move |state| {
+            let key = state.swarm.behaviour_mut().request_response.send_request(
+                protocol.as_deref(),
+                &peer,
+                request,
+            )?;
+            state.pending_requests.insert(key.clone(), sender);
+            Some(key)
+        })
+        .await
+        .map_err(|_| RequestError::InstanceNotFound)?
+        .ok_or(RequestError::UnknownProtocol)?;
+    match tokio::time::timeout(Duration::from_millis(timeout), receiver).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err(RequestError::Closed),
+        Err(_) => {
+            let _ = wrapper.send(move |state| {
+                state.pending_requests.remove(&key);
+            });
+            Err(RequestError::Timeout)
+        }
     }
 }
 
-pub async fn libp2p_close_response(channel: ResponseChannel<Vec<u8>>) -> () {
-    drop(channel);
+/// Remove a request awaited by `libp2p_request`, none when it was sent by `libp2p_send_request`.
+pub(crate) fn take_pending_request(
+    state: &mut SwarmState,
+    protocol: &str,
+    request_id: OutboundRequestId,
+) -> Option<oneshot::Sender<Result<Vec<u8>, RequestError>>> {
+    let key = (protocol.to_string(), request_id);
+    state.pending_requests.remove(&key)
+}
+
+pub async fn libp2p_close_response(channel: &CustomResponseChannel) -> () {
+    drop(channel.take());
 }
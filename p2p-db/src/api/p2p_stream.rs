@@ -0,0 +1,272 @@
+use super::{p2p_common::GenericError, p2p_utils::CancellationToken, wrapper::Wrapper};
+use crate::frb_generated::StreamSink;
+use flutter_rust_bridge::frb;
+use futures::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use futures::StreamExt;
+use libp2p::swarm::Stream;
+use libp2p::{PeerId, StreamProtocol};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+//
+// CONSTANTS
+//
+// reads are bounded whatever `max_size`, a read returns what the muxer already received
+const MAX_READ_SIZE: usize = 1 << 20;
+
+//
+// STRUCTS
+//
+/// Bidirectional byte stream with a peer, opened by `libp2p_open_stream` or accepted by `libp2p_stream_accept`.
+#[frb(opaque)]
+pub struct CustomStream {
+    peer: PeerId,
+    protocol: StreamProtocol,
+    // reads and writes do not wait for each other
+    reader: Mutex<ReadHalf<Stream>>,
+    // none once closed
+    writer: Mutex<Option<WriteHalf<Stream>>>,
+}
+
+impl CustomStream {
+    fn new(peer: PeerId, protocol: StreamProtocol, stream: Stream) -> Self {
+        let (reader, writer) = stream.split();
+        CustomStream {
+            peer,
+            protocol,
+            reader: Mutex::new(reader),
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+}
+
+//
+// FUNCTIONS
+//
+/// Open a stream with the peer on the protocol, the peer is dialed when it is not connected.
+pub async fn libp2p_open_stream(
+    wrapper: &Arc<Wrapper>,
+    peer: String,
+    protocol: String,
+) -> Result<CustomStream, GenericError> {
+    let peer = peer
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    let protocol = parse_protocol(protocol)?;
+    let stream = open_stream(wrapper, peer, protocol.clone()).await?;
+    Ok(CustomStream::new(peer, protocol, stream))
+}
+
+/// Deliver the streams opened by peers on the protocol until cancelled,
+/// false when cancelled and true when the instance is dropped.
+pub async fn libp2p_stream_accept(
+    wrapper: &Arc<Wrapper>,
+    protocol: String,
+    cancellation_token: &CancellationToken,
+    sink: StreamSink<CustomStream>,
+) -> Result<bool, GenericError> {
+    let protocol = parse_protocol(protocol)?;
+    let accepted = protocol.clone();
+    accept_streams(wrapper, accepted, cancellation_token, |peer, stream| {
+        sink.add(CustomStream::new(peer, protocol.clone(), stream))
+            .map_err(|e| GenericError::Other(e.to_string()))
+    })
+    .await
+}
+
+/// Read up to `max_size` bytes (at most 1 MiB), empty once the remote closed the stream.
+pub async fn libp2p_stream_read(
+    stream: &CustomStream,
+    max_size: u32,
+) -> Result<Vec<u8>, GenericError> {
+    let mut buffer = vec![0u8; (max_size as usize).min(MAX_READ_SIZE)];
+    let size = stream
+        .reader
+        .lock()
+        .await
+        .read(&mut buffer)
+        .await
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    buffer.truncate(size);
+    Ok(buffer)
+}
+
+/// Write all the bytes and flush them.
+pub async fn libp2p_stream_write(stream: &CustomStream, data: Vec<u8>) -> Result<(), GenericError> {
+    let mut writer = stream.writer.lock().await;
+    let writer = writer
+        .as_mut()
+        .ok_or_else(|| GenericError::Other("stream_closed".to_string()))?;
+    writer
+        .write_all(&data)
+        .await
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    writer
+        .flush()
+        .await
+        .map_err(|e| GenericError::Other(e.to_string()))
+}
+
+/// Close the writing side, the remote reads the end of the stream but can still write.
+pub async fn libp2p_stream_close(stream: &CustomStream) -> Result<(), GenericError> {
+    let mut writer = stream.writer.lock().await;
+    match writer.take() {
+        Some(mut writer) => writer
+            .close()
+            .await
+            .map_err(|e| GenericError::Other(e.to_string())),
+        None => Ok(()),
+    }
+}
+
+#[frb(sync)]
+pub fn libp2p_stream_peer_id(stream: &CustomStream) -> String {
+    stream.peer.to_string()
+}
+
+#[frb(sync)]
+pub fn libp2p_stream_protocol(stream: &CustomStream) -> String {
+    stream.protocol.to_string()
+}
+
+//
+// UTILS
+//
+pub(crate) fn parse_protocol(protocol: String) -> Result<StreamProtocol, GenericError> {
+    StreamProtocol::try_from_owned(protocol).map_err(|e| GenericError::Other(e.to_string()))
+}
+
+pub(crate) async fn open_stream(
+    wrapper: &Wrapper,
+    peer: PeerId,
+    protocol: StreamProtocol,
+) -> Result<Stream, GenericError> {
+    let mut control = wrapper
+        .with_swarm(|swarm| swarm.behaviour().stream.new_control())
+        .await?;
+    control
+        .open_stream(peer, protocol)
+        .await
+        .map_err(|e| GenericError::Other(e.to_string()))
+}
+
+/// Hand the inbound streams of the protocol to `on_stream` until cancelled or `on_stream` fails,
+/// false when cancelled and true when the instance is dropped.
+pub(crate) async fn accept_streams<F>(
+    wrapper: &Wrapper,
+    protocol: StreamProtocol,
+    cancellation_token: &CancellationToken,
+    mut on_stream: F,
+) -> Result<bool, GenericError>
+where
+    F: FnMut(PeerId, Stream) -> Result<(), GenericError>,
+{
+    // no control is kept: the streams end when the behaviour is dropped with the instance,
+    // dropping them stops accepting the protocol
+    let mut streams = wrapper
+        .with_swarm(move |swarm| swarm.behaviour().stream.new_control().accept(protocol))
+        .await?
+        .map_err(|_| GenericError::Other("protocol_already_accepted".to_string()))?;
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => return Ok(false),
+            stream = streams.next() => match stream {
+                Some((peer, stream)) => on_stream(peer, stream)?,
+                None => return Ok(true),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::connected_pair;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    const TEST_PROTOCOL: &str = "/p2p-db/test-stream/1.0.0";
+
+    // the instances are returned to keep the connection open
+    type StreamPair = (
+        CustomStream,
+        CustomStream,
+        CancellationToken,
+        Vec<Arc<Wrapper>>,
+    );
+
+    /// Open a stream from the dialer and return it with the stream accepted by the listener.
+    async fn stream_pair() -> StreamPair {
+        let (dialer, listener) = connected_pair().await;
+        let token = CancellationToken::new();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        {
+            let (listener, token) = (listener.clone(), token.clone());
+            tokio::spawn(async move {
+                let protocol = parse_protocol(TEST_PROTOCOL.to_string()).ok().unwrap();
+                let accepted = protocol.clone();
+                accept_streams(&listener, accepted, &token, |peer, stream| {
+                    let _ = sender.send(CustomStream::new(peer, protocol.clone(), stream));
+                    Ok(())
+                })
+                .await
+            });
+        }
+        let peer = listener.peer_id().to_string();
+        // the listener may not accept the protocol yet
+        for _ in 0..50 {
+            let protocol = TEST_PROTOCOL.to_string();
+            if let Ok(opened) = libp2p_open_stream(&dialer, peer.clone(), protocol).await {
+                let accepted = receiver.recv().await.unwrap();
+                return (opened, accepted, token, vec![dialer, listener]);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("stream not opened");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn streams_are_bidirectional() {
+        let (opened, accepted, token, _instances) = stream_pair().await;
+        assert_eq!(libp2p_stream_protocol(&accepted), TEST_PROTOCOL);
+        assert!(libp2p_stream_write(&opened, b"hello".to_vec())
+            .await
+            .is_ok());
+        assert!(libp2p_stream_close(&opened).await.is_ok());
+        let read = libp2p_stream_read(&accepted, 1024).await.ok().unwrap();
+        assert_eq!(read, b"hello");
+        // the end of the stream once the remote closed it
+        let end = libp2p_stream_read(&accepted, 1024).await.ok().unwrap();
+        assert!(end.is_empty());
+        // the other side can still write
+        assert!(libp2p_stream_write(&accepted, b"world".to_vec())
+            .await
+            .is_ok());
+        let read = libp2p_stream_read(&opened, 1024).await.ok().unwrap();
+        assert_eq!(read, b"world");
+        assert!(libp2p_stream_write(&opened, vec![1]).await.is_err());
+        token.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_are_bounded() {
+        let (opened, accepted, token, _instances) = stream_pair().await;
+        let size = 2 * MAX_READ_SIZE + 1;
+        let writer = tokio::spawn(async move {
+            assert!(libp2p_stream_write(&opened, vec![7; size]).await.is_ok());
+            assert!(libp2p_stream_close(&opened).await.is_ok());
+        });
+        let mut total = 0;
+        loop {
+            let read = libp2p_stream_read(&accepted, u32::MAX).await.ok().unwrap();
+            if read.is_empty() {
+                break;
+            }
+            assert!(read.len() <= MAX_READ_SIZE);
+            total += read.len();
+        }
+        assert_eq!(total, size);
+        writer.await.unwrap();
+        token.cancel();
+    }
+}
@@ -1,9 +1,6 @@
-pub use super::{
-    p2p_common::{CustomBehaviour, GenericError},
-    wrapper::Wrapper,
-};
+pub use super::{p2p_common::GenericError, wrapper::Wrapper};
 use flutter_rust_bridge::frb;
-use libp2p::{swarm::ConnectionId, PeerId, Swarm};
+use libp2p::{swarm::ConnectionId, PeerId};
 use std::sync::Arc;
 pub use tokio_util::sync::CancellationToken;
 
@@ -29,11 +26,7 @@ pub fn create_cancellation_token() -> CancellationToken {
 //
 #[frb(sync)]
 pub fn libp2p_peerid(wrapper: &Arc<Wrapper>) -> Result<String, GenericError> {
-    let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    let res = swarm
-        .ok_or(GenericError::InstanceNotFound)?
-        .local_peer_id()
-        .to_string();
+    let res = wrapper.peer_id().to_string();
     Ok(res)
 }
 #[frb(sync)]
@@ -47,10 +40,8 @@ pub fn libp2p_add_external_address(
     address: String,
 ) -> Result<bool, GenericError> {
     if let Ok(multiaddr) = address.parse() {
-        let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-        swarm
-            .ok_or(GenericError::InstanceNotFound)?
-            .add_external_address(multiaddr);
+        // sync function, the command is queued without waiting
+        wrapper.send(move |state| state.swarm.add_external_address(multiaddr))?;
         Ok(true)
     } else {
         Err(GenericError::BadAddress)
@@ -62,12 +53,9 @@ pub async fn libp2p_add_blacklist(
     peer: String,
 ) -> Result<bool, GenericError> {
     if let Ok(peerid) = peer.parse::<PeerId>() {
-        let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-        swarm
-            .ok_or(GenericError::InstanceNotFound)?
-            .behaviour_mut()
-            .blacklist
-            .block_peer(peerid);
+        wrapper
+            .with_swarm(move |swarm| swarm.behaviour_mut().blacklist.block_peer(peerid))
+            .await?;
         Ok(true)
     } else {
         Err(GenericError::BadAddress)
@@ -79,12 +67,9 @@ pub async fn libp2p_remove_blacklist(
     peer: String,
 ) -> Result<bool, GenericError> {
     if let Ok(peerid) = peer.parse::<PeerId>() {
-        let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-        swarm
-            .ok_or(GenericError::InstanceNotFound)?
-            .behaviour_mut()
-            .blacklist
-            .unblock_peer(peerid);
+        wrapper
+            .with_swarm(move |swarm| swarm.behaviour_mut().blacklist.unblock_peer(peerid))
+            .await?;
         Ok(true)
     } else {
         Err(GenericError::BadAddress)
@@ -95,24 +80,19 @@ pub async fn libp2p_close_connection(
     wrapper: &Arc<Wrapper>,
     connection_id: ConnectionId,
 ) -> Result<bool, GenericError> {
-    let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-    let res = swarm
-        .ok_or(GenericError::InstanceNotFound)?
-        .close_connection(connection_id);
-    Ok(res)
+    wrapper
+        .with_swarm(move |swarm| swarm.close_connection(connection_id))
+        .await
 }
-/*
+
 pub async fn libp2p_add_whitelist(
     wrapper: &Arc<Wrapper>,
     peer: String,
 ) -> Result<bool, GenericError> {
     if let Ok(peerid) = peer.parse::<PeerId>() {
-        let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-        swarm
-            .ok_or(GenericError::InstanceNotFound)?
-            .behaviour_mut()
-            .whitelist
-            .allow_peer(peerid);
+        wrapper
+            .with_swarm(move |swarm| swarm.behaviour_mut().whitelist.allow_peer(peerid))
+            .await??;
         Ok(true)
     } else {
         Err(GenericError::BadAddress)
@@ -124,15 +104,19 @@ pub async fn libp2p_remove_whitelist(
     peer: String,
 ) -> Result<bool, GenericError> {
     if let Ok(peerid) = peer.parse::<PeerId>() {
-        let swarm: Option<&mut Swarm<CustomBehaviour>> = wrapper.as_mut();
-        swarm
-            .ok_or(GenericError::InstanceNotFound)?
-            .behaviour_mut()
-            .whitelist
-            .disallow_peer(peerid);
+        wrapper
+            .with_swarm(move |swarm| {
+                let whitelist = &mut swarm.behaviour_mut().whitelist;
+                whitelist.disallow_peer(peerid)?;
+                if !whitelist.is_allowed(&peerid) {
+                    // whitelist only mode, the peer can not stay connected
+                    let _ = swarm.disconnect_peer_id(peerid);
+                }
+                Ok::<(), GenericError>(())
+            })
+            .await??;
         Ok(true)
     } else {
         Err(GenericError::BadAddress)
     }
 }
-*/
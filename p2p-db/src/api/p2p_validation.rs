@@ -0,0 +1,377 @@
+use super::{p2p_common::GenericError, wrapper::Wrapper};
+use flutter_rust_bridge::frb;
+use jsonschema::JSONSchema;
+use libp2p::gossipsub::{self, MessageAcceptance, MessageId, TopicHash};
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+//
+// CONSTANTS
+//
+/// Interval between two checks of the forwarded messages timeouts.
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_millis(250);
+
+//
+// STRUCTS
+//
+/// Validators run in order, the first one rejecting or ignoring a message decides.
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub enum CustomValidator {
+    /// Reject messages larger than this number of bytes.
+    MaxSize(usize),
+    /// Reject messages without a source. Gossipsub runs in strict validation mode: it verifies
+    /// the signature of every message with a source and drops the unsigned ones before they
+    /// reach the pipeline, so this only matters if the validation mode is relaxed.
+    SignatureRequired,
+    /// Reject messages that are not json documents valid against this json schema.
+    JsonSchema(String),
+    /// Ignore messages with the same content as a message received during the window.
+    Dedupe { window: u64 },
+    /// Ignore the messages of a source above `messages` per `interval`.
+    RateLimit { messages: u32, interval: u64 },
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+#[derive(Clone, Debug)]
+pub struct CustomTopicValidation {
+    pub validators: Vec<CustomValidator>,
+    /// Messages passing every validator are left to `libp2p_pubsub_validate` when true,
+    /// they are accepted otherwise.
+    pub forward: bool,
+    /// Forwarded messages not validated in time are ignored, none to wait forever.
+    pub timeout: Option<u64>,
+}
+
+enum Validator {
+    MaxSize(usize),
+    SignatureRequired,
+    JsonSchema(JSONSchema),
+    Dedupe {
+        window: Duration,
+        seen: HashMap<[u8; 32], Instant>,
+    },
+    RateLimit {
+        messages: u32,
+        interval: Duration,
+        // start of the current window and messages counted in it
+        sources: HashMap<PeerId, (Instant, u32)>,
+    },
+}
+
+impl Validator {
+    fn new(validator: CustomValidator) -> Result<Validator, GenericError> {
+        match validator {
+            CustomValidator::MaxSize(size) => Ok(Validator::MaxSize(size)),
+            CustomValidator::SignatureRequired => Ok(Validator::SignatureRequired),
+            CustomValidator::JsonSchema(schema) => {
+                let value: serde_json::Value = serde_json::from_str(&schema)
+                    .map_err(|e| GenericError::Other(e.to_string()))?;
+                let compiled =
+                    JSONSchema::compile(&value).map_err(|e| GenericError::Other(e.to_string()))?;
+                Ok(Validator::JsonSchema(compiled))
+            }
+            CustomValidator::Dedupe { window } => Ok(Validator::Dedupe {
+                window: Duration::from_millis(window),
+                seen: HashMap::new(),
+            }),
+            CustomValidator::RateLimit { messages, interval } => Ok(Validator::RateLimit {
+                messages,
+                interval: Duration::from_millis(interval),
+                sources: HashMap::new(),
+            }),
+        }
+    }
+
+    /// None when the message passes.
+    fn validate(
+        &mut self,
+        propagation_source: &PeerId,
+        message: &gossipsub::Message,
+        now: Instant,
+    ) -> Option<MessageAcceptance> {
+        match self {
+            Validator::MaxSize(size) => {
+                (message.data.len() > *size).then_some(MessageAcceptance::Reject)
+            }
+            Validator::SignatureRequired => message
+                .source
+                .is_none()
+                .then_some(MessageAcceptance::Reject),
+            Validator::JsonSchema(schema) => {
+                let valid = serde_json::from_slice::<serde_json::Value>(&message.data)
+                    .map(|value| schema.is_valid(&value))
+                    .unwrap_or(false);
+                (!valid).then_some(MessageAcceptance::Reject)
+            }
+            Validator::Dedupe { window, seen } => {
+                let hash: [u8; 32] = Sha256::digest(&message.data).into();
+                match seen.get(&hash) {
+                    Some(at) if now.duration_since(*at) < *window => {
+                        Some(MessageAcceptance::Ignore)
+                    }
+                    _ => {
+                        seen.insert(hash, now);
+                        None
+                    }
+                }
+            }
+            Validator::RateLimit {
+                messages,
+                interval,
+                sources,
+            } => {
+                let source = message.source.unwrap_or(*propagation_source);
+                let (start, count) = sources.entry(source).or_insert((now, 0));
+                if now.duration_since(*start) >= *interval {
+                    *start = now;
+                    *count = 0;
+                }
+                *count += 1;
+                (*count > *messages).then_some(MessageAcceptance::Ignore)
+            }
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        match self {
+            Validator::Dedupe { window, seen } => {
+                seen.retain(|_, at| now.duration_since(*at) < *window);
+            }
+            Validator::RateLimit {
+                interval, sources, ..
+            } => {
+                sources.retain(|_, (start, _)| now.duration_since(*start) < *interval);
+            }
+            _ => {}
+        }
+    }
+}
+
+struct TopicPipeline {
+    validators: Vec<Validator>,
+    forward: bool,
+    timeout: Option<Duration>,
+}
+
+/// Validation of the gossipsub messages, owned by the swarm task.
+#[derive(Default)]
+#[frb(ignore)]
+#[frb(opaque)]
+pub(crate) struct ValidationPipeline {
+    topics: HashMap<TopicHash, TopicPipeline>,
+    // forwarded messages with a timeout: propagation source and deadline
+    pending: HashMap<MessageId, (PeerId, Instant)>,
+}
+
+impl ValidationPipeline {
+    /// Acceptance decided in rust, none when the message is left to `libp2p_pubsub_validate`.
+    pub(crate) fn validate(
+        &mut self,
+        propagation_source: &PeerId,
+        message_id: &MessageId,
+        message: &gossipsub::Message,
+    ) -> Option<MessageAcceptance> {
+        let pipeline = self.topics.get_mut(&message.topic)?;
+        let now = Instant::now();
+        for validator in pipeline.validators.iter_mut() {
+            if let Some(acceptance) = validator.validate(propagation_source, message, now) {
+                return Some(acceptance);
+            }
+        }
+        if !pipeline.forward {
+            return Some(MessageAcceptance::Accept);
+        }
+        // a timeout too large for an instant waits forever
+        if let Some(deadline) = pipeline.timeout.and_then(|t| now.checked_add(t)) {
+            self.pending
+                .insert(message_id.clone(), (*propagation_source, deadline));
+        }
+        None
+    }
+
+    pub(crate) fn validated(&mut self, message_id: &MessageId) {
+        self.pending.remove(message_id);
+    }
+
+    /// Forwarded messages whose timeout elapsed, they are forgotten.
+    pub(crate) fn expired(&mut self) -> Vec<(MessageId, PeerId)> {
+        let now = Instant::now();
+        for pipeline in self.topics.values_mut() {
+            for validator in pipeline.validators.iter_mut() {
+                validator.prune(now);
+            }
+        }
+        let expired: Vec<(MessageId, PeerId)> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(id, (peer, _))| (id.clone(), *peer))
+            .collect();
+        for (id, _) in expired.iter() {
+            self.pending.remove(id);
+        }
+        expired
+    }
+}
+
+//
+// FUNCTIONS
+//
+/// Validate the messages of the topic in rust, replaces the previous validation of the topic.
+pub async fn libp2p_pubsub_set_validation(
+    wrapper: &Arc<Wrapper>,
+    topic: String,
+    validation: CustomTopicValidation,
+) -> Result<(), GenericError> {
+    let hash = gossipsub::IdentTopic::new(topic).hash();
+    let validators = validation
+        .validators
+        .into_iter()
+        .map(Validator::new)
+        .collect::<Result<Vec<Validator>, GenericError>>()?;
+    let pipeline = TopicPipeline {
+        validators,
+        forward: validation.forward,
+        timeout: validation.timeout.map(Duration::from_millis),
+    };
+    wrapper
+        .call(move |state| {
+            state.validation.topics.insert(hash, pipeline);
+        })
+        .await
+}
+
+/// Messages of the topic are forwarded without validation again.
+pub async fn libp2p_pubsub_remove_validation(
+    wrapper: &Arc<Wrapper>,
+    topic: String,
+) -> Result<bool, GenericError> {
+    let hash = gossipsub::IdentTopic::new(topic).hash();
+    wrapper
+        .call(move |state| state.validation.topics.remove(&hash).is_some())
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(validators: Vec<CustomValidator>, timeout: Option<u64>) -> ValidationPipeline {
+        let mut pipeline = ValidationPipeline::default();
+        let topic = TopicPipeline {
+            validators: validators
+                .into_iter()
+                .map(|v| Validator::new(v).ok().unwrap())
+                .collect(),
+            forward: timeout.is_some(),
+            timeout: timeout.map(Duration::from_millis),
+        };
+        pipeline
+            .topics
+            .insert(gossipsub::IdentTopic::new("topic").hash(), topic);
+        pipeline
+    }
+
+    fn message(source: PeerId, data: &[u8]) -> gossipsub::Message {
+        gossipsub::Message {
+            source: Some(source),
+            data: data.to_vec(),
+            sequence_number: None,
+            topic: gossipsub::IdentTopic::new("topic").hash(),
+        }
+    }
+
+    #[test]
+    fn validators_run_in_order() {
+        let peer = PeerId::random();
+        let id = MessageId::from("id");
+        let mut pipeline = pipeline(
+            vec![
+                CustomValidator::MaxSize(16),
+                CustomValidator::JsonSchema(r#"{"type":"object"}"#.to_string()),
+                CustomValidator::Dedupe { window: 60_000 },
+                CustomValidator::RateLimit {
+                    messages: 2,
+                    interval: 60_000,
+                },
+            ],
+            None,
+        );
+        let validate = |pipeline: &mut ValidationPipeline, data: &[u8]| {
+            pipeline.validate(&peer, &id, &message(peer, data))
+        };
+        assert!(matches!(
+            validate(&mut pipeline, br#"{"a":"0123456789"}"#),
+            Some(MessageAcceptance::Reject)
+        ));
+        assert!(matches!(
+            validate(&mut pipeline, b"[]"),
+            Some(MessageAcceptance::Reject)
+        ));
+        assert!(matches!(
+            validate(&mut pipeline, b"{}"),
+            Some(MessageAcceptance::Accept)
+        ));
+        assert!(matches!(
+            validate(&mut pipeline, b"{}"),
+            Some(MessageAcceptance::Ignore)
+        ));
+        assert!(matches!(
+            validate(&mut pipeline, br#"{"a":1}"#),
+            Some(MessageAcceptance::Accept)
+        ));
+        assert!(matches!(
+            validate(&mut pipeline, br#"{"a":2}"#),
+            Some(MessageAcceptance::Ignore)
+        ));
+        // other topics are not validated
+        let mut other = message(peer, b"[]");
+        other.topic = gossipsub::IdentTopic::new("other").hash();
+        assert!(pipeline.validate(&peer, &id, &other).is_none());
+    }
+
+    #[test]
+    fn anonymous_messages_rejected() {
+        let peer = PeerId::random();
+        let id = MessageId::from("id");
+        let mut pipeline = pipeline(vec![CustomValidator::SignatureRequired], None);
+        let mut anonymous = message(peer, b"a");
+        anonymous.source = None;
+        assert!(matches!(
+            pipeline.validate(&peer, &id, &anonymous),
+            Some(MessageAcceptance::Reject)
+        ));
+        assert!(matches!(
+            pipeline.validate(&peer, &id, &message(peer, b"a")),
+            Some(MessageAcceptance::Accept)
+        ));
+    }
+
+    #[test]
+    fn forwarded_messages_expire() {
+        let peer = PeerId::random();
+        let mut pipeline = pipeline(vec![], Some(0));
+        let id = MessageId::from("expired");
+        assert!(pipeline
+            .validate(&peer, &id, &message(peer, b"a"))
+            .is_none());
+        let validated = MessageId::from("validated");
+        assert!(pipeline
+            .validate(&peer, &validated, &message(peer, b"b"))
+            .is_none());
+        pipeline.validated(&validated);
+        assert_eq!(pipeline.expired(), vec![(id, peer)]);
+        assert!(pipeline.expired().is_empty());
+        // a timeout too large for an instant waits forever
+        let mut forever = self::pipeline(vec![], Some(u64::MAX));
+        let id = MessageId::from("forever");
+        assert!(forever.validate(&peer, &id, &message(peer, b"a")).is_none());
+        assert!(forever.expired().is_empty());
+    }
+}
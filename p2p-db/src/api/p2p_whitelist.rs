@@ -0,0 +1,281 @@
+// allow_block_list::AllowedPeers can not be switched off, the whitelist has its own behaviour
+use super::{
+    p2p_common::{CustomBehaviour, GenericError},
+    sled::CustomDB,
+    wrapper::Wrapper,
+};
+use flutter_rust_bridge::frb;
+use libp2p::core::Endpoint;
+use libp2p::swarm::{
+    dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+    THandlerOutEvent, ToSwarm,
+};
+use libp2p::{Multiaddr, PeerId, Swarm};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+//
+// CONSTANTS
+//
+const TREE_WHITELIST: &str = "__whitelist";
+// not a valid peer id, stored next to the peers
+const KEY_WHITELIST_ONLY: &[u8] = b"__whitelist_only";
+
+//
+// BEHAVIOUR
+//
+/// Deny the connections of peers outside the whitelist when enabled.
+#[derive(Default)]
+#[frb(ignore)]
+#[frb(opaque)]
+pub struct Whitelist {
+    enabled: bool,
+    peers: HashSet<PeerId>,
+    // set once attached, changes are written through
+    tree: Option<sled::Tree>,
+}
+
+#[derive(Debug)]
+#[frb(ignore)]
+#[frb(opaque)]
+struct NotWhitelisted {
+    peer: PeerId,
+}
+
+impl fmt::Display for NotWhitelisted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer {} is not whitelisted", self.peer)
+    }
+}
+
+impl std::error::Error for NotWhitelisted {}
+
+impl Whitelist {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Whitelist {
+            enabled,
+            ..Whitelist::default()
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) -> Result<(), GenericError> {
+        if let Some(tree) = self.tree.as_ref() {
+            tree.insert(KEY_WHITELIST_ONLY, vec![enabled as u8])
+                .map_err(|e| GenericError::Other(e.to_string()))?;
+        }
+        self.enabled = enabled;
+        Ok(())
+    }
+
+    pub(crate) fn is_allowed(&self, peer: &PeerId) -> bool {
+        !self.enabled || self.peers.contains(peer)
+    }
+
+    pub(crate) fn peers(&self) -> Vec<PeerId> {
+        self.peers.iter().copied().collect()
+    }
+
+    pub(crate) fn allow_peer(&mut self, peer: PeerId) -> Result<bool, GenericError> {
+        if let Some(tree) = self.tree.as_ref() {
+            tree.insert(peer.to_bytes(), vec![])
+                .map_err(|e| GenericError::Other(e.to_string()))?;
+        }
+        Ok(self.peers.insert(peer))
+    }
+
+    pub(crate) fn disallow_peer(&mut self, peer: PeerId) -> Result<bool, GenericError> {
+        if let Some(tree) = self.tree.as_ref() {
+            tree.remove(peer.to_bytes())
+                .map_err(|e| GenericError::Other(e.to_string()))?;
+        }
+        Ok(self.peers.remove(&peer))
+    }
+
+    /// Load the persisted peers and mode, the peers allowed before are persisted too.
+    /// The current mode is persisted when none was.
+    pub(crate) fn attach(&mut self, tree: sled::Tree) -> Result<usize, GenericError> {
+        for peer in self.peers.iter() {
+            tree.insert(peer.to_bytes(), vec![])
+                .map_err(|e| GenericError::Other(e.to_string()))?;
+        }
+        match tree
+            .get(KEY_WHITELIST_ONLY)
+            .map_err(|e| GenericError::Other(e.to_string()))?
+        {
+            Some(value) => self.enabled = value.first() == Some(&1),
+            None => {
+                tree.insert(KEY_WHITELIST_ONLY, vec![self.enabled as u8])
+                    .map_err(|e| GenericError::Other(e.to_string()))?;
+            }
+        }
+        for current in tree.iter() {
+            let (key, _) = current.map_err(|e| GenericError::Other(e.to_string()))?;
+            if let Ok(peer) = PeerId::from_bytes(&key) {
+                self.peers.insert(peer);
+            }
+        }
+        self.tree = Some(tree);
+        Ok(self.peers.len())
+    }
+
+    fn enforce(&self, peer: &PeerId) -> Result<(), ConnectionDenied> {
+        if self.is_allowed(peer) {
+            Ok(())
+        } else {
+            Err(ConnectionDenied::new(NotWhitelisted { peer: *peer }))
+        }
+    }
+}
+
+impl NetworkBehaviour for Whitelist {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.enforce(&peer)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: Option<PeerId>,
+        _: &[Multiaddr],
+        _: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        if let Some(peer) = peer {
+            self.enforce(&peer)?;
+        }
+        Ok(vec![])
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.enforce(&peer)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        // connected peers are disconnected by the api functions
+        Poll::Pending
+    }
+}
+
+//
+// FUNCTIONS
+//
+/// Load the whitelist and the whitelist only mode persisted in the database,
+/// later changes are persisted too.
+pub async fn libp2p_whitelist_attach(
+    wrapper: &Arc<Wrapper>,
+    db: &Arc<CustomDB>,
+) -> Result<usize, GenericError> {
+    let tree = db
+        .db
+        .open_tree(TREE_WHITELIST)
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    wrapper
+        .with_swarm(move |swarm| {
+            let count = swarm.behaviour_mut().whitelist.attach(tree)?;
+            // the persisted mode may enable the whitelist
+            disconnect_denied(swarm);
+            Ok(count)
+        })
+        .await?
+}
+
+/// Only whitelisted peers may connect when enabled, other connected peers are disconnected.
+pub async fn libp2p_set_whitelist_only(
+    wrapper: &Arc<Wrapper>,
+    enabled: bool,
+) -> Result<(), GenericError> {
+    wrapper
+        .with_swarm(move |swarm| {
+            swarm.behaviour_mut().whitelist.set_enabled(enabled)?;
+            disconnect_denied(swarm);
+            Ok(())
+        })
+        .await?
+}
+
+pub async fn libp2p_is_whitelist_only(wrapper: &Arc<Wrapper>) -> Result<bool, GenericError> {
+    wrapper
+        .with_swarm(|swarm| swarm.behaviour().whitelist.is_enabled())
+        .await
+}
+
+pub async fn libp2p_whitelist(wrapper: &Arc<Wrapper>) -> Result<Vec<String>, GenericError> {
+    wrapper
+        .with_swarm(|swarm| {
+            swarm
+                .behaviour()
+                .whitelist
+                .peers()
+                .iter()
+                .map(|f| f.to_string())
+                .collect()
+        })
+        .await
+}
+
+fn disconnect_denied(swarm: &mut Swarm<CustomBehaviour>) {
+    let denied: Vec<PeerId> = swarm
+        .connected_peers()
+        .filter(|peer| !swarm.behaviour().whitelist.is_allowed(peer))
+        .copied()
+        .collect();
+    for peer in denied {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_is_persisted() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let tree = db.open_tree(TREE_WHITELIST).unwrap();
+        let peer = PeerId::random();
+        let mut whitelist = Whitelist::new(false);
+        assert_eq!(whitelist.attach(tree.clone()).ok(), Some(0));
+        assert!(whitelist.allow_peer(peer).is_ok());
+        assert!(whitelist.set_enabled(true).is_ok());
+        // a new instance created with the mode disabled
+        let mut restored = Whitelist::new(false);
+        assert_eq!(restored.attach(tree).ok(), Some(1));
+        assert!(restored.is_enabled());
+        assert!(restored.is_allowed(&peer));
+        assert!(!restored.is_allowed(&PeerId::random()));
+    }
+}
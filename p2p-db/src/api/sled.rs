@@ -1,12 +1,18 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
 
 use flutter_rust_bridge::{frb, DartFnFuture};
-use sled::{Batch, IVec};
+use sled::{transaction::TransactionError, Batch, IVec};
 
 #[frb(external)]
 #[frb(opaque)]
 pub struct CustomDB {
-    db: sled::Db,
+    pub(crate) db: sled::Db,
+    // lock of each log tree, appends hold it so that entries are committed in sequence order
+    pub(crate) log_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
 }
 
 #[frb(external)]
@@ -37,7 +43,10 @@ pub async fn sled_db_open(
         .temporary(temporary)
         .open()
         .map_err(|e| e.to_string())?;
-    let res = Arc::new(CustomDB { db });
+    let res = Arc::new(CustomDB {
+        db,
+        log_locks: Mutex::new(HashMap::new()),
+    });
     Ok(res)
 }
 
@@ -378,7 +387,7 @@ async fn convert_keys_values_to_bytes_fn(
     }
     Ok(())
 }
-fn get_tree(db: &Arc<CustomDB>, tree: Option<String>) -> Result<sled::Tree, String> {
+pub(crate) fn get_tree(db: &Arc<CustomDB>, tree: Option<String>) -> Result<sled::Tree, String> {
     if let Some(tree_name) = tree {
         let tree = db.db.open_tree(tree_name).map_err(|e| e.to_string())?;
         Ok(tree)
@@ -387,3 +396,9 @@ fn get_tree(db: &Arc<CustomDB>, tree: Option<String>) -> Result<sled::Tree, Stri
         Ok(tree)
     }
 }
+pub(crate) fn transaction_error(error: TransactionError<String>) -> String {
+    match error {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.to_string(),
+    }
+}
@@ -0,0 +1,830 @@
+use super::{
+    p2p_common::{CustomResponseChannel, GenericError},
+    p2p_config::Libp2pRequestProtocolConfig,
+    sled::{transaction_error, CustomDB},
+    wrapper::Wrapper,
+};
+use crate::frb_generated::StreamSink;
+use flutter_rust_bridge::frb;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+use std::io::Read;
+use std::sync::Arc;
+
+//
+// CONSTANTS
+//
+const TREE_CHUNKS: &str = "__blob_chunks";
+const TREE_CHUNK_REFS: &str = "__blob_chunk_refs";
+const TREE_MANIFESTS: &str = "__blob_manifests";
+const TREE_MANIFEST_REFS: &str = "__blob_manifest_refs";
+const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+// larger chunks are refused when writing and when received from a peer
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const HASH_SIZE: usize = 32;
+// total size (u64) + chunk size (u32)
+const MANIFEST_HEADER_SIZE: usize = 12;
+/// Request-response protocol of the blob messages, registered with every instance.
+pub(crate) const BLOB_PROTOCOL: &str = "/p2p-db/blob/1.0.0";
+const BLOB_KIND_MANIFEST: u8 = 0;
+const BLOB_KIND_CHUNK: u8 = 1;
+// a put is written again when `blob_gc` collects its chunks before the manifest is committed
+const MAX_PUT_ATTEMPTS: usize = 3;
+const CHUNK_COLLECTED: &str = "blob_chunk_collected";
+
+//
+// STRUCTS
+//
+struct BlobTrees {
+    chunks: sled::Tree,
+    chunk_refs: sled::Tree,
+    manifests: sled::Tree,
+    manifest_refs: sled::Tree,
+}
+
+/// A blob is identified by the hash of its manifest,
+/// the manifest lists the hash of every chunk in order.
+struct BlobManifest {
+    size: u64,
+    chunk_size: u32,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl BlobManifest {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MANIFEST_HEADER_SIZE + self.chunks.len() * HASH_SIZE);
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&self.chunk_size.to_be_bytes());
+        for chunk in self.chunks.iter() {
+            bytes.extend_from_slice(chunk);
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<BlobManifest, String> {
+        if bytes.len() < MANIFEST_HEADER_SIZE
+            || !(bytes.len() - MANIFEST_HEADER_SIZE).is_multiple_of(HASH_SIZE)
+        {
+            return Err("blob_bad_manifest".to_string());
+        }
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&bytes[0..8]);
+        let mut chunk_size = [0u8; 4];
+        chunk_size.copy_from_slice(&bytes[8..MANIFEST_HEADER_SIZE]);
+        let manifest = BlobManifest {
+            size: u64::from_be_bytes(size),
+            chunk_size: u32::from_be_bytes(chunk_size),
+            chunks: bytes[MANIFEST_HEADER_SIZE..]
+                .chunks(HASH_SIZE)
+                .map(|e| e.to_vec())
+                .collect(),
+        };
+        // manifests of peers are only checked against their hash
+        if manifest.chunk_size == 0
+            || manifest.chunk_size as usize > MAX_CHUNK_SIZE
+            || manifest.size.div_ceil(manifest.chunk_size as u64) != manifest.chunks.len() as u64
+        {
+            return Err("blob_bad_manifest".to_string());
+        }
+        Ok(manifest)
+    }
+}
+
+/// A request (without payload) or a response exchanged with a peer.
+struct BlobMessage<'a> {
+    kind: u8,
+    hash: &'a [u8],
+    payload: Option<&'a [u8]>,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+pub struct CustomBlobInfo {
+    pub size: u64,
+    pub chunk_size: u32,
+    pub chunk_count: usize,
+    pub missing_chunks: usize,
+    pub references: u64,
+}
+
+//
+// FUNCTIONS
+//
+pub async fn blob_put(
+    db: &Arc<CustomDB>,
+    data: Vec<u8>,
+    chunk_size: Option<usize>,
+) -> Result<Vec<u8>, String> {
+    let trees = open_trees(db)?;
+    put_blob(&trees, || Ok(data.as_slice()), chunk_size)
+}
+
+pub async fn blob_put_file(
+    db: &Arc<CustomDB>,
+    path: String,
+    chunk_size: Option<usize>,
+) -> Result<Vec<u8>, String> {
+    let trees = open_trees(db)?;
+    // the file is read with blocking io
+    tokio::task::spawn_blocking(move || {
+        put_blob(
+            &trees,
+            || std::fs::File::open(&path).map_err(|e| e.to_string()),
+            chunk_size,
+        )
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+pub async fn blob_get(db: &Arc<CustomDB>, id: Vec<u8>) -> Result<Option<Vec<u8>>, String> {
+    let trees = open_trees(db)?;
+    let manifest_opt = get_manifest(&trees, &id)?;
+    match manifest_opt {
+        None => Ok(None),
+        Some(manifest) => {
+            // the size of a manifest received from a peer is not trusted
+            let mut data = Vec::new();
+            for chunk in manifest.chunks.iter() {
+                let bytes = get_chunk(&trees, chunk)?;
+                data.extend_from_slice(&bytes);
+            }
+            Ok(Some(data))
+        }
+    }
+}
+
+pub async fn blob_get_stream(
+    db: &Arc<CustomDB>,
+    id: Vec<u8>,
+    sink: StreamSink<Vec<u8>>,
+) -> Result<bool, String> {
+    let trees = open_trees(db)?;
+    let manifest_opt = get_manifest(&trees, &id)?;
+    match manifest_opt {
+        None => Ok(false),
+        Some(manifest) => {
+            for chunk in manifest.chunks.iter() {
+                let bytes = get_chunk(&trees, chunk)?;
+                sink.add(bytes.to_vec()).map_err(|e| e.to_string())?;
+            }
+            Ok(true)
+        }
+    }
+}
+
+pub async fn blob_info(db: &Arc<CustomDB>, id: Vec<u8>) -> Result<Option<CustomBlobInfo>, String> {
+    let trees = open_trees(db)?;
+    let manifest_opt = get_manifest(&trees, &id)?;
+    match manifest_opt {
+        None => Ok(None),
+        Some(manifest) => {
+            let references = trees
+                .manifest_refs
+                .get(&id)
+                .map_err(|e| e.to_string())?
+                .map(|e| decode_counter(&e))
+                .unwrap_or(0);
+            let missing_chunks = get_missing_chunks(&trees, &manifest)?.len();
+            Ok(Some(CustomBlobInfo {
+                size: manifest.size,
+                chunk_size: manifest.chunk_size,
+                chunk_count: manifest.chunks.len(),
+                missing_chunks,
+                references,
+            }))
+        }
+    }
+}
+
+pub async fn blob_missing_chunks(db: &Arc<CustomDB>, id: Vec<u8>) -> Result<Vec<Vec<u8>>, String> {
+    let trees = open_trees(db)?;
+    let manifest = get_manifest(&trees, &id)?.ok_or("blob_not_found")?;
+    get_missing_chunks(&trees, &manifest)
+}
+
+/// Increment the reference count of an existing blob.
+pub async fn blob_retain(db: &Arc<CustomDB>, id: Vec<u8>) -> Result<bool, String> {
+    let trees = open_trees(db)?;
+    let manifest_opt = get_manifest(&trees, &id)?;
+    match manifest_opt {
+        None => Ok(false),
+        Some(manifest) => {
+            commit_manifest(&trees, &manifest, true)?;
+            Ok(true)
+        }
+    }
+}
+
+/// Decrement the reference count of a blob. When no reference is left the manifest is removed,
+/// chunks are only deleted by `blob_gc`.
+pub async fn blob_release(db: &Arc<CustomDB>, id: Vec<u8>) -> Result<bool, String> {
+    let trees = open_trees(db)?;
+    let res: Result<bool, TransactionError<String>> =
+        (&trees.manifests, &trees.manifest_refs, &trees.chunk_refs).transaction(
+            |(manifests, manifest_refs, chunk_refs)| {
+                let refs = manifest_refs
+                    .get(&id)?
+                    .map(|e| decode_counter(&e))
+                    .unwrap_or(0);
+                if refs == 0 {
+                    return Ok(false);
+                }
+                if refs > 1 {
+                    manifest_refs.insert(id.as_slice(), &encode_counter(refs - 1))?;
+                    return Ok(true);
+                }
+                manifest_refs.remove(id.as_slice())?;
+                if let Some(bytes) = manifests.remove(id.as_slice())? {
+                    let manifest = BlobManifest::decode(&bytes)
+                        .map_err(ConflictableTransactionError::Abort)?;
+                    for chunk in manifest.chunks.iter() {
+                        let chunk_count = chunk_refs
+                            .get(chunk)?
+                            .map(|e| decode_counter(&e))
+                            .unwrap_or(0);
+                        if chunk_count > 1 {
+                            chunk_refs
+                                .insert(chunk.as_slice(), &encode_counter(chunk_count - 1))?;
+                        } else {
+                            chunk_refs.remove(chunk.as_slice())?;
+                        }
+                    }
+                }
+                Ok(true)
+            },
+        );
+    res.map_err(transaction_error)
+}
+
+/// Delete every chunk that is not referenced by a blob anymore.
+pub async fn blob_gc(db: &Arc<CustomDB>) -> Result<usize, String> {
+    let trees = open_trees(db)?;
+    let mut removed = 0;
+    for current in trees.chunks.iter().keys() {
+        let key = current.map_err(|e| e.to_string())?;
+        let res: Result<bool, TransactionError<String>> = (&trees.chunks, &trees.chunk_refs)
+            .transaction(|(chunks, chunk_refs)| {
+                if chunk_refs.get(&key)?.is_none() {
+                    chunks.remove(&key)?;
+                    return Ok(true);
+                }
+                Ok(false)
+            });
+        if res.map_err(transaction_error)? {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+//
+// PEER TRANSFER
+//
+/// Protocol of the `RequestMessage` events to pass to `blob_serve_request`.
+#[frb(sync)]
+pub fn blob_protocol() -> String {
+    BLOB_PROTOCOL.to_string()
+}
+
+/// Request the manifest (when unknown) or the missing chunks of a blob from a peer.
+/// Responses must be passed to `blob_handle_response`.
+pub async fn blob_fetch(
+    db: &Arc<CustomDB>,
+    wrapper: &Arc<Wrapper>,
+    peerid: String,
+    id: Vec<u8>,
+) -> Result<Vec<String>, GenericError> {
+    let peer = peerid
+        .parse::<PeerId>()
+        .map_err(|_| GenericError::BadAddress)?;
+    let trees = open_trees(db).map_err(GenericError::Other)?;
+    let requests: Vec<Vec<u8>> = match get_manifest(&trees, &id).map_err(GenericError::Other)? {
+        None => vec![encode_request(BLOB_KIND_MANIFEST, &id)],
+        Some(manifest) => get_missing_chunks(&trees, &manifest)
+            .map_err(GenericError::Other)?
+            .iter()
+            .map(|chunk| encode_request(BLOB_KIND_CHUNK, chunk))
+            .collect(),
+    };
+    let request_ids = wrapper
+        .with_swarm(move |swarm| {
+            let behaviour = swarm.behaviour_mut();
+            requests
+                .into_iter()
+                .map(|request| {
+                    behaviour
+                        .request_response
+                        .send_request(Some(BLOB_PROTOCOL), &peer, request)
+                        .map(|(_, request_id)| request_id.to_string())
+                })
+                .collect::<Option<Vec<String>>>()
+        })
+        .await?;
+    request_ids.ok_or(GenericError::UnknownProtocol)
+}
+
+/// Answer a blob request received from a peer, returns whether the manifest or chunk was found.
+pub async fn blob_serve_request(
+    db: &Arc<CustomDB>,
+    wrapper: &Arc<Wrapper>,
+    channel: &CustomResponseChannel,
+    message: Vec<u8>,
+) -> Result<bool, GenericError> {
+    let request = decode_message(&message).map_err(GenericError::Other)?;
+    let trees = open_trees(db).map_err(GenericError::Other)?;
+    let tree = match request.kind {
+        BLOB_KIND_MANIFEST => &trees.manifests,
+        BLOB_KIND_CHUNK => &trees.chunks,
+        _ => return Err(GenericError::Other("blob_bad_message".to_string())),
+    };
+    let payload = tree
+        .get(request.hash)
+        .map_err(|e| GenericError::Other(e.to_string()))?;
+    let found = payload.is_some();
+    let response = encode_response(request.kind, request.hash, payload.as_deref());
+    let Some(channel) = channel.take() else {
+        return Err(GenericError::ResponseAlreadySent);
+    };
+    wrapper
+        .with_swarm(move |swarm| {
+            swarm
+                .behaviour_mut()
+                .request_response
+                .send_response(channel, response)
+        })
+        .await?
+        .map_err(GenericError::Bytes)?;
+    Ok(found)
+}
+
+/// Store a manifest or chunk received from a peer after checking its hash.
+pub async fn blob_handle_response(db: &Arc<CustomDB>, message: Vec<u8>) -> Result<bool, String> {
+    let response = decode_message(&message)?;
+    let hash = response.hash;
+    let payload = match response.payload {
+        Some(payload) => payload,
+        None => return Ok(false),
+    };
+    if Sha256::digest(payload).as_slice() != hash {
+        return Err("blob_hash_mismatch".to_string());
+    }
+    let trees = open_trees(db)?;
+    match response.kind {
+        BLOB_KIND_MANIFEST => {
+            if trees
+                .manifests
+                .contains_key(hash)
+                .map_err(|e| e.to_string())?
+            {
+                return Ok(true);
+            }
+            let manifest = BlobManifest::decode(payload)?;
+            commit_manifest(&trees, &manifest, true)?;
+            Ok(true)
+        }
+        BLOB_KIND_CHUNK => {
+            if payload.len() > MAX_CHUNK_SIZE {
+                return Err("blob_bad_chunk".to_string());
+            }
+            // only keep chunks referenced by a known manifest
+            if !trees
+                .chunk_refs
+                .contains_key(hash)
+                .map_err(|e| e.to_string())?
+            {
+                return Ok(false);
+            }
+            trees
+                .chunks
+                .insert(hash, payload)
+                .map_err(|e| e.to_string())?;
+            Ok(true)
+        }
+        _ => Err("blob_bad_message".to_string()),
+    }
+}
+
+//
+// HELPERS
+//
+/// Limits of the blob protocol, used unless it is configured.
+pub(crate) fn blob_protocol_config() -> Libp2pRequestProtocolConfig {
+    Libp2pRequestProtocolConfig {
+        name: BLOB_PROTOCOL.to_string(),
+        request_timeout: 30_000,
+        max_concurrent_streams: 100,
+        max_request_size: 1024,
+        // a chunk or a manifest in a cbor array, bytes above 23 take two bytes
+        max_response_size: (2 * MAX_CHUNK_SIZE + 1024) as u64,
+    }
+}
+
+fn open_trees(db: &Arc<CustomDB>) -> Result<BlobTrees, String> {
+    Ok(BlobTrees {
+        chunks: db.db.open_tree(TREE_CHUNKS).map_err(|e| e.to_string())?,
+        chunk_refs: db
+            .db
+            .open_tree(TREE_CHUNK_REFS)
+            .map_err(|e| e.to_string())?,
+        manifests: db.db.open_tree(TREE_MANIFESTS).map_err(|e| e.to_string())?,
+        manifest_refs: db
+            .db
+            .open_tree(TREE_MANIFEST_REFS)
+            .map_err(|e| e.to_string())?,
+    })
+}
+
+fn get_manifest(trees: &BlobTrees, id: &[u8]) -> Result<Option<BlobManifest>, String> {
+    let bytes_opt = trees.manifests.get(id).map_err(|e| e.to_string())?;
+    match bytes_opt {
+        None => Ok(None),
+        Some(bytes) => BlobManifest::decode(&bytes).map(Some),
+    }
+}
+
+fn get_chunk(trees: &BlobTrees, hash: &[u8]) -> Result<sled::IVec, String> {
+    trees
+        .chunks
+        .get(hash)
+        .map_err(|e| e.to_string())?
+        .ok_or("blob_incomplete".to_string())
+}
+
+fn get_missing_chunks(trees: &BlobTrees, manifest: &BlobManifest) -> Result<Vec<Vec<u8>>, String> {
+    let mut missing: Vec<Vec<u8>> = Vec::new();
+    for chunk in manifest.chunks.iter() {
+        let exists = trees
+            .chunks
+            .contains_key(chunk)
+            .map_err(|e| e.to_string())?;
+        if !exists && !missing.contains(chunk) {
+            missing.push(chunk.clone());
+        }
+    }
+    Ok(missing)
+}
+
+/// Write the chunks and commit the manifest, the chunks are written again when they are
+/// collected before the commit.
+fn put_blob<R: Read>(
+    trees: &BlobTrees,
+    mut open: impl FnMut() -> Result<R, String>,
+    chunk_size: Option<usize>,
+) -> Result<Vec<u8>, String> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let manifest = put_chunks(trees, open()?, chunk_size)?;
+        match commit_manifest(trees, &manifest, false) {
+            Err(e) if e == CHUNK_COLLECTED && attempts < MAX_PUT_ATTEMPTS => continue,
+            res => return res,
+        }
+    }
+}
+
+/// Write the chunks without taking any reference, unreferenced chunks are collected by `blob_gc`
+/// if the manifest is never committed.
+fn put_chunks(
+    trees: &BlobTrees,
+    mut reader: impl Read,
+    chunk_size: Option<usize>,
+) -> Result<BlobManifest, String> {
+    let real_chunk_size = chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1);
+    if real_chunk_size > MAX_CHUNK_SIZE {
+        return Err("blob_bad_chunk_size".to_string());
+    }
+    let mut buffer = vec![0u8; real_chunk_size];
+    let mut manifest = BlobManifest {
+        size: 0,
+        chunk_size: real_chunk_size as u32,
+        chunks: Vec::new(),
+    };
+    loop {
+        let read = read_chunk(&mut reader, &mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
+        }
+        let chunk = &buffer[..read];
+        let hash = Sha256::digest(chunk).to_vec();
+        if !trees
+            .chunks
+            .contains_key(&hash)
+            .map_err(|e| e.to_string())?
+        {
+            trees
+                .chunks
+                .insert(hash.as_slice(), chunk)
+                .map_err(|e| e.to_string())?;
+        }
+        manifest.size += read as u64;
+        manifest.chunks.push(hash);
+    }
+    Ok(manifest)
+}
+
+/// Take a reference on the manifest, the first reference also takes a reference on each chunk.
+fn commit_manifest(
+    trees: &BlobTrees,
+    manifest: &BlobManifest,
+    allow_missing_chunks: bool,
+) -> Result<Vec<u8>, String> {
+    let encoded = manifest.encode();
+    let id = Sha256::digest(&encoded).to_vec();
+    let res: Result<(), TransactionError<String>> = (
+        &trees.manifests,
+        &trees.manifest_refs,
+        &trees.chunks,
+        &trees.chunk_refs,
+    )
+        .transaction(|(manifests, manifest_refs, chunks, chunk_refs)| {
+            let refs = manifest_refs
+                .get(&id)?
+                .map(|e| decode_counter(&e))
+                .unwrap_or(0);
+            if refs == 0 {
+                for chunk in manifest.chunks.iter() {
+                    if !allow_missing_chunks && chunks.get(chunk)?.is_none() {
+                        // the chunk has been collected while writing the blob
+                        return Err(ConflictableTransactionError::Abort(
+                            CHUNK_COLLECTED.to_string(),
+                        ));
+                    }
+                    let chunk_count = chunk_refs
+                        .get(chunk)?
+                        .map(|e| decode_counter(&e))
+                        .unwrap_or(0);
+                    chunk_refs.insert(chunk.as_slice(), &encode_counter(chunk_count + 1))?;
+                }
+                manifests.insert(id.as_slice(), encoded.as_slice())?;
+            }
+            manifest_refs.insert(id.as_slice(), &encode_counter(refs + 1))?;
+            Ok(())
+        });
+    res.map_err(transaction_error)?;
+    Ok(id)
+}
+
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let read = reader.read(&mut buffer[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    Ok(filled)
+}
+
+fn encode_counter(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+fn decode_counter(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(value)
+}
+
+fn encode_request(kind: u8, hash: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(1 + HASH_SIZE);
+    bytes.push(kind);
+    bytes.extend_from_slice(hash);
+    bytes
+}
+
+fn encode_response(kind: u8, hash: &[u8], payload: Option<&[u8]>) -> Vec<u8> {
+    let mut bytes = encode_request(kind, hash);
+    match payload {
+        Some(payload) => {
+            bytes.push(1);
+            bytes.extend_from_slice(payload);
+        }
+        None => bytes.push(0),
+    }
+    bytes
+}
+
+fn decode_message(message: &[u8]) -> Result<BlobMessage<'_>, String> {
+    let header = 1 + HASH_SIZE;
+    if message.len() < header {
+        return Err("blob_bad_message".to_string());
+    }
+    let kind = message[0];
+    if kind != BLOB_KIND_MANIFEST && kind != BLOB_KIND_CHUNK {
+        return Err("blob_bad_message".to_string());
+    }
+    let hash = &message[1..header];
+    let payload = match message.get(header) {
+        Some(1) => Some(&message[header + 1..]),
+        _ => None,
+    };
+    Ok(BlobMessage {
+        kind,
+        hash,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::p2p_common::CustomSwarmEvent;
+    use crate::api::sled::sled_db_open;
+    use crate::test_utils::{connected_pair, TemporaryPath};
+    use std::time::Duration;
+
+    /// Removed by sled when the last reference is dropped.
+    async fn temporary_db(name: &str) -> Arc<CustomDB> {
+        let path = std::env::temp_dir().join(format!("sled_blob_{}_{}", name, std::process::id()));
+        sled_db_open(path.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap()
+    }
+
+    /// Simulate `blob_gc` running between the chunk writes and the manifest commit.
+    struct CollectOnEnd<'a> {
+        data: &'a [u8],
+        chunks: Option<sled::Tree>,
+    }
+
+    impl Read for CollectOnEnd<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let read = self.data.read(buf)?;
+            if read == 0 {
+                if let Some(chunks) = self.chunks.take() {
+                    chunks.clear().unwrap();
+                }
+            }
+            Ok(read)
+        }
+    }
+
+    #[tokio::test]
+    async fn put_get_chunks() {
+        let db = temporary_db("put_get_chunks").await;
+        let data: Vec<u8> = (0..1000u32).map(|e| e as u8).collect();
+        let id = blob_put(&db, data.clone(), Some(64)).await.unwrap();
+        assert_eq!(blob_get(&db, id.clone()).await.unwrap(), Some(data.clone()));
+        let info = blob_info(&db, id.clone()).await.unwrap().unwrap();
+        assert_eq!(info.size, 1000);
+        assert_eq!(info.chunk_size, 64);
+        assert_eq!(info.chunk_count, 16);
+        assert_eq!(info.missing_chunks, 0);
+        assert_eq!(info.references, 1);
+        // same content same id
+        let other = blob_put(&db, data, Some(64)).await.unwrap();
+        assert_eq!(other, id);
+        assert_eq!(blob_info(&db, id).await.unwrap().unwrap().references, 2);
+    }
+
+    #[tokio::test]
+    async fn put_file() {
+        let db = temporary_db("put_file").await;
+        let file = TemporaryPath::new("blob_put_file");
+        std::fs::write(&file.0, b"file content").unwrap();
+        let id = blob_put_file(&db, file.0.to_string_lossy().to_string(), Some(4))
+            .await
+            .unwrap();
+        assert_eq!(
+            blob_get(&db, id).await.unwrap(),
+            Some(b"file content".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn gc_keeps_shared_chunks() {
+        let db = temporary_db("gc_keeps_shared_chunks").await;
+        let first = blob_put(&db, b"aaaabbbb".to_vec(), Some(4)).await.unwrap();
+        let second = blob_put(&db, b"aaaacccc".to_vec(), Some(4)).await.unwrap();
+        assert_eq!(blob_gc(&db).await.unwrap(), 0);
+        assert!(blob_release(&db, first.clone()).await.unwrap());
+        assert!(!blob_release(&db, first.clone()).await.unwrap());
+        // only "bbbb" is not referenced anymore
+        assert_eq!(blob_gc(&db).await.unwrap(), 1);
+        assert_eq!(blob_get(&db, first).await.unwrap(), None);
+        assert_eq!(
+            blob_get(&db, second).await.unwrap(),
+            Some(b"aaaacccc".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn put_retries_collected_chunks() {
+        let db = temporary_db("put_retries_collected_chunks").await;
+        let trees = open_trees(&db).unwrap();
+        let data = b"collected while writing".to_vec();
+        let mut chunks = Some(trees.chunks.clone());
+        let id = put_blob(
+            &trees,
+            || {
+                Ok(CollectOnEnd {
+                    data: data.as_slice(),
+                    chunks: chunks.take(),
+                })
+            },
+            Some(4),
+        )
+        .unwrap();
+        assert_eq!(blob_get(&db, id).await.unwrap(), Some(data));
+    }
+
+    #[tokio::test]
+    async fn unknown_kind_rejected() {
+        let db = temporary_db("unknown_kind_rejected").await;
+        let response = encode_response(7, &[0u8; HASH_SIZE], Some(b"payload"));
+        assert_eq!(
+            blob_handle_response(&db, response).await,
+            Err("blob_bad_message".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn chunk_size_is_bounded() {
+        let db = temporary_db("chunk_size_is_bounded").await;
+        assert_eq!(
+            blob_put(&db, vec![0u8; 8], Some(MAX_CHUNK_SIZE + 1)).await,
+            Err("blob_bad_chunk_size".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn inconsistent_manifest_rejected() {
+        let db = temporary_db("inconsistent_manifest_rejected").await;
+        // a single chunk can not hold that many bytes
+        let manifest = BlobManifest {
+            size: u64::MAX,
+            chunk_size: 4,
+            chunks: vec![vec![0u8; HASH_SIZE]],
+        }
+        .encode();
+        let id = Sha256::digest(&manifest).to_vec();
+        let response = encode_response(BLOB_KIND_MANIFEST, &id, Some(&manifest));
+        assert_eq!(
+            blob_handle_response(&db, response).await,
+            Err("blob_bad_manifest".to_string())
+        );
+        assert_eq!(blob_get(&db, id).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fetch_over_the_blob_protocol() {
+        let (fetcher, owner) = connected_pair().await;
+        let (fetcher_db, owner_db) = (
+            temporary_db("fetch_fetcher").await,
+            temporary_db("fetch_owner").await,
+        );
+        let data: Vec<u8> = (0..100u32).map(|e| e as u8).collect();
+        let id = blob_put(&owner_db, data.clone(), Some(16)).await.unwrap();
+        let mut requests = owner.subscribe();
+        let serving = owner.clone();
+        tokio::spawn(async move {
+            while let Ok(event) = requests.recv().await {
+                if let CustomSwarmEvent::RequestMessage {
+                    protocol,
+                    message,
+                    channel,
+                    ..
+                } = event
+                {
+                    assert_eq!(protocol, BLOB_PROTOCOL);
+                    let channel = channel.read().await;
+                    assert!(blob_serve_request(&owner_db, &serving, &channel, message)
+                        .await
+                        .ok()
+                        .unwrap());
+                }
+            }
+        });
+        let mut responses = fetcher.subscribe();
+        let peer = owner.peer_id().to_string();
+        // the manifest first, then its chunks
+        for expected in [1, 7] {
+            let sent = blob_fetch(&fetcher_db, &fetcher, peer.clone(), id.clone())
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(sent.len(), expected);
+            for _ in 0..expected {
+                let message = tokio::time::timeout(Duration::from_secs(10), async {
+                    loop {
+                        if let Ok(CustomSwarmEvent::ResponseMessage {
+                            protocol, message, ..
+                        }) = responses.recv().await
+                        {
+                            assert_eq!(protocol, BLOB_PROTOCOL);
+                            return message;
+                        }
+                    }
+                })
+                .await
+                .unwrap();
+                assert!(blob_handle_response(&fetcher_db, message).await.unwrap());
+            }
+        }
+        assert_eq!(blob_get(&fetcher_db, id).await.unwrap(), Some(data));
+    }
+}
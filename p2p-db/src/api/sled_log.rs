@@ -0,0 +1,266 @@
+use super::sled::{get_tree, CustomDB};
+use crate::frb_generated::StreamSink;
+use flutter_rust_bridge::frb;
+use sled::{Batch, Event};
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+#[frb(external)]
+#[frb(non_opaque)]
+pub struct CustomLogEntry {
+    pub seq: u64,
+    pub data: Vec<u8>,
+}
+
+//
+// FUNCTIONS
+//
+pub async fn log_append(db: &Arc<CustomDB>, tree: String, data: Vec<u8>) -> Result<u64, String> {
+    let lock = tree_lock(db, &tree)?;
+    let tree_safe = get_tree(db, Some(tree))?;
+    // a concurrent append could otherwise insert a smaller seq after a greater one
+    let _guard = lock.lock().map_err(|e| e.to_string())?;
+    // ids are monotonic across restarts
+    let seq = db.db.generate_id().map_err(|e| e.to_string())?;
+    tree_safe
+        .insert(seq.to_be_bytes(), data)
+        .map_err(|e| e.to_string())?;
+    Ok(seq)
+}
+
+pub async fn log_read_from(
+    db: &Arc<CustomDB>,
+    tree: String,
+    seq: u64,
+    limit: Option<usize>,
+) -> Result<Vec<CustomLogEntry>, String> {
+    let tree_safe = get_tree(db, Some(tree))?;
+    let real_limit = limit.unwrap_or(0);
+    let mut result: Vec<CustomLogEntry> = Vec::new();
+    for current in tree_safe.range(seq.to_be_bytes()..) {
+        if 0 != real_limit && result.len() >= real_limit {
+            break;
+        }
+        let (key, value) = current.map_err(|e| e.to_string())?;
+        result.push(CustomLogEntry {
+            seq: decode_seq(&key)?,
+            data: value.to_vec(),
+        });
+    }
+    Ok(result)
+}
+
+pub async fn log_last_seq(db: &Arc<CustomDB>, tree: String) -> Result<Option<u64>, String> {
+    let tree_safe = get_tree(db, Some(tree))?;
+    let last = tree_safe.last().map_err(|e| e.to_string())?;
+    last.map(|(key, _)| decode_seq(&key)).transpose()
+}
+
+/// Remove every entry strictly before `seq`, returns the number of removed entries.
+pub async fn log_truncate_before(
+    db: &Arc<CustomDB>,
+    tree: String,
+    seq: u64,
+) -> Result<usize, String> {
+    let tree_safe = get_tree(db, Some(tree))?;
+    let mut batch = Batch::default();
+    let mut count = 0;
+    for current in tree_safe.range(..seq.to_be_bytes()).keys() {
+        let key = current.map_err(|e| e.to_string())?;
+        batch.remove(key);
+        count += 1;
+    }
+    tree_safe.apply_batch(batch).map_err(|e| e.to_string())?;
+    Ok(count)
+}
+
+/// Push every entry starting at `from_seq` (or only new entries when none) to the sink,
+/// until the token is cancelled or the sink is closed.
+pub async fn log_subscribe(
+    db: &Arc<CustomDB>,
+    tree: String,
+    from_seq: Option<u64>,
+    cancellation_token: &CancellationToken,
+    sink: StreamSink<CustomLogEntry>,
+) -> Result<bool, String> {
+    let tree_safe = get_tree(db, Some(tree))?;
+    subscribe_entries(&tree_safe, from_seq, cancellation_token, |entry| {
+        sink.add(entry).map_err(|e| e.to_string())
+    })
+    .await
+}
+
+// keys that are not sequences were not written by `log_append`, they are skipped
+async fn subscribe_entries(
+    tree: &sled::Tree,
+    from_seq: Option<u64>,
+    cancellation_token: &CancellationToken,
+    mut on_entry: impl FnMut(CustomLogEntry) -> Result<(), String>,
+) -> Result<bool, String> {
+    // subscribe before replaying so that no entry is missed in between
+    let mut subscriber = tree.watch_prefix(vec![]);
+    let mut last_seq: Option<u64> = None;
+    if let Some(seq) = from_seq {
+        for current in tree.range(seq.to_be_bytes()..) {
+            let (key, value) = current.map_err(|e| e.to_string())?;
+            let Ok(entry_seq) = decode_seq(&key) else {
+                continue;
+            };
+            on_entry(CustomLogEntry {
+                seq: entry_seq,
+                data: value.to_vec(),
+            })?;
+            last_seq = Some(entry_seq);
+        }
+    }
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => {
+                return Ok(false);
+            },
+            event_opt = &mut subscriber => {
+                match event_opt {
+                    None => return Ok(true),
+                    Some(Event::Insert { key, value }) => {
+                        let Ok(entry_seq) = decode_seq(&key) else {
+                            continue;
+                        };
+                        // skip entries already sent while replaying
+                        if last_seq.is_none_or(|last| entry_seq > last) {
+                            on_entry(CustomLogEntry {
+                                seq: entry_seq,
+                                data: value.to_vec(),
+                            })?;
+                            last_seq = Some(entry_seq);
+                        }
+                    }
+                    Some(Event::Remove { .. }) => {}
+                }
+            }
+        }
+    }
+}
+
+fn tree_lock(db: &CustomDB, tree: &str) -> Result<Arc<Mutex<()>>, String> {
+    let mut locks = db.log_locks.lock().map_err(|e| e.to_string())?;
+    Ok(locks.entry(tree.to_string()).or_default().clone())
+}
+
+fn decode_seq(key: &[u8]) -> Result<u64, String> {
+    let seq: [u8; 8] = key.try_into().map_err(|_| "log_bad_key".to_string())?;
+    Ok(u64::from_be_bytes(seq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sled::sled_db_open;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn append_commits_in_order() {
+        let path = std::env::temp_dir().join(format!("sled_log_order_{}", std::process::id()));
+        let db = sled_db_open(path.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap();
+        let tree = "log".to_string();
+        let subscriber = get_tree(&db, Some(tree.clone()))
+            .unwrap()
+            .watch_prefix(vec![]);
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                let tree = tree.clone();
+                tokio::spawn(async move {
+                    for _ in 0..50 {
+                        log_append(&db, tree.clone(), vec![1]).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let seqs: Vec<u64> = subscriber
+            .take(200)
+            .map(|event| match event {
+                Event::Insert { key, .. } => decode_seq(&key).unwrap(),
+                Event::Remove { .. } => panic!("unexpected remove"),
+            })
+            .collect();
+        assert!(seqs.windows(2).all(|e| e[0] < e[1]));
+        let entries = log_read_from(&db, tree.clone(), 0, None).await.unwrap();
+        assert_eq!(entries.len(), 200);
+        assert_eq!(log_last_seq(&db, tree).await.unwrap(), seqs.last().copied());
+    }
+
+    #[tokio::test]
+    async fn truncate_removes_older_entries() {
+        let path = std::env::temp_dir().join(format!("sled_log_truncate_{}", std::process::id()));
+        let db = sled_db_open(path.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap();
+        let tree = "log".to_string();
+        let mut seqs = vec![];
+        for i in 0..5u8 {
+            seqs.push(log_append(&db, tree.clone(), vec![i]).await.unwrap());
+        }
+        let removed = log_truncate_before(&db, tree.clone(), seqs[2]).await;
+        assert_eq!(removed, Ok(2));
+        let entries = log_read_from(&db, tree.clone(), 0, None).await.unwrap();
+        let remaining: Vec<u64> = entries.iter().map(|e| e.seq).collect();
+        assert_eq!(remaining, seqs[2..]);
+        assert_eq!(log_truncate_before(&db, tree, seqs[2]).await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn subscribe_replays_then_follows() {
+        let path = std::env::temp_dir().join(format!("sled_log_subscribe_{}", std::process::id()));
+        let db = sled_db_open(path.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap();
+        let tree = "log".to_string();
+        let mut seqs = vec![];
+        for i in 0..3u8 {
+            seqs.push(log_append(&db, tree.clone(), vec![i]).await.unwrap());
+        }
+        let tree_safe = get_tree(&db, Some(tree.clone())).unwrap();
+        // a key that is not a sequence is skipped during the replay and the watch
+        tree_safe.insert(vec![0xff], vec![9]).unwrap();
+        let token = CancellationToken::new();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let task = {
+            let token = token.clone();
+            let from = seqs[1];
+            tokio::spawn(async move {
+                subscribe_entries(&tree_safe, Some(from), &token, |entry| {
+                    sender.send(entry).map_err(|e| e.to_string())
+                })
+                .await
+            })
+        };
+        let mut received = vec![];
+        for _ in 0..2 {
+            received.push(receiver.recv().await.unwrap());
+        }
+        get_tree(&db, Some(tree.clone()))
+            .unwrap()
+            .insert(vec![1, 2], vec![9])
+            .unwrap();
+        seqs.push(log_append(&db, tree.clone(), vec![3]).await.unwrap());
+        received.push(receiver.recv().await.unwrap());
+        let got: Vec<(u64, Vec<u8>)> = received.into_iter().map(|e| (e.seq, e.data)).collect();
+        assert_eq!(
+            got,
+            vec![(seqs[1], vec![1]), (seqs[2], vec![2]), (seqs[3], vec![3])]
+        );
+        token.cancel();
+        assert_eq!(task.await.unwrap(), Ok(false));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn decode_short_key() {
+        assert_eq!(decode_seq(&[1, 2, 3]), Err("log_bad_key".to_string()));
+        assert_eq!(decode_seq(&7u64.to_be_bytes()), Ok(7));
+    }
+}
@@ -0,0 +1,652 @@
+use super::sled::{transaction_error, CustomDB};
+use flutter_rust_bridge::frb;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
+use sled::Transactional;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+// priority (u8) + attempts (u32) + max attempts (u32)
+const RECORD_HEADER_SIZE: usize = 9;
+
+//
+// STRUCTS
+//
+struct QueueTrees {
+    // id => record
+    jobs: sled::Tree,
+    // inverted priority + id => ()
+    ready: sled::Tree,
+    // available at + id => ()
+    delayed: sled::Tree,
+    // id => lease deadline + lease token
+    in_flight: sled::Tree,
+    // id => record
+    dead: sled::Tree,
+}
+
+struct QueueRecord {
+    priority: u8,
+    attempts: u32,
+    max_attempts: u32,
+    payload: Vec<u8>,
+}
+
+impl QueueRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RECORD_HEADER_SIZE + self.payload.len());
+        bytes.push(self.priority);
+        bytes.extend_from_slice(&self.attempts.to_be_bytes());
+        bytes.extend_from_slice(&self.max_attempts.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<QueueRecord, String> {
+        if bytes.len() < RECORD_HEADER_SIZE {
+            return Err("queue_bad_record".to_string());
+        }
+        let mut attempts = [0u8; 4];
+        attempts.copy_from_slice(&bytes[1..5]);
+        let mut max_attempts = [0u8; 4];
+        max_attempts.copy_from_slice(&bytes[5..RECORD_HEADER_SIZE]);
+        Ok(QueueRecord {
+            priority: bytes[0],
+            attempts: u32::from_be_bytes(attempts),
+            max_attempts: u32::from_be_bytes(max_attempts),
+            payload: bytes[RECORD_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    fn into_job(self, id: u64, lease: Option<u64>) -> CustomQueueJob {
+        CustomQueueJob {
+            id,
+            lease,
+            payload: self.payload,
+            priority: self.priority,
+            attempts: self.attempts,
+            max_attempts: self.max_attempts,
+        }
+    }
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+pub struct CustomQueueJob {
+    pub id: u64,
+    /// Token of the lease taken by `queue_dequeue`, required to ack or nack the job.
+    pub lease: Option<u64>,
+    pub payload: Vec<u8>,
+    pub priority: u8,
+    pub attempts: u32,
+    pub max_attempts: u32,
+}
+
+#[frb(external)]
+#[frb(non_opaque)]
+pub struct CustomQueueSize {
+    pub ready: usize,
+    pub delayed: usize,
+    pub in_flight: usize,
+    pub dead: usize,
+}
+
+//
+// FUNCTIONS
+//
+/// Jobs with a higher priority are dequeued first, jobs with the same priority in FIFO order.
+pub async fn queue_enqueue(
+    db: &Arc<CustomDB>,
+    queue: String,
+    payload: Vec<u8>,
+    priority: Option<u8>,
+    delay_ms: Option<u64>,
+    max_attempts: Option<u32>,
+) -> Result<u64, String> {
+    let trees = open_trees(db, &queue)?;
+    let id = db.db.generate_id().map_err(|e| e.to_string())?;
+    let record = QueueRecord {
+        priority: priority.unwrap_or(0),
+        attempts: 0,
+        max_attempts: max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+        payload,
+    };
+    let available_at = delay_ms.map(|delay| now_millis().saturating_add(delay));
+    let res: Result<(), TransactionError<String>> = (&trees.jobs, &trees.ready, &trees.delayed)
+        .transaction(|(jobs, ready, delayed)| {
+            jobs.insert(&id.to_be_bytes(), record.encode())?;
+            schedule(ready, delayed, id, record.priority, available_at)?;
+            Ok(())
+        });
+    res.map_err(transaction_error)?;
+    Ok(id)
+}
+
+/// Lease the next ready job, the job is made visible again if it is not acked before
+/// `visibility_timeout_ms`.
+pub async fn queue_dequeue(
+    db: &Arc<CustomDB>,
+    queue: String,
+    visibility_timeout_ms: u64,
+) -> Result<Option<CustomQueueJob>, String> {
+    let trees = open_trees(db, &queue)?;
+    let now = now_millis();
+    release_expired_leases(&trees, now)?;
+    promote_delayed(&trees, now)?;
+    let deadline = now.saturating_add(visibility_timeout_ms);
+    // a job leased again after its lease expired gets a new token
+    let lease = db.db.generate_id().map_err(|e| e.to_string())?;
+    let lease_value = lease_value(deadline, lease);
+    for current in trees.ready.iter().keys() {
+        let ready_key = current.map_err(|e| e.to_string())?;
+        let id = decode_u64(&ready_key[1..]);
+        let res: Result<Option<CustomQueueJob>, TransactionError<String>> =
+            (&trees.jobs, &trees.ready, &trees.in_flight).transaction(
+                |(jobs, ready, in_flight)| {
+                    // another consumer may have leased the job since the scan
+                    if ready.remove(&ready_key)?.is_none() {
+                        return Ok(None);
+                    }
+                    let record = get_record(jobs, id)?;
+                    in_flight.insert(&id.to_be_bytes(), &lease_value)?;
+                    Ok(Some(record.into_job(id, Some(lease))))
+                },
+            );
+        if let Some(job) = res.map_err(transaction_error)? {
+            return Ok(Some(job));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns false when the lease is not held anymore (expired, acked or nacked).
+pub async fn queue_ack(
+    db: &Arc<CustomDB>,
+    queue: String,
+    id: u64,
+    lease: u64,
+) -> Result<bool, String> {
+    let trees = open_trees(db, &queue)?;
+    let res: Result<bool, TransactionError<String>> =
+        (&trees.jobs, &trees.in_flight).transaction(|(jobs, in_flight)| {
+            if !remove_lease(in_flight, id, lease)? {
+                return Ok(false);
+            }
+            jobs.remove(&id.to_be_bytes())?;
+            Ok(true)
+        });
+    res.map_err(transaction_error)
+}
+
+/// Give a leased job back, it is retried after `delay_ms` or moved to the dead letters
+/// once it reached its max attempts.
+pub async fn queue_nack(
+    db: &Arc<CustomDB>,
+    queue: String,
+    id: u64,
+    lease: u64,
+    delay_ms: Option<u64>,
+) -> Result<bool, String> {
+    let trees = open_trees(db, &queue)?;
+    let available_at = delay_ms.map(|delay| now_millis().saturating_add(delay));
+    let res: Result<bool, TransactionError<String>> = (
+        &trees.jobs,
+        &trees.ready,
+        &trees.delayed,
+        &trees.in_flight,
+        &trees.dead,
+    )
+        .transaction(|(jobs, ready, delayed, in_flight, dead)| {
+            if !remove_lease(in_flight, id, lease)? {
+                return Ok(false);
+            }
+            fail(jobs, ready, delayed, dead, id, available_at)?;
+            Ok(true)
+        });
+    res.map_err(transaction_error)
+}
+
+pub async fn queue_peek(
+    db: &Arc<CustomDB>,
+    queue: String,
+    limit: Option<usize>,
+) -> Result<Vec<CustomQueueJob>, String> {
+    let trees = open_trees(db, &queue)?;
+    let real_limit = limit.unwrap_or(0);
+    let mut result: Vec<CustomQueueJob> = Vec::new();
+    for current in trees.ready.iter().keys() {
+        if 0 != real_limit && result.len() >= real_limit {
+            break;
+        }
+        let ready_key = current.map_err(|e| e.to_string())?;
+        let id = decode_u64(&ready_key[1..]);
+        if let Some(bytes) = trees
+            .jobs
+            .get(id.to_be_bytes())
+            .map_err(|e| e.to_string())?
+        {
+            result.push(QueueRecord::decode(&bytes)?.into_job(id, None));
+        }
+    }
+    Ok(result)
+}
+
+pub async fn queue_size(db: &Arc<CustomDB>, queue: String) -> Result<CustomQueueSize, String> {
+    let trees = open_trees(db, &queue)?;
+    // expired leases and due jobs are counted as ready
+    let now = now_millis();
+    release_expired_leases(&trees, now)?;
+    promote_delayed(&trees, now)?;
+    Ok(CustomQueueSize {
+        ready: trees.ready.len(),
+        delayed: trees.delayed.len(),
+        in_flight: trees.in_flight.len(),
+        dead: trees.dead.len(),
+    })
+}
+
+pub async fn queue_dead_letters(
+    db: &Arc<CustomDB>,
+    queue: String,
+    limit: Option<usize>,
+) -> Result<Vec<CustomQueueJob>, String> {
+    let trees = open_trees(db, &queue)?;
+    let real_limit = limit.unwrap_or(0);
+    let mut result: Vec<CustomQueueJob> = Vec::new();
+    for current in trees.dead.iter() {
+        if 0 != real_limit && result.len() >= real_limit {
+            break;
+        }
+        let (key, value) = current.map_err(|e| e.to_string())?;
+        result.push(QueueRecord::decode(&value)?.into_job(decode_u64(&key), None));
+    }
+    Ok(result)
+}
+
+/// Move a dead letter back to the ready jobs with its attempts reset.
+pub async fn queue_requeue_dead(
+    db: &Arc<CustomDB>,
+    queue: String,
+    id: u64,
+) -> Result<bool, String> {
+    let trees = open_trees(db, &queue)?;
+    let res: Result<bool, TransactionError<String>> =
+        (&trees.jobs, &trees.ready, &trees.delayed, &trees.dead).transaction(
+            |(jobs, ready, delayed, dead)| {
+                let bytes = match dead.remove(&id.to_be_bytes())? {
+                    Some(bytes) => bytes,
+                    None => return Ok(false),
+                };
+                let mut record =
+                    QueueRecord::decode(&bytes).map_err(ConflictableTransactionError::Abort)?;
+                record.attempts = 0;
+                jobs.insert(&id.to_be_bytes(), record.encode())?;
+                schedule(ready, delayed, id, record.priority, None)?;
+                Ok(true)
+            },
+        );
+    res.map_err(transaction_error)
+}
+
+pub async fn queue_purge_dead(db: &Arc<CustomDB>, queue: String) -> Result<usize, String> {
+    let trees = open_trees(db, &queue)?;
+    let len = trees.dead.len();
+    trees.dead.clear().map_err(|e| e.to_string())?;
+    Ok(len)
+}
+
+//
+// HELPERS
+//
+fn open_trees(db: &Arc<CustomDB>, queue: &str) -> Result<QueueTrees, String> {
+    let open = |suffix: &str| {
+        db.db
+            .open_tree(format!("__queue_{}_{}", queue, suffix))
+            .map_err(|e| e.to_string())
+    };
+    Ok(QueueTrees {
+        jobs: open("jobs")?,
+        ready: open("ready")?,
+        delayed: open("delayed")?,
+        in_flight: open("in_flight")?,
+        dead: open("dead")?,
+    })
+}
+
+/// Leases that were not acked in time count as a failed attempt.
+fn release_expired_leases(trees: &QueueTrees, now: u64) -> Result<(), String> {
+    for current in trees.in_flight.iter() {
+        let (key, deadline) = current.map_err(|e| e.to_string())?;
+        if decode_u64(&deadline) > now {
+            continue;
+        }
+        let id = decode_u64(&key);
+        let res: Result<(), TransactionError<String>> = (
+            &trees.jobs,
+            &trees.ready,
+            &trees.delayed,
+            &trees.in_flight,
+            &trees.dead,
+        )
+            .transaction(|(jobs, ready, delayed, in_flight, dead)| {
+                // the job may have been acked since the scan
+                match in_flight.get(&key)? {
+                    Some(current_deadline) if current_deadline == deadline => {}
+                    _ => return Ok(()),
+                }
+                in_flight.remove(&key)?;
+                fail(jobs, ready, delayed, dead, id, None)
+            });
+        res.map_err(transaction_error)?;
+    }
+    Ok(())
+}
+
+fn promote_delayed(trees: &QueueTrees, now: u64) -> Result<(), String> {
+    let end = delayed_key(now, u64::MAX);
+    for current in trees.delayed.range(..=end).keys() {
+        let key = current.map_err(|e| e.to_string())?;
+        let id = decode_u64(&key[8..]);
+        let res: Result<(), TransactionError<String>> = (&trees.jobs, &trees.ready, &trees.delayed)
+            .transaction(|(jobs, ready, delayed)| {
+                if delayed.remove(&key)?.is_none() {
+                    return Ok(());
+                }
+                let record = get_record(jobs, id)?;
+                ready.insert(&ready_key(record.priority, id), &[])?;
+                Ok(())
+            });
+        res.map_err(transaction_error)?;
+    }
+    Ok(())
+}
+
+fn fail(
+    jobs: &TransactionalTree,
+    ready: &TransactionalTree,
+    delayed: &TransactionalTree,
+    dead: &TransactionalTree,
+    id: u64,
+    available_at: Option<u64>,
+) -> ConflictableTransactionResult<(), String> {
+    let mut record = get_record(jobs, id)?;
+    record.attempts += 1;
+    if record.attempts >= record.max_attempts {
+        jobs.remove(&id.to_be_bytes())?;
+        dead.insert(&id.to_be_bytes(), record.encode())?;
+    } else {
+        jobs.insert(&id.to_be_bytes(), record.encode())?;
+        schedule(ready, delayed, id, record.priority, available_at)?;
+    }
+    Ok(())
+}
+
+fn remove_lease(
+    in_flight: &TransactionalTree,
+    id: u64,
+    lease: u64,
+) -> ConflictableTransactionResult<bool, String> {
+    match in_flight.get(id.to_be_bytes())? {
+        Some(value) if value.len() == 16 && decode_u64(&value[8..]) == lease => {
+            in_flight.remove(&id.to_be_bytes())?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn schedule(
+    ready: &TransactionalTree,
+    delayed: &TransactionalTree,
+    id: u64,
+    priority: u8,
+    available_at: Option<u64>,
+) -> ConflictableTransactionResult<(), String> {
+    match available_at {
+        Some(at) => delayed.insert(&delayed_key(at, id), &[])?,
+        None => ready.insert(&ready_key(priority, id), &[])?,
+    };
+    Ok(())
+}
+
+fn get_record(
+    jobs: &TransactionalTree,
+    id: u64,
+) -> ConflictableTransactionResult<QueueRecord, String> {
+    let bytes = jobs
+        .get(id.to_be_bytes())?
+        .ok_or(ConflictableTransactionError::Abort(
+            "queue_job_not_found".to_string(),
+        ))?;
+    QueueRecord::decode(&bytes).map_err(ConflictableTransactionError::Abort)
+}
+
+fn ready_key(priority: u8, id: u64) -> [u8; 9] {
+    let mut key = [0u8; 9];
+    // highest priority first
+    key[0] = u8::MAX - priority;
+    key[1..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn delayed_key(available_at: u64, id: u64) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..8].copy_from_slice(&available_at.to_be_bytes());
+    key[8..].copy_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn lease_value(deadline: u64, lease: u64) -> [u8; 16] {
+    let mut value = [0u8; 16];
+    value[..8].copy_from_slice(&deadline.to_be_bytes());
+    value[8..].copy_from_slice(&lease.to_be_bytes());
+    value
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(value)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::sled::sled_db_open;
+
+    async fn temporary_db(name: &str) -> Arc<CustomDB> {
+        let path = std::env::temp_dir().join(format!("sled_queue_{}_{}", name, std::process::id()));
+        sled_db_open(path.to_string_lossy().to_string(), false, true)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn expired_lease_is_released() {
+        let db = temporary_db("expired_lease").await;
+        let queue = "jobs".to_string();
+        let id = queue_enqueue(&db, queue.clone(), vec![1], None, None, None)
+            .await
+            .unwrap();
+        let first = queue_dequeue(&db, queue.clone(), 0).await.unwrap().unwrap();
+        assert_eq!(first.id, id);
+        let size = queue_size(&db, queue.clone()).await.unwrap();
+        assert_eq!((size.ready, size.in_flight), (1, 0));
+        // the expired lease cannot be acked anymore
+        let first_lease = first.lease.unwrap();
+        assert!(!queue_ack(&db, queue.clone(), id, first_lease)
+            .await
+            .unwrap());
+        let second = queue_dequeue(&db, queue.clone(), 60_000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.attempts, 1);
+        assert_ne!(second.lease, first.lease);
+        assert!(!queue_nack(&db, queue.clone(), id, first_lease, None)
+            .await
+            .unwrap());
+        let size = queue_size(&db, queue.clone()).await.unwrap();
+        assert_eq!((size.ready, size.in_flight), (0, 1));
+        assert!(queue_ack(&db, queue.clone(), id, second.lease.unwrap())
+            .await
+            .unwrap());
+        assert!(queue_dequeue(&db, queue, 0).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn higher_priority_first() {
+        let db = temporary_db("priority").await;
+        let queue = "jobs".to_string();
+        let low = queue_enqueue(&db, queue.clone(), vec![1], Some(1), None, None)
+            .await
+            .unwrap();
+        let high = queue_enqueue(&db, queue.clone(), vec![2], Some(9), None, None)
+            .await
+            .unwrap();
+        let low_second = queue_enqueue(&db, queue.clone(), vec![3], Some(1), None, None)
+            .await
+            .unwrap();
+        let mut ids = vec![];
+        while let Some(job) = queue_dequeue(&db, queue.clone(), 60_000).await.unwrap() {
+            ids.push(job.id);
+        }
+        assert_eq!(ids, vec![high, low, low_second]);
+    }
+
+    #[tokio::test]
+    async fn delayed_job_is_hidden_until_due() {
+        let db = temporary_db("delayed").await;
+        let queue = "jobs".to_string();
+        let id = queue_enqueue(&db, queue.clone(), vec![1], None, Some(200), None)
+            .await
+            .unwrap();
+        assert!(queue_dequeue(&db, queue.clone(), 60_000)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(queue_peek(&db, queue.clone(), None)
+            .await
+            .unwrap()
+            .is_empty());
+        let size = queue_size(&db, queue.clone()).await.unwrap();
+        assert_eq!((size.ready, size.delayed), (0, 1));
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let job = queue_dequeue(&db, queue, 60_000).await.unwrap().unwrap();
+        assert_eq!(job.id, id);
+    }
+
+    #[tokio::test]
+    async fn dead_letter_after_max_attempts() {
+        let db = temporary_db("dead_letter").await;
+        let queue = "jobs".to_string();
+        let id = queue_enqueue(&db, queue.clone(), vec![1], None, None, Some(2))
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let job = queue_dequeue(&db, queue.clone(), 60_000)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(queue_nack(&db, queue.clone(), id, job.lease.unwrap(), None)
+                .await
+                .unwrap());
+        }
+        assert!(queue_dequeue(&db, queue.clone(), 60_000)
+            .await
+            .unwrap()
+            .is_none());
+        let dead = queue_dead_letters(&db, queue.clone(), None).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!((dead[0].id, dead[0].attempts), (id, 2));
+        let size = queue_size(&db, queue).await.unwrap();
+        assert_eq!((size.ready, size.in_flight, size.dead), (0, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn requeue_dead_resets_attempts() {
+        let db = temporary_db("requeue").await;
+        let queue = "jobs".to_string();
+        let id = queue_enqueue(&db, queue.clone(), vec![1], None, None, Some(1))
+            .await
+            .unwrap();
+        let job = queue_dequeue(&db, queue.clone(), 60_000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(queue_nack(&db, queue.clone(), id, job.lease.unwrap(), None)
+            .await
+            .unwrap());
+        assert!(queue_requeue_dead(&db, queue.clone(), id).await.unwrap());
+        assert!(!queue_requeue_dead(&db, queue.clone(), id).await.unwrap());
+        let job = queue_dequeue(&db, queue.clone(), 60_000)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((job.id, job.attempts, job.payload), (id, 0, vec![1]));
+        let size = queue_size(&db, queue).await.unwrap();
+        assert_eq!(size.dead, 0);
+    }
+
+    #[tokio::test]
+    async fn purge_dead_only_removes_dead_letters() {
+        let db = temporary_db("purge").await;
+        let queue = "jobs".to_string();
+        for _ in 0..2 {
+            let id = queue_enqueue(&db, queue.clone(), vec![1], None, None, Some(1))
+                .await
+                .unwrap();
+            let job = queue_dequeue(&db, queue.clone(), 60_000)
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(queue_nack(&db, queue.clone(), id, job.lease.unwrap(), None)
+                .await
+                .unwrap());
+        }
+        queue_enqueue(&db, queue.clone(), vec![2], None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(queue_purge_dead(&db, queue.clone()).await, Ok(2));
+        assert!(queue_dead_letters(&db, queue.clone(), None)
+            .await
+            .unwrap()
+            .is_empty());
+        let size = queue_size(&db, queue.clone()).await.unwrap();
+        assert_eq!((size.ready, size.dead), (1, 0));
+        assert_eq!(queue_purge_dead(&db, queue).await, Ok(0));
+    }
+
+    #[tokio::test]
+    async fn large_delays_saturate() {
+        let db = temporary_db("large_delays").await;
+        let queue = "jobs".to_string();
+        let id = queue_enqueue(&db, queue.clone(), vec![1], None, None, None)
+            .await
+            .unwrap();
+        let job = queue_dequeue(&db, queue.clone(), u64::MAX)
+            .await
+            .unwrap()
+            .unwrap();
+        let lease = job.lease.unwrap();
+        assert!(queue_nack(&db, queue.clone(), id, lease, Some(u64::MAX))
+            .await
+            .unwrap());
+        let size = queue_size(&db, queue.clone()).await.unwrap();
+        assert_eq!((size.ready, size.delayed), (0, 1));
+        queue_enqueue(&db, queue.clone(), vec![2], None, Some(u64::MAX), None)
+            .await
+            .unwrap();
+        assert!(queue_dequeue(&db, queue, 0).await.unwrap().is_none());
+    }
+}
@@ -1,60 +1,123 @@
-// use a sync mutex to unlnock when awaiting
-use std::sync::{Arc, Mutex};
+// the swarm is owned by a single task, api functions send it commands
+use super::p2p_common::{CustomBehaviour, CustomSwarmEvent, GenericError};
+use super::p2p_dht::RoutingTableStore;
+use super::p2p_event::run_swarm;
+use super::p2p_file::FileTransfers;
+use super::p2p_outbox::Outbox;
+use super::p2p_peer_store::PeerStore;
+use super::p2p_pubsub::TopicCounters;
+use super::p2p_rdv::Rendezvous;
+use super::p2p_relay::{RelayReservations, RelayServerStats};
+use super::p2p_request::PendingRequests;
+use super::p2p_validation::ValidationPipeline;
+use libp2p::{PeerId, Swarm};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+/// Options of the swarm task, set when the instance is created.
+pub(crate) struct SwarmOptions {
+    /// Subscribers lagging by more than this number of events miss the oldest ones.
+    pub(crate) event_buffer_size: usize,
+    pub(crate) mdns_auto_dial: bool,
+}
+
+/// State owned by the swarm task, commands get exclusive access to it.
+pub(crate) struct SwarmState {
+    pub(crate) swarm: Swarm<CustomBehaviour>,
+    pub(crate) pending_requests: PendingRequests,
+    pub(crate) mdns_auto_dial: bool,
+    /// Set once the instance is attached to a database.
+    pub(crate) peer_store: Option<PeerStore>,
+    /// Set once the outbox is attached to a database.
+    pub(crate) outbox: Option<Outbox>,
+    /// Set once the routing table is attached to a database.
+    pub(crate) routing_table: Option<RoutingTableStore>,
+    pub(crate) topic_counters: TopicCounters,
+    pub(crate) validation: ValidationPipeline,
+    pub(crate) relay_reservations: RelayReservations,
+    pub(crate) relay_server_stats: RelayServerStats,
+    pub(crate) rendezvous: Rendezvous,
+    pub(crate) file_transfers: FileTransfers,
+}
+
+pub(crate) type SwarmCommand = Box<dyn FnOnce(&mut SwarmState) + Send>;
 
 pub struct Wrapper {
-    inner: Arc<Mutex<(bool, *mut ())>>,
+    peer_id: PeerId,
+    commands: mpsc::UnboundedSender<SwarmCommand>,
+    events: broadcast::Sender<CustomSwarmEvent>,
 }
 
 impl Wrapper {
-    pub fn new<T>(value: T) -> Self {
-        let boxed = Box::new(value);
-        let raw = Box::into_raw(boxed) as *mut ();
+    /// Spawn the swarm task, it stops when the wrapper is dropped.
+    pub(crate) fn new(swarm: Swarm<CustomBehaviour>, options: SwarmOptions) -> Self {
+        let peer_id = *swarm.local_peer_id();
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(options.event_buffer_size.max(1));
+        let state = SwarmState {
+            swarm,
+            pending_requests: PendingRequests::default(),
+            mdns_auto_dial: options.mdns_auto_dial,
+            peer_store: None,
+            outbox: None,
+            routing_table: None,
+            topic_counters: TopicCounters::default(),
+            validation: ValidationPipeline::default(),
+            relay_reservations: RelayReservations::default(),
+            relay_server_stats: RelayServerStats::default(),
+            rendezvous: Rendezvous::default(),
+            file_transfers: FileTransfers::default(),
+        };
+        tokio::spawn(run_swarm(state, receiver, events.clone()));
         Self {
-            inner: Arc::new(Mutex::new((false, raw))),
+            peer_id,
+            commands,
+            events,
         }
     }
 
-    pub fn unsafe_ref<T>(&self) -> Option<&T> {
-        let lock = self.inner.lock().unwrap();
-        if lock.0 {
-            None
-        } else {
-            unsafe { Some(&*(lock.1 as *const T)) }
-        }
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
     }
 
-    pub fn as_ref<T>(&self) -> Option<&T> {
-        let lock = self.inner.lock().unwrap();
-        if lock.0 {
-            None
-        } else {
-            unsafe { Some(&*(lock.1 as *const T)) }
-        }
+    /// Run a command on the swarm task and wait for its result.
+    pub(crate) async fn call<R, F>(&self, command: F) -> Result<R, GenericError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut SwarmState) -> R + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.send(move |state| {
+            let _ = sender.send(command(state));
+        })?;
+        receiver.await.map_err(|_| GenericError::InstanceNotFound)
     }
 
-    pub fn as_mut<T>(&self) -> Option<&mut T> {
-        let lock = self.inner.lock().unwrap();
-        if lock.0 {
-            None
-        } else {
-            unsafe { Some(&mut *(lock.1 as *mut T)) }
-        }
+    /// Same as `call` with only the swarm.
+    pub(crate) async fn with_swarm<R, F>(&self, command: F) -> Result<R, GenericError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Swarm<CustomBehaviour>) -> R + Send + 'static,
+    {
+        self.call(move |state| command(&mut state.swarm)).await
     }
-}
 
-impl Drop for Wrapper {
-    fn drop(&mut self) {
-        let mut lock = self.inner.lock().unwrap();
-        if !lock.0 {
-            unsafe {
-                drop(Box::from_raw(lock.1));
-            }
-            lock.0 = true;
-        }
+    /// Queue a command without waiting for it.
+    pub(crate) fn send<F>(&self, command: F) -> Result<(), GenericError>
+    where
+        F: FnOnce(&mut SwarmState) + Send + 'static,
+    {
+        self.commands
+            .send(Box::new(command))
+            .map_err(|_| GenericError::InstanceNotFound)
     }
-}
 
+    /// Publish an event produced outside the swarm task.
+    pub(crate) fn emit(&self, event: CustomSwarmEvent) {
+        // sending fails when there is no listener
+        let _ = self.events.send(event);
+    }
 
-// Ensure that Wrapper implements Sync
-unsafe impl Send for Wrapper {}
-unsafe impl Sync for Wrapper {}
\ No newline at end of file
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<CustomSwarmEvent> {
+        self.events.subscribe()
+    }
+}
@@ -1,2 +1,4 @@
 pub mod api;
+#[cfg(test)]
+mod test_utils;
 mod frb_generated; /* AUTO INJECTED BY flutter_rust_bridge. This line may not be accurate, and you can change it according to your needs. */
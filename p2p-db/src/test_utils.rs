@@ -0,0 +1,98 @@
+use crate::api::p2p_config::{Libp2pBehavioursConfig, Libp2pConfig};
+use crate::api::p2p_dial::{libp2p_dial_address, libp2p_isconnected};
+use crate::api::p2p_factory::create_libp2p_instance;
+use crate::api::p2p_listen::libp2p_listen;
+use crate::api::wrapper::Wrapper;
+use libp2p::identity::Keypair;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Configuration without the optional behaviours, they dial or listen on their own.
+pub(crate) fn test_config() -> Libp2pConfig {
+    Libp2pConfig {
+        behaviours: Libp2pBehavioursConfig {
+            upnp: false,
+            autonat: false,
+            dcutr: false,
+            relay_server: false,
+            rendezvous_server: false,
+            rendezvous_client: false,
+            kademlia: false,
+            mdns: false,
+        },
+        ..Libp2pConfig::default()
+    }
+}
+
+pub(crate) async fn test_instance(config: Libp2pConfig) -> Arc<Wrapper> {
+    create_libp2p_instance(Keypair::generate_ed25519(), config)
+        .await
+        .ok()
+        .unwrap()
+}
+
+/// Listen on the address and return the first listen address with the peer id.
+pub(crate) async fn listen(wrapper: &Arc<Wrapper>, address: &str) -> String {
+    assert!(libp2p_listen(wrapper, address.to_string()).await.is_ok());
+    for _ in 0..50 {
+        let listening: Option<String> = wrapper
+            .with_swarm(|swarm| swarm.listeners().next().map(|f| f.to_string()))
+            .await
+            .ok()
+            .unwrap();
+        if let Some(address) = listening {
+            return format!("{}/p2p/{}", address, wrapper.peer_id());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("not listening");
+}
+
+/// Dial the address returned by `listen`, false when not connected in time.
+pub(crate) async fn connect(dialer: &Arc<Wrapper>, listener: &Arc<Wrapper>, address: &str) -> bool {
+    assert!(libp2p_dial_address(dialer, address.to_string())
+        .await
+        .is_ok());
+    for _ in 0..50 {
+        let peer = listener.peer_id().to_string();
+        if libp2p_isconnected(dialer, peer).await.ok().unwrap() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+/// Two instances connected over tcp on the loopback, the second one listens.
+pub(crate) async fn connected_pair() -> (Arc<Wrapper>, Arc<Wrapper>) {
+    let (dialer, listener) = (
+        test_instance(test_config()).await,
+        test_instance(test_config()).await,
+    );
+    let address = listen(&listener, "/ip4/127.0.0.1/tcp/0").await;
+    assert!(connect(&dialer, &listener, &address).await);
+    (dialer, listener)
+}
+
+/// Removes the file or directory when dropped, tests leave nothing behind when they fail.
+pub(crate) struct TemporaryPath(pub(crate) std::path::PathBuf);
+
+impl TemporaryPath {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("p2p_db_{}_{}", name, std::process::id()));
+        let temporary = TemporaryPath(path);
+        temporary.remove();
+        temporary
+    }
+
+    fn remove(&self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl Drop for TemporaryPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}